@@ -0,0 +1,452 @@
+//! A documented view of compiled templates, for tools that need to walk or
+//! rewrite them.
+//!
+//! ```rust
+//! use mustache::ast::{Variable, Visitor};
+//!
+//! struct Names(Vec<String>);
+//!
+//! impl Visitor for Names {
+//!     fn visit_variable(&mut self, variable: &Variable) {
+//!         self.0.push(variable.path.join("."));
+//!     }
+//! }
+//!
+//! let template = mustache::compile_str("{{#user}}{{name}} <{{email}}>{{/user}}").unwrap();
+//! let mut names = Names(Vec::new());
+//! template.ast().visit(&mut names);
+//! assert_eq!(names.0, vec!["name", "email"]);
+//! ```
+
+use std::collections::HashMap;
+
+use crate::parser::{Span, Token};
+
+/// A compiled template and the partials it uses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ast {
+    pub nodes: Vec<Node>,
+    /// The partials and parents used by the template, directly or through
+    /// other partials, by name.
+    pub partials: HashMap<String, Vec<Node>>,
+}
+
+impl Ast {
+    /// Walks the template's nodes, not including its partials.
+    pub fn visit<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        walk_nodes(visitor, &self.nodes);
+    }
+
+    /// Rewrites the template's nodes and those of its partials.
+    pub fn fold<F: Fold + ?Sized>(self, folder: &mut F) -> Ast {
+        Ast {
+            nodes: fold_nodes(folder, self.nodes),
+            partials: self
+                .partials
+                .into_iter()
+                .map(|(name, nodes)| (name, fold_nodes(folder, nodes)))
+                .collect(),
+        }
+    }
+}
+
+/// A node of a template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Node {
+    Text(Text),
+    Variable(Variable),
+    Section(Section),
+    Partial(Partial),
+    DynamicPartial(DynamicPartial),
+    Parent(Parent),
+    Block(Block),
+    #[cfg(feature = "CFEngine")]
+    At(At),
+    #[cfg(feature = "CFEngine")]
+    Json(Json),
+    #[cfg(feature = "CFEngine")]
+    TopSection(TopSection),
+}
+
+impl Node {
+    /// Where the node is in the template source.
+    pub fn span(&self) -> Span {
+        match *self {
+            Node::Text(ref node) => node.span,
+            Node::Variable(ref node) => node.span,
+            Node::Section(ref node) => node.span,
+            Node::Partial(ref node) => node.span,
+            Node::DynamicPartial(ref node) => node.span,
+            Node::Parent(ref node) => node.span,
+            Node::Block(ref node) => node.span,
+            #[cfg(feature = "CFEngine")]
+            Node::At(ref node) => node.span,
+            #[cfg(feature = "CFEngine")]
+            Node::Json(ref node) => node.span,
+            #[cfg(feature = "CFEngine")]
+            Node::TopSection(ref node) => node.span,
+        }
+    }
+}
+
+/// Literal text, rendered as is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Text {
+    pub text: String,
+    pub span: Span,
+}
+
+/// A `{{name}}`, `{{{name}}}` or `{{&name}}` interpolation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variable {
+    /// The dotted path, split on dots. `{{.}}` is the empty path.
+    pub path: Vec<String>,
+    /// Whether the value is escaped, which is only the case for `{{name}}`.
+    pub escaped: bool,
+    /// The source of the tag.
+    pub tag: String,
+    pub span: Span,
+}
+
+/// A `{{#name}}...{{/name}}` section or `{{^name}}...{{/name}}` inverted
+/// section.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+    pub path: Vec<String>,
+    pub inverted: bool,
+    pub children: Vec<Node>,
+    /// The source of the opening tag.
+    pub open_tag: String,
+    /// The source of the closing tag.
+    pub close_tag: String,
+    /// The source between the tags, as passed to lambdas.
+    pub source: String,
+    /// The opening and closing delimiters in effect in the section.
+    pub delimiters: (String, String),
+    /// Where the section is, from its opening tag to its closing tag.
+    pub span: Span,
+}
+
+/// A `{{>name}}` partial.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partial {
+    pub name: String,
+    /// The whitespace before a partial tag standing alone on its line, which
+    /// is added in front of each line of the partial.
+    pub indent: String,
+    pub tag: String,
+    pub span: Span,
+}
+
+/// A `{{>*name}}` partial whose name is looked up in the data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DynamicPartial {
+    pub path: Vec<String>,
+    pub indent: String,
+    pub tag: String,
+    pub span: Span,
+}
+
+/// A `{{<name}}...{{/name}}` parent, rendered like a partial with its blocks
+/// overridden by the ones inside it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Parent {
+    pub name: String,
+    pub indent: String,
+    pub children: Vec<Node>,
+    pub open_tag: String,
+    pub close_tag: String,
+    pub span: Span,
+}
+
+/// A `{{$name}}...{{/name}}` block, which parents can override.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub name: String,
+    /// The indentation of the block, which overrides take on in place of
+    /// their own.
+    pub indent: String,
+    pub children: Vec<Node>,
+    pub open_tag: String,
+    pub close_tag: String,
+    pub span: Span,
+}
+
+/// The `{{@}}` key or index of the current iteration.
+#[cfg(feature = "CFEngine")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct At {
+    pub span: Span,
+}
+
+/// A `{{$name}}` or `{{%name}}` JSON dump of a value. The `-top-` name dumps
+/// the whole data.
+#[cfg(feature = "CFEngine")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Json {
+    pub path: Vec<String>,
+    /// Whether the JSON is pretty-printed over multiple lines, for `{{%name}}`.
+    pub pretty: bool,
+    pub tag: String,
+    pub span: Span,
+}
+
+/// A `{{#-top-}}...{{/-top-}}` section over the whole data.
+#[cfg(feature = "CFEngine")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopSection {
+    pub children: Vec<Node>,
+    pub span: Span,
+}
+
+/// Walks a template without changing it. Each method walks the children of
+/// its node by default, so implementations only override the nodes they care
+/// about, calling the matching `walk_` function to keep walking.
+pub trait Visitor {
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node);
+    }
+
+    fn visit_text(&mut self, _text: &Text) {}
+
+    fn visit_variable(&mut self, _variable: &Variable) {}
+
+    fn visit_section(&mut self, section: &Section) {
+        walk_nodes(self, &section.children);
+    }
+
+    fn visit_partial(&mut self, _partial: &Partial) {}
+
+    fn visit_dynamic_partial(&mut self, _partial: &DynamicPartial) {}
+
+    fn visit_parent(&mut self, parent: &Parent) {
+        walk_nodes(self, &parent.children);
+    }
+
+    fn visit_block(&mut self, block: &Block) {
+        walk_nodes(self, &block.children);
+    }
+
+    #[cfg(feature = "CFEngine")]
+    fn visit_at(&mut self, _at: &At) {}
+
+    #[cfg(feature = "CFEngine")]
+    fn visit_json(&mut self, _json: &Json) {}
+
+    #[cfg(feature = "CFEngine")]
+    fn visit_top_section(&mut self, section: &TopSection) {
+        walk_nodes(self, &section.children);
+    }
+}
+
+/// Calls the visitor method for the kind of `node`.
+pub fn walk_node<V: Visitor + ?Sized>(visitor: &mut V, node: &Node) {
+    match *node {
+        Node::Text(ref node) => visitor.visit_text(node),
+        Node::Variable(ref node) => visitor.visit_variable(node),
+        Node::Section(ref node) => visitor.visit_section(node),
+        Node::Partial(ref node) => visitor.visit_partial(node),
+        Node::DynamicPartial(ref node) => visitor.visit_dynamic_partial(node),
+        Node::Parent(ref node) => visitor.visit_parent(node),
+        Node::Block(ref node) => visitor.visit_block(node),
+        #[cfg(feature = "CFEngine")]
+        Node::At(ref node) => visitor.visit_at(node),
+        #[cfg(feature = "CFEngine")]
+        Node::Json(ref node) => visitor.visit_json(node),
+        #[cfg(feature = "CFEngine")]
+        Node::TopSection(ref node) => visitor.visit_top_section(node),
+    }
+}
+
+/// Visits each of `nodes` in order.
+pub fn walk_nodes<V: Visitor + ?Sized>(visitor: &mut V, nodes: &[Node]) {
+    for node in nodes.iter() {
+        visitor.visit_node(node);
+    }
+}
+
+/// Rewrites a template, node by node. Each method folds the children of its
+/// node and returns it by default, so implementations only override the nodes
+/// they change. A node can be replaced by a node of another kind.
+pub trait Fold {
+    fn fold_node(&mut self, node: Node) -> Node {
+        fold_node(self, node)
+    }
+
+    fn fold_text(&mut self, text: Text) -> Node {
+        Node::Text(text)
+    }
+
+    fn fold_variable(&mut self, variable: Variable) -> Node {
+        Node::Variable(variable)
+    }
+
+    fn fold_section(&mut self, mut section: Section) -> Node {
+        section.children = fold_nodes(self, section.children);
+        Node::Section(section)
+    }
+
+    fn fold_partial(&mut self, partial: Partial) -> Node {
+        Node::Partial(partial)
+    }
+
+    fn fold_dynamic_partial(&mut self, partial: DynamicPartial) -> Node {
+        Node::DynamicPartial(partial)
+    }
+
+    fn fold_parent(&mut self, mut parent: Parent) -> Node {
+        parent.children = fold_nodes(self, parent.children);
+        Node::Parent(parent)
+    }
+
+    fn fold_block(&mut self, mut block: Block) -> Node {
+        block.children = fold_nodes(self, block.children);
+        Node::Block(block)
+    }
+
+    #[cfg(feature = "CFEngine")]
+    fn fold_at(&mut self, at: At) -> Node {
+        Node::At(at)
+    }
+
+    #[cfg(feature = "CFEngine")]
+    fn fold_json(&mut self, json: Json) -> Node {
+        Node::Json(json)
+    }
+
+    #[cfg(feature = "CFEngine")]
+    fn fold_top_section(&mut self, mut section: TopSection) -> Node {
+        section.children = fold_nodes(self, section.children);
+        Node::TopSection(section)
+    }
+}
+
+/// Calls the fold method for the kind of `node`.
+pub fn fold_node<F: Fold + ?Sized>(folder: &mut F, node: Node) -> Node {
+    match node {
+        Node::Text(node) => folder.fold_text(node),
+        Node::Variable(node) => folder.fold_variable(node),
+        Node::Section(node) => folder.fold_section(node),
+        Node::Partial(node) => folder.fold_partial(node),
+        Node::DynamicPartial(node) => folder.fold_dynamic_partial(node),
+        Node::Parent(node) => folder.fold_parent(node),
+        Node::Block(node) => folder.fold_block(node),
+        #[cfg(feature = "CFEngine")]
+        Node::At(node) => folder.fold_at(node),
+        #[cfg(feature = "CFEngine")]
+        Node::Json(node) => folder.fold_json(node),
+        #[cfg(feature = "CFEngine")]
+        Node::TopSection(node) => folder.fold_top_section(node),
+    }
+}
+
+/// Folds each of `nodes` in order.
+pub fn fold_nodes<F: Fold + ?Sized>(folder: &mut F, nodes: Vec<Node>) -> Vec<Node> {
+    nodes
+        .into_iter()
+        .map(|node| folder.fold_node(node))
+        .collect()
+}
+
+/// Converts compiled tokens into nodes.
+pub(crate) fn from_tokens(tokens: &[Token]) -> Vec<Node> {
+    tokens.iter().filter_map(from_token).collect()
+}
+
+fn from_token(token: &Token) -> Option<Node> {
+    let node = match *token {
+        Token::Text(ref text, span) => Node::Text(Text {
+            text: text.clone(),
+            span,
+        }),
+        Token::EscapedTag(ref path, ref tag, span) => Node::Variable(Variable {
+            path: path.clone(),
+            escaped: true,
+            tag: tag.clone(),
+            span,
+        }),
+        Token::UnescapedTag(ref path, ref tag, span) => Node::Variable(Variable {
+            path: path.clone(),
+            escaped: false,
+            tag: tag.clone(),
+            span,
+        }),
+        Token::Section(ref path, inverted, ref children, ref otag, ref ctag, ref fdata, span) => {
+            Node::Section(Section {
+                path: path.clone(),
+                inverted,
+                children: from_tokens(children),
+                open_tag: otag.clone(),
+                close_tag: ctag.clone(),
+                source: fdata.as_str().to_string(),
+                delimiters: (fdata.otag.clone(), fdata.ctag.clone()),
+                span,
+            })
+        }
+        Token::Partial(ref name, ref indent, ref tag, span) => Node::Partial(Partial {
+            name: name.clone(),
+            indent: indent.clone(),
+            tag: tag.clone(),
+            span,
+        }),
+        Token::DynamicPartial(ref path, ref indent, ref tag, span) => {
+            Node::DynamicPartial(DynamicPartial {
+                path: path.clone(),
+                indent: indent.clone(),
+                tag: tag.clone(),
+                span,
+            })
+        }
+        Token::Parent(ref name, ref indent, ref children, ref otag, ref ctag, span) => {
+            Node::Parent(Parent {
+                name: name.clone(),
+                indent: indent.clone(),
+                children: from_tokens(children),
+                open_tag: otag.clone(),
+                close_tag: ctag.clone(),
+                span,
+            })
+        }
+        Token::Block(ref name, ref indent, ref children, ref otag, ref ctag, span) => {
+            Node::Block(Block {
+                name: name.clone(),
+                indent: indent.clone(),
+                children: from_tokens(children),
+                open_tag: otag.clone(),
+                close_tag: ctag.clone(),
+                span,
+            })
+        }
+        #[cfg(feature = "CFEngine")]
+        Token::At(span) => Node::At(At { span }),
+        #[cfg(feature = "CFEngine")]
+        Token::JSON(ref path, ref tag, span) | Token::TopJSON(ref path, ref tag, span) => {
+            Node::Json(Json {
+                path: path.clone(),
+                pretty: false,
+                tag: tag.clone(),
+                span,
+            })
+        }
+        #[cfg(feature = "CFEngine")]
+        Token::JSONMulti(ref path, ref tag, span)
+        | Token::TopJSONMulti(ref path, ref tag, span) => Node::Json(Json {
+            path: path.clone(),
+            pretty: true,
+            tag: tag.clone(),
+            span,
+        }),
+        #[cfg(feature = "CFEngine")]
+        Token::TopSection(ref children, span) => Node::TopSection(TopSection {
+            children: from_tokens(children),
+            span,
+        }),
+        // Compiled templates never contain incomplete tokens.
+        Token::IncompleteSection(..) | Token::IncompleteParent(..) | Token::IncompleteBlock(..) => {
+            return None;
+        }
+    };
+
+    Some(node)
+}
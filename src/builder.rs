@@ -1,15 +1,16 @@
 use serde::Serialize;
-use std::cell::RefCell;
-use std::collections::HashMap;
 use std::string::ToString;
+use std::sync::Arc;
 
-use super::{Data, to_data};
+use indexmap::IndexMap;
+
+use super::{Computed, Data, LambdaContext, LambdaError, Stream, to_data};
 use crate::encoder::Error;
 
 /// `MapBuilder` is a helper type that construct `Data` types.
 #[derive(Default)]
 pub struct MapBuilder {
-    data: HashMap<String, Data>,
+    data: IndexMap<String, Data>,
 }
 
 impl MapBuilder {
@@ -130,25 +131,106 @@ impl MapBuilder {
         MapBuilder { data }
     }
 
-    /// Add a function to the `MapBuilder`.
+    /// Add a function to the `MapBuilder`. Functions may be called from
+    /// several threads at once, so state they keep has to be synchronized.
     ///
     /// ```rust
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
     /// use mustache::MapBuilder;
-    /// let mut count = 0;
+    /// let count = AtomicUsize::new(0);
     /// let data = MapBuilder::new()
     ///     .insert_fn("increment", move |_| {
-    ///         count += 1usize;
-    ///         count.to_string()
+    ///         (count.fetch_add(1, Ordering::Relaxed) + 1).to_string()
     ///     })
     ///     .build();
     /// ```
     #[inline]
     pub fn insert_fn<K: ToString, F>(self, key: K, f: F) -> MapBuilder
     where
-        F: FnMut(String) -> String + Send + 'static,
+        F: Fn(String) -> String + Send + Sync + 'static,
+    {
+        let MapBuilder { mut data } = self;
+        data.insert(key.to_string(), Data::Fun(Arc::new(f)));
+        MapBuilder { data }
+    }
+
+    /// Add a lambda that is given a `LambdaContext` to the `MapBuilder`.
+    ///
+    /// ```rust
+    /// use mustache::MapBuilder;
+    /// let data = MapBuilder::new()
+    ///     .insert_lambda("bold", |ctx| Ok(format!("<b>{}</b>", ctx.render_section()?)))
+    ///     .build();
+    /// ```
+    #[inline]
+    pub fn insert_lambda<K: ToString, F>(self, key: K, f: F) -> MapBuilder
+    where
+        F: Fn(&mut LambdaContext<'_, '_>) -> Result<String, LambdaError> + Send + Sync + 'static,
     {
         let MapBuilder { mut data } = self;
-        data.insert(key.to_string(), Data::Fun(RefCell::new(Box::new(f))));
+        data.insert(key.to_string(), Data::Lambda(Arc::new(f)));
+        MapBuilder { data }
+    }
+
+    /// Add a function that can fail to the `MapBuilder`. It is used like
+    /// the functions added with `insert_fn`, and its error ends rendering
+    /// with `Error::Lambda`.
+    ///
+    /// ```rust
+    /// use mustache::MapBuilder;
+    /// let data = MapBuilder::new()
+    ///     .insert_fallible_fn("cents", |s| match s.trim().parse::<f64>() {
+    ///         Ok(amount) => Ok((amount * 100.0).round().to_string()),
+    ///         Err(_) => Err(format!("not an amount: {s}")),
+    ///     })
+    ///     .build();
+    /// ```
+    #[inline]
+    pub fn insert_fallible_fn<K: ToString, F, E>(self, key: K, f: F) -> MapBuilder
+    where
+        F: Fn(String) -> Result<String, E> + Send + Sync + 'static,
+        E: Into<LambdaError> + 'static,
+    {
+        self.insert_lambda(key, fallible(f))
+    }
+
+    /// Add a value to the `MapBuilder` that is computed the first time a
+    /// template uses it.
+    ///
+    /// ```rust
+    /// use mustache::{Data, MapBuilder};
+    /// let data = MapBuilder::new()
+    ///     .insert_computed("total", || Data::Int(42))
+    ///     .build();
+    /// ```
+    #[inline]
+    pub fn insert_computed<K: ToString, F>(self, key: K, f: F) -> MapBuilder
+    where
+        F: Fn() -> Data + Send + Sync + 'static,
+    {
+        let MapBuilder { mut data } = self;
+        data.insert(key.to_string(), Data::Computed(Arc::new(Computed::new(f))));
+        MapBuilder { data }
+    }
+
+    /// Add a list to the `MapBuilder` whose items are made by an iterator
+    /// while it is rendered.
+    ///
+    /// ```rust
+    /// use mustache::{Data, MapBuilder};
+    /// let data = MapBuilder::new()
+    ///     .insert_stream("numbers", || (0..1000).map(Data::Int))
+    ///     .build();
+    /// ```
+    #[inline]
+    pub fn insert_stream<K: ToString, F, I>(self, key: K, f: F) -> MapBuilder
+    where
+        F: Fn() -> I + Send + Sync + 'static,
+        I: IntoIterator<Item = Data>,
+        I::IntoIter: 'static,
+    {
+        let MapBuilder { mut data } = self;
+        data.insert(key.to_string(), Data::Stream(Arc::new(Stream::new(f))));
         MapBuilder { data }
     }
 
@@ -271,25 +353,106 @@ impl VecBuilder {
         VecBuilder { data }
     }
 
-    /// Add a function to the `VecBuilder`.
+    /// Add a function to the `VecBuilder`. Functions may be called from
+    /// several threads at once, so state they keep has to be synchronized.
     ///
     /// ```rust
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
     /// use mustache::VecBuilder;
-    /// let mut count = 0;
+    /// let count = AtomicUsize::new(0);
     /// let data = VecBuilder::new()
     ///     .push_fn(move |s| {
-    ///         count += 1usize;
-    ///         s + &count.to_string()
+    ///         s + &(count.fetch_add(1, Ordering::Relaxed) + 1).to_string()
     ///     })
     ///     .build();
     /// ```
     #[inline]
     pub fn push_fn<F>(self, f: F) -> VecBuilder
     where
-        F: FnMut(String) -> String + Send + 'static,
+        F: Fn(String) -> String + Send + Sync + 'static,
+    {
+        let VecBuilder { mut data } = self;
+        data.push(Data::Fun(Arc::new(f)));
+        VecBuilder { data }
+    }
+
+    /// Add a lambda that is given a `LambdaContext` to the `VecBuilder`.
+    ///
+    /// ```rust
+    /// use mustache::VecBuilder;
+    /// let data = VecBuilder::new()
+    ///     .push_lambda(|ctx| Ok(format!("<b>{}</b>", ctx.render_section()?)))
+    ///     .build();
+    /// ```
+    #[inline]
+    pub fn push_lambda<F>(self, f: F) -> VecBuilder
+    where
+        F: Fn(&mut LambdaContext<'_, '_>) -> Result<String, LambdaError> + Send + Sync + 'static,
     {
         let VecBuilder { mut data } = self;
-        data.push(Data::Fun(RefCell::new(Box::new(f))));
+        data.push(Data::Lambda(Arc::new(f)));
+        VecBuilder { data }
+    }
+
+    /// Add a function that can fail to the `VecBuilder`. It is used like
+    /// the functions added with `push_fn`, and its error ends rendering with
+    /// `Error::Lambda`.
+    ///
+    /// ```rust
+    /// use mustache::VecBuilder;
+    /// let data = VecBuilder::new()
+    ///     .push_fallible_fn(|s| match s.as_str() {
+    ///         "" => Err("nothing to translate"),
+    ///         _ => Ok(s.to_uppercase()),
+    ///     })
+    ///     .build();
+    /// ```
+    #[inline]
+    pub fn push_fallible_fn<F, E>(self, f: F) -> VecBuilder
+    where
+        F: Fn(String) -> Result<String, E> + Send + Sync + 'static,
+        E: Into<LambdaError> + 'static,
+    {
+        self.push_lambda(fallible(f))
+    }
+
+    /// Add a value to the `VecBuilder` that is computed the first time a
+    /// template uses it.
+    ///
+    /// ```rust
+    /// use mustache::{Data, VecBuilder};
+    /// let data = VecBuilder::new()
+    ///     .push_computed(|| Data::Bool(true))
+    ///     .build();
+    /// ```
+    #[inline]
+    pub fn push_computed<F>(self, f: F) -> VecBuilder
+    where
+        F: Fn() -> Data + Send + Sync + 'static,
+    {
+        let VecBuilder { mut data } = self;
+        data.push(Data::Computed(Arc::new(Computed::new(f))));
+        VecBuilder { data }
+    }
+
+    /// Add a list to the `VecBuilder` whose items are made by an iterator
+    /// while it is rendered.
+    ///
+    /// ```rust
+    /// use mustache::{Data, VecBuilder};
+    /// let data = VecBuilder::new()
+    ///     .push_stream(|| (0..1000).map(Data::Int))
+    ///     .build();
+    /// ```
+    #[inline]
+    pub fn push_stream<F, I>(self, f: F) -> VecBuilder
+    where
+        F: Fn() -> I + Send + Sync + 'static,
+        I: IntoIterator<Item = Data>,
+        I::IntoIter: 'static,
+    {
+        let VecBuilder { mut data } = self;
+        data.push(Data::Stream(Arc::new(Stream::new(f))));
         VecBuilder { data }
     }
 
@@ -298,3 +461,16 @@ impl VecBuilder {
         Data::Vec(self.data)
     }
 }
+
+// Turns a function that can fail into a lambda that renders what it returns,
+// like the ones in `Data::Fun`.
+fn fallible<F, E>(f: F) -> impl Fn(&mut LambdaContext<'_, '_>) -> Result<String, LambdaError>
+where
+    F: Fn(String) -> Result<String, E>,
+    E: Into<LambdaError> + 'static,
+{
+    move |ctx| {
+        let text = f(ctx.source().to_string()).map_err(Into::into)?;
+        Ok(ctx.render(&text)?)
+    }
+}
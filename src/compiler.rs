@@ -1,12 +1,9 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::ErrorKind::NotFound;
-use std::io::Read;
+use std::collections::{BTreeSet, HashMap};
 
 use super::Context;
-use crate::parser::{Parser, Token};
+use crate::parser::{Error as ParserError, Parser, Token};
 
-use crate::Result;
+use crate::{Error, Result};
 
 pub type PartialsMap = HashMap<String, Vec<Token>>;
 
@@ -14,9 +11,9 @@ pub type PartialsMap = HashMap<String, Vec<Token>>;
 pub struct Compiler<T> {
     ctx: Context,
     reader: T,
-    partials: PartialsMap,
     otag: String,
     ctag: String,
+    name: Option<String>,
 }
 
 impl<T: Iterator<Item = char>> Compiler<T> {
@@ -25,78 +22,177 @@ impl<T: Iterator<Item = char>> Compiler<T> {
         Compiler {
             ctx,
             reader,
-            partials: HashMap::new(),
             otag: "{{".to_string(),
             ctag: "}}".to_string(),
+            name: None,
         }
     }
 
     /// Construct a default compiler.
-    pub fn new_with(
-        ctx: Context,
-        reader: T,
-        partials: PartialsMap,
-        otag: String,
-        ctag: String,
-    ) -> Compiler<T> {
+    pub fn new_with(ctx: Context, reader: T, otag: String, ctag: String) -> Compiler<T> {
         Compiler {
             ctx,
             reader,
-            partials,
             otag,
             ctag,
+            name: None,
         }
     }
 
-    /// Compiles a template into a series of tokens.
-    pub fn compile(mut self) -> Result<(Vec<Token>, PartialsMap)> {
-        let (tokens, partials) = {
-            let parser = Parser::new(&mut self.reader, &self.otag, &self.ctag);
-            parser.parse()?
-        };
+    /// Names the template being compiled, so that errors can report which
+    /// file they came from.
+    pub fn with_name(mut self, name: String) -> Compiler<T> {
+        self.name = Some(name);
+        self
+    }
+
+    /// Compiles a template into a series of tokens, along with the partials
+    /// it uses.
+    pub fn compile(self) -> Result<(Vec<Token>, PartialsMap)> {
+        self.compile_with(&|_| false)
+    }
+
+    /// Compiles a template into a series of tokens, along with the partials
+    /// it uses that `compiled` doesn't say were compiled already.
+    pub fn compile_with(
+        mut self,
+        compiled: &dyn Fn(&str) -> bool,
+    ) -> Result<(Vec<Token>, PartialsMap)> {
+        let (tokens, names) = parse(
+            &self.ctx,
+            &mut self.reader,
+            &self.otag,
+            &self.ctag,
+            self.name.as_deref(),
+        )?;
 
         // Compile the partials if we haven't done so already.
-        for name in partials.into_iter() {
-            let path = self
-                .ctx
-                .template_path
-                .join(&(name.clone() + "." + &self.ctx.template_extension));
-
-            if !self.partials.contains_key(&name) {
-                // Insert a placeholder so we don't recurse off to infinity.
-                self.partials.insert(name.to_string(), Vec::new());
-
-                match File::open(&path) {
-                    Ok(mut file) => {
-                        let mut string = String::new();
-                        file.read_to_string(&mut string)?;
-
-                        let compiler = Compiler {
-                            ctx: self.ctx.clone(),
-                            reader: string.chars(),
-                            partials: self.partials.clone(),
-                            otag: "{{".to_string(),
-                            ctag: "}}".to_string(),
-                        };
-
-                        let (tokens, subpartials) = compiler.compile()?;
-
-                        // Include subpartials
-                        self.partials.extend(subpartials.into_iter());
-
-                        // Set final compiled tokens for *this* partial
-                        self.partials.insert(name, tokens);
-                    }
-                    // Ignore missing files.
-                    Err(ref e) if e.kind() == NotFound => {}
-                    Err(e) => return Err(e.into()),
+        let mut partials = PartialsMap::new();
+        for name in names {
+            compile_partial(&self.ctx, &name, compiled, &mut partials)?;
+        }
+
+        Ok((tokens, partials))
+    }
+}
+
+// Parses a template, applying the source limit and naming any error after
+// the file it came from.
+fn parse<T: Iterator<Item = char>>(
+    ctx: &Context,
+    reader: &mut T,
+    otag: &str,
+    ctag: &str,
+    name: Option<&str>,
+) -> Result<(Vec<Token>, Vec<String>)> {
+    let mut reader = SourceLimit {
+        reader,
+        left: ctx.limits.max_source,
+        exceeded: false,
+    };
+    let parsed = Parser::new(&mut reader, otag, ctag)
+        .with_max_depth(ctx.limits.max_depth)
+        .parse();
+
+    // A source cut short is likely to be invalid, so check that first.
+    let parsed = match (ctx.limits.max_source, parsed) {
+        (Some(max), _) if reader.exceeded => Err(Error::SourceTooLarge(max)),
+        (_, Err(ParserError::TooDeep(max, _))) => Err(Error::TooDeep(max)),
+        (_, parsed) => parsed.map_err(Error::from),
+    };
+    match (parsed, name) {
+        (Ok(parsed), _) => Ok(parsed),
+        (Err(err), Some(name)) => Err(Error::Template(name.to_string(), Box::new(err))),
+        (Err(err), None) => Err(err),
+    }
+}
+
+// Stops reading the source once it is longer than `left` bytes.
+struct SourceLimit<'a, T> {
+    reader: &'a mut T,
+    left: Option<usize>,
+    exceeded: bool,
+}
+
+impl<T: Iterator<Item = char>> Iterator for SourceLimit<'_, T> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.reader.next()?;
+        if let Some(ref mut left) = self.left {
+            match left.checked_sub(ch.len_utf8()) {
+                Some(rest) => *left = rest,
+                None => {
+                    self.exceeded = true;
+                    return None;
                 }
             }
         }
+        Some(ch)
+    }
+}
 
-        let Compiler { partials, .. } = self;
+/// Compiles the named partial, and any partials it uses in turn, into
+/// `partials` unless `compiled` says it was compiled already or it is in
+/// `partials`. Missing partials are left out.
+pub fn compile_partial(
+    ctx: &Context,
+    name: &str,
+    compiled: &dyn Fn(&str) -> bool,
+    partials: &mut PartialsMap,
+) -> Result<()> {
+    if compiled(name) || partials.contains_key(name) {
+        return Ok(());
+    }
 
-        Ok((tokens, partials))
+    // Missing partials are left out and render as nothing.
+    let Some(partial) = ctx.load_partial(name)? else {
+        return Ok(());
+    };
+
+    // Insert a placeholder so we don't recurse off to infinity.
+    partials.insert(name.to_string(), Vec::new());
+
+    let (tokens, names) = parse(
+        ctx,
+        &mut partial.source.chars(),
+        "{{",
+        "}}",
+        Some(&partial.origin),
+    )?;
+    for name in names {
+        compile_partial(ctx, &name, compiled, partials)?;
+    }
+
+    // Set final compiled tokens for *this* partial
+    partials.insert(name.to_string(), tokens);
+
+    Ok(())
+}
+
+/// Returns the names of the partials and parents `tokens` use directly.
+pub fn dependencies(tokens: &[Token]) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    add_dependencies(tokens, &mut names);
+    names
+}
+
+fn add_dependencies(tokens: &[Token], names: &mut BTreeSet<String>) {
+    for token in tokens.iter() {
+        match *token {
+            Token::Partial(ref name, _, _, _) => {
+                names.insert(name.clone());
+            }
+            Token::Parent(ref name, _, ref children, _, _, _) => {
+                names.insert(name.clone());
+                add_dependencies(children, names);
+            }
+            Token::Section(_, _, ref children, _, _, _, _)
+            | Token::Block(_, _, ref children, _, _, _) => add_dependencies(children, names),
+            #[cfg(feature = "CFEngine")]
+            Token::TopSection(ref children, _) => add_dependencies(children, names),
+            _ => {}
+        }
     }
 }
 
@@ -106,7 +202,7 @@ mod tests {
 
     use crate::compiler::Compiler;
     use crate::context::Context;
-    use crate::parser::Token;
+    use crate::parser::{SectionSource, Span, Token};
 
     fn compile_str(template: &str) -> Vec<Token> {
         let ctx = Context::new(PathBuf::from("."));
@@ -120,23 +216,33 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    // All the templates below fit on the first line.
+    fn span(start: usize, end: usize) -> Span {
+        Span {
+            start,
+            end,
+            line: 1,
+            col: start + 1,
+        }
+    }
+
     #[test]
     fn test_compile_texts() {
         check_tokens(
             compile_str("hello world"),
-            &[Token::Text("hello world".to_string())],
+            &[Token::Text("hello world".to_string(), span(0, 11))],
         );
         check_tokens(
             compile_str("hello {world"),
-            &[Token::Text("hello {world".to_string())],
+            &[Token::Text("hello {world".to_string(), span(0, 12))],
         );
         check_tokens(
             compile_str("hello world}"),
-            &[Token::Text("hello world}".to_string())],
+            &[Token::Text("hello world}".to_string(), span(0, 12))],
         );
         check_tokens(
             compile_str("hello world}}"),
-            &[Token::Text("hello world}}".to_string())],
+            &[Token::Text("hello world}}".to_string(), span(0, 13))],
         );
     }
 
@@ -147,31 +253,40 @@ mod tests {
             &[Token::EscapedTag(
                 vec!["name".to_string()],
                 "{{ name }}".to_string(),
+                span(0, 10),
             )],
         );
 
         check_tokens(
             compile_str("before {{name}} after"),
             &[
-                Token::Text("before ".to_string()),
-                Token::EscapedTag(vec!["name".to_string()], "{{name}}".to_string()),
-                Token::Text(" after".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
+                Token::EscapedTag(
+                    vec!["name".to_string()],
+                    "{{name}}".to_string(),
+                    span(7, 15),
+                ),
+                Token::Text(" after".to_string(), span(15, 21)),
             ],
         );
 
         check_tokens(
             compile_str("before {{name}}"),
             &[
-                Token::Text("before ".to_string()),
-                Token::EscapedTag(vec!["name".to_string()], "{{name}}".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
+                Token::EscapedTag(
+                    vec!["name".to_string()],
+                    "{{name}}".to_string(),
+                    span(7, 15),
+                ),
             ],
         );
 
         check_tokens(
             compile_str("{{name}} after"),
             &[
-                Token::EscapedTag(vec!["name".to_string()], "{{name}}".to_string()),
-                Token::Text(" after".to_string()),
+                Token::EscapedTag(vec!["name".to_string()], "{{name}}".to_string(), span(0, 8)),
+                Token::Text(" after".to_string(), span(8, 14)),
             ],
         );
     }
@@ -183,31 +298,44 @@ mod tests {
             &[Token::UnescapedTag(
                 vec!["name".to_string()],
                 "{{{name}}}".to_string(),
+                span(0, 10),
             )],
         );
 
         check_tokens(
             compile_str("before {{{name}}} after"),
             &[
-                Token::Text("before ".to_string()),
-                Token::UnescapedTag(vec!["name".to_string()], "{{{name}}}".to_string()),
-                Token::Text(" after".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
+                Token::UnescapedTag(
+                    vec!["name".to_string()],
+                    "{{{name}}}".to_string(),
+                    span(7, 17),
+                ),
+                Token::Text(" after".to_string(), span(17, 23)),
             ],
         );
 
         check_tokens(
             compile_str("before {{{name}}}"),
             &[
-                Token::Text("before ".to_string()),
-                Token::UnescapedTag(vec!["name".to_string()], "{{{name}}}".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
+                Token::UnescapedTag(
+                    vec!["name".to_string()],
+                    "{{{name}}}".to_string(),
+                    span(7, 17),
+                ),
             ],
         );
 
         check_tokens(
             compile_str("{{{name}}} after"),
             &[
-                Token::UnescapedTag(vec!["name".to_string()], "{{{name}}}".to_string()),
-                Token::Text(" after".to_string()),
+                Token::UnescapedTag(
+                    vec!["name".to_string()],
+                    "{{{name}}}".to_string(),
+                    span(0, 10),
+                ),
+                Token::Text(" after".to_string(), span(10, 16)),
             ],
         );
     }
@@ -222,37 +350,40 @@ mod tests {
                 Vec::new(),
                 "{{# name}}".to_string(),
                 "{{/name}}".to_string(),
-                vec!["{{".to_string(), "".to_string(), "}}".to_string()],
+                SectionSource::new("", "{{", "}}"),
+                span(0, 19),
             )],
         );
 
         check_tokens(
             compile_str("before {{^name}}{{/name}} after"),
             &[
-                Token::Text("before ".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
                 Token::Section(
                     vec!["name".to_string()],
                     true,
                     Vec::new(),
                     "{{^name}}".to_string(),
                     "{{/name}}".to_string(),
-                    vec!["{{".to_string(), "".to_string(), "}}".to_string()],
+                    SectionSource::new("", "{{", "}}"),
+                    span(7, 25),
                 ),
-                Token::Text(" after".to_string()),
+                Token::Text(" after".to_string(), span(25, 31)),
             ],
         );
 
         check_tokens(
             compile_str("before {{#name}}{{/name}}"),
             &[
-                Token::Text("before ".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
                 Token::Section(
                     vec!["name".to_string()],
                     false,
                     Vec::new(),
                     "{{#name}}".to_string(),
                     "{{/name}}".to_string(),
-                    vec!["{{".to_string(), "".to_string(), "}}".to_string()],
+                    SectionSource::new("", "{{", "}}"),
+                    span(7, 25),
                 ),
             ],
         );
@@ -266,40 +397,39 @@ mod tests {
                     Vec::new(),
                     "{{#name}}".to_string(),
                     "{{/name}}".to_string(),
-                    vec!["{{".to_string(), "".to_string(), "}}".to_string()],
+                    SectionSource::new("", "{{", "}}"),
+                    span(0, 18),
                 ),
-                Token::Text(" after".to_string()),
+                Token::Text(" after".to_string(), span(18, 24)),
             ],
         );
 
         check_tokens(
             compile_str("before {{#a}} 1 {{^b}} 2 {{/b}} {{/a}} after"),
             &[
-                Token::Text("before ".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
                 Token::Section(
                     vec!["a".to_string()],
                     false,
                     vec![
-                        Token::Text(" 1 ".to_string()),
+                        Token::Text(" 1 ".to_string(), span(13, 16)),
                         Token::Section(
                             vec!["b".to_string()],
                             true,
-                            vec![Token::Text(" 2 ".to_string())],
+                            vec![Token::Text(" 2 ".to_string(), span(22, 25))],
                             "{{^b}}".to_string(),
                             "{{/b}}".to_string(),
-                            vec!["{{".to_string(), " 2 ".to_string(), "}}".to_string()],
+                            SectionSource::new(" 2 ", "{{", "}}"),
+                            span(16, 31),
                         ),
-                        Token::Text(" ".to_string()),
+                        Token::Text(" ".to_string(), span(31, 32)),
                     ],
                     "{{#a}}".to_string(),
                     "{{/a}}".to_string(),
-                    vec![
-                        "{{".to_string(),
-                        " 1 {{^b}} 2 {{/b}} ".to_string(),
-                        "}}".to_string(),
-                    ],
+                    SectionSource::new(" 1 {{^b}} 2 {{/b}} ", "{{", "}}"),
+                    span(7, 38),
                 ),
-                Token::Text(" after".to_string()),
+                Token::Text(" after".to_string(), span(38, 44)),
             ],
         );
     }
@@ -312,31 +442,47 @@ mod tests {
                 "test".to_string(),
                 "".to_string(),
                 "{{> test}}".to_string(),
+                span(0, 10),
             )],
         );
 
         check_tokens(
             compile_str("before {{>test}} after"),
             &[
-                Token::Text("before ".to_string()),
-                Token::Partial("test".to_string(), "".to_string(), "{{>test}}".to_string()),
-                Token::Text(" after".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
+                Token::Partial(
+                    "test".to_string(),
+                    "".to_string(),
+                    "{{>test}}".to_string(),
+                    span(7, 16),
+                ),
+                Token::Text(" after".to_string(), span(16, 22)),
             ],
         );
 
         check_tokens(
             compile_str("before {{> test}}"),
             &[
-                Token::Text("before ".to_string()),
-                Token::Partial("test".to_string(), "".to_string(), "{{> test}}".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
+                Token::Partial(
+                    "test".to_string(),
+                    "".to_string(),
+                    "{{> test}}".to_string(),
+                    span(7, 17),
+                ),
             ],
         );
 
         check_tokens(
             compile_str("{{>test}} after"),
             &[
-                Token::Partial("test".to_string(), "".to_string(), "{{>test}}".to_string()),
-                Token::Text(" after".to_string()),
+                Token::Partial(
+                    "test".to_string(),
+                    "".to_string(),
+                    "{{>test}}".to_string(),
+                    span(0, 9),
+                ),
+                Token::Text(" after".to_string(), span(9, 15)),
             ],
         );
     }
@@ -346,10 +492,21 @@ mod tests {
         check_tokens(
             compile_str("before {{=<% %>=}}<%name%> after"),
             &[
-                Token::Text("before ".to_string()),
-                Token::EscapedTag(vec!["name".to_string()], "<%name%>".to_string()),
-                Token::Text(" after".to_string()),
+                Token::Text("before ".to_string(), span(0, 7)),
+                Token::EscapedTag(
+                    vec!["name".to_string()],
+                    "<%name%>".to_string(),
+                    span(18, 26),
+                ),
+                Token::Text(" after".to_string(), span(26, 32)),
             ],
         );
     }
+
+    #[test]
+    fn test_dependencies() {
+        let tokens = compile_str("{{>a}}{{#s}}{{>b}}{{/s}}{{>*dynamic}}{{>a}}");
+        let names: Vec<_> = super::dependencies(&tokens).into_iter().collect();
+        assert_eq!(names, ["a", "b"]);
+    }
 }
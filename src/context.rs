@@ -1,28 +1,58 @@
 use crate::compiler;
+use crate::escape::{self, Escaper, HtmlEscaper};
+use crate::limits::Limits;
+use crate::loader::{self, FileSystemLoader, PartialLoader, PartialSource};
 use crate::template::{self, Template};
+use crate::template_set::{self, TemplateSet};
 use crate::{Error, Result};
 
 use std::fmt;
 use std::fs::File;
+use std::io::ErrorKind::NotFound;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::Arc;
 
 /// Represents the shared metadata needed to compile and render a mustache
 /// template.
 #[derive(Clone)]
 pub struct Context {
     pub template_path: PathBuf,
+    /// Directories to look for templates and partials in when they aren't
+    /// in `template_path`, in order.
+    pub search_paths: Vec<PathBuf>,
     pub template_extension: String,
+    /// Where partials are loaded from. When unset, partials are read from
+    /// `template_path` with the `template_extension`.
+    pub partial_loader: Option<Arc<dyn PartialLoader>>,
+    /// Whether rendering fails on names and partials that can't be resolved,
+    /// instead of rendering nothing for them.
+    pub strict: bool,
+    /// How `{{name}}` tags escape values. Defaults to HTML escaping.
+    pub escaper: Arc<dyn Escaper>,
+    /// Whether `compile_path` picks the escaper from the template's file
+    /// extension, such as `page.html.mustache` or `config.json`, when it
+    /// recognizes it.
+    pub auto_escape: bool,
+    /// Caps on the templates compiled and rendered from this context.
+    pub limits: Limits,
 }
 
 impl fmt::Debug for Context {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "Context {{ template_path: {:?}, template_extension: {} }}",
-            &*self.template_path, self.template_extension
-        )
+        f.debug_struct("Context")
+            .field("template_path", &self.template_path)
+            .field("search_paths", &self.search_paths)
+            .field("template_extension", &self.template_extension)
+            .field(
+                "partial_loader",
+                &self.partial_loader.as_ref().map(|_| ".."),
+            )
+            .field("strict", &self.strict)
+            .field("auto_escape", &self.auto_escape)
+            .field("limits", &self.limits)
+            .finish_non_exhaustive()
     }
 }
 
@@ -31,26 +61,108 @@ impl Context {
     pub fn new(path: PathBuf) -> Context {
         Context {
             template_path: path,
+            search_paths: Vec::new(),
             template_extension: "mustache".to_string(),
+            partial_loader: None,
+            strict: false,
+            escaper: Arc::new(HtmlEscaper),
+            auto_escape: false,
+            limits: Limits::default(),
         }
     }
 
+    /// Escapes `{{name}}` tags with `escaper` instead of HTML escaping.
+    pub fn with_escaper<E: Escaper + 'static>(mut self, escaper: E) -> Context {
+        self.escaper = Arc::new(escaper);
+        self
+    }
+
+    /// Turns picking the escaper from file extensions in `compile_path` on
+    /// or off.
+    pub fn with_auto_escape(mut self, auto_escape: bool) -> Context {
+        self.auto_escape = auto_escape;
+        self
+    }
+
+    /// Turns strict mode on or off for the templates compiled from this
+    /// context.
+    pub fn with_strict(mut self, strict: bool) -> Context {
+        self.strict = strict;
+        self
+    }
+
+    /// Caps the work compiling and rendering templates from this context
+    /// can do.
+    pub fn with_limits(mut self, limits: Limits) -> Context {
+        self.limits = limits;
+        self
+    }
+
+    /// Loads partials through `loader` instead of from `template_path`.
+    pub fn with_loader<L: PartialLoader + 'static>(mut self, loader: L) -> Context {
+        self.partial_loader = Some(Arc::new(loader));
+        self
+    }
+
+    /// Looks up templates and partials in `path` when they aren't in the
+    /// directories searched so far, such as a base theme that a customer
+    /// theme in `template_path` overrides.
+    pub fn with_search_path(mut self, path: PathBuf) -> Context {
+        self.search_paths.push(path);
+        self
+    }
+
+    /// Returns the directories templates and partials are looked up in,
+    /// starting with `template_path`.
+    pub fn search_roots(&self) -> impl Iterator<Item = &Path> {
+        Some(self.template_path.as_path())
+            .into_iter()
+            .chain(self.search_paths.iter().map(|path| path.as_path()))
+    }
+
+    /// Looks up the source of the named partial. Partials read from the
+    /// search roots record which root they were found in. Fails with
+    /// `Error::InvalidPartialName` for names that `check_partial_name`
+    /// rejects, whatever the loader.
+    pub fn load_partial(&self, name: &str) -> Result<Option<PartialSource>> {
+        loader::check_partial_name(name)?;
+
+        if let Some(ref loader) = self.partial_loader {
+            return loader.load(name);
+        }
+
+        for root in self.search_roots() {
+            let loader = FileSystemLoader {
+                path: root.to_path_buf(),
+                extension: self.template_extension.clone(),
+            };
+            if let Some(partial) = loader.load(name)? {
+                return Ok(Some(partial));
+            }
+        }
+
+        Ok(None)
+    }
+
     /// Compiles a template from a string
     pub fn compile<IT: Iterator<Item = char>>(&self, reader: IT) -> Result<Template> {
         let compiler = compiler::Compiler::new(self.clone(), reader);
         let (tokens, partials) = compiler.compile()?;
 
-        Ok(template::new(self.clone(), tokens, partials))
+        Ok(template::new(
+            self.clone(),
+            None,
+            tokens,
+            Arc::new(partials),
+        ))
     }
 
     /// Compiles a template from a path.
     pub fn compile_path<U: AsRef<Path>>(&self, path: U) -> Result<Template> {
         // FIXME(#6164): This should use the file decoding tools when they are
         // written. For now we'll just read the file and treat it as UTF-8file.
-        let mut path = self.template_path.join(path.as_ref());
-        path.set_extension(&self.template_extension);
+        let (path, mut file) = self.open(path.as_ref())?;
         let mut s = vec![];
-        let mut file = File::open(&path)?;
         file.read_to_end(&mut s)?;
 
         // TODO: maybe allow UTF-16 as well?
@@ -61,6 +173,53 @@ impl Context {
             }
         };
 
-        self.compile(template.chars())
+        let name = path.display().to_string();
+        let compiler =
+            compiler::Compiler::new(self.clone(), template.chars()).with_name(name.clone());
+        let (tokens, partials) = compiler.compile()?;
+
+        Ok(template::new(
+            self.for_path(&path),
+            Some(name),
+            tokens,
+            Arc::new(partials),
+        ))
+    }
+
+    // Opens the template at `path` in the first search root that has it.
+    fn open(&self, path: &Path) -> Result<(PathBuf, File)> {
+        let mut not_found = None;
+        for root in self.search_roots() {
+            let mut path = root.join(path);
+            path.set_extension(&self.template_extension);
+
+            match File::open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == NotFound => {
+                    not_found.get_or_insert(e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(not_found.expect("there is always a search root").into())
+    }
+
+    /// Compiles every template in `template_path` and its subdirectories
+    /// into a `TemplateSet`.
+    pub fn compile_dir(&self) -> Result<TemplateSet> {
+        template_set::load(self)
+    }
+
+    /// The context to render the template at `path` with, which has the
+    /// escaper for its extension when `auto_escape` is on.
+    pub(crate) fn for_path(&self, path: &Path) -> Context {
+        let mut ctx = self.clone();
+        if self.auto_escape {
+            if let Some(escaper) = escape::for_path(path, &self.template_extension) {
+                ctx.escaper = escaper;
+            }
+        }
+        ctx
     }
 }
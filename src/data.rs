@@ -1,18 +1,112 @@
+use indexmap::IndexMap;
 use serde::ser::{SerializeMap, SerializeSeq};
-use std::collections::HashMap;
 use std::fmt;
-use std::{cell::RefCell, collections::BTreeMap};
+use std::sync::{Arc, OnceLock};
 
-// for bug!
-use log::{error, log};
+use crate::{ContextLambda, Lambda};
 
+#[derive(Clone)]
 pub enum Data {
     Null,
     String(String),
     Bool(bool),
+    Int(i64),
+    /// Unsigned integers too large for an `i64`.
+    UInt(u64),
+    Float(f64),
     Vec(Vec<Data>),
-    Map(HashMap<String, Data>),
-    Fun(RefCell<Box<dyn FnMut(String) -> String + Send>>),
+    /// A map that keeps its keys in the order they were inserted. Maps from a
+    /// `serde_json::Value` come in serde_json's own order, which is sorted
+    /// by key unless its `preserve_order` feature is enabled.
+    Map(IndexMap<String, Data>),
+    /// A lambda. Clones share the same function, and two lambdas are equal
+    /// only if they are the same function.
+    Fun(Arc<Lambda>),
+    /// A lambda that is given a `LambdaContext`, compared like `Fun`.
+    Lambda(Arc<ContextLambda>),
+    /// A value computed the first time it is used, compared like `Fun`.
+    /// Clones share the value, so it is only computed once.
+    Computed(Arc<Computed>),
+    /// A list whose items are made while it is rendered, compared like
+    /// `Fun`.
+    Stream(Arc<Stream>),
+}
+
+/// A value computed by a function the first time it is needed, so data that
+/// templates rarely use doesn't have to be built up front.
+///
+/// ```rust
+/// use mustache::MapBuilder;
+///
+/// let data = MapBuilder::new()
+///     .insert_computed("items", || {
+///         MapBuilder::new().insert_str("name", "costly").build()
+///     })
+///     .build();
+/// let template = mustache::compile_str("{{#items}}{{name}}{{/items}}").unwrap();
+/// assert_eq!(template.render_data_to_string(&data).unwrap(), "costly");
+/// ```
+pub struct Computed {
+    f: Box<dyn Fn() -> Data + Send + Sync>,
+    value: OnceLock<Data>,
+}
+
+impl Computed {
+    /// Wraps the function that computes the value.
+    pub fn new<F>(f: F) -> Computed
+    where
+        F: Fn() -> Data + Send + Sync + 'static,
+    {
+        Computed {
+            f: Box::new(f),
+            value: OnceLock::new(),
+        }
+    }
+
+    /// Returns the value, computing it if this is the first time.
+    pub fn get(&self) -> &Data {
+        self.value.get_or_init(|| (self.f)())
+    }
+}
+
+/// A list whose items are made by an iterator while it is rendered, so long
+/// lists can be written out without holding all of their items at once.
+///
+/// The function is called for a new iterator every time the list is used,
+/// including when an inverted section checks whether it is empty.
+///
+/// ```rust
+/// use mustache::{Data, MapBuilder};
+///
+/// let data = MapBuilder::new()
+///     .insert_stream("rows", || (1..=3).map(Data::Int))
+///     .build();
+/// let template = mustache::compile_str("{{#rows}}<{{.}}>{{/rows}}").unwrap();
+/// let mut out = Vec::new();
+/// template.render_data(&mut out, &data).unwrap();
+/// assert_eq!(out, b"<1><2><3>");
+/// ```
+pub struct Stream {
+    f: Box<dyn Fn() -> Box<dyn Iterator<Item = Data>> + Send + Sync>,
+}
+
+impl Stream {
+    /// Wraps the function that makes the iterator.
+    pub fn new<F, I>(f: F) -> Stream
+    where
+        F: Fn() -> I + Send + Sync + 'static,
+        I: IntoIterator<Item = Data>,
+        I::IntoIter: 'static,
+    {
+        Stream {
+            f: Box::new(move || Box::new(f().into_iter())),
+        }
+    }
+
+    /// Returns a new iterator over the items.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Data>> {
+        (self.f)()
+    }
 }
 
 impl serde::Serialize for Data {
@@ -24,6 +118,9 @@ impl serde::Serialize for Data {
             Data::Null => serializer.serialize_none(),
             Data::String(ref v) => serializer.serialize_str(v),
             Data::Bool(v) => serializer.serialize_bool(v),
+            Data::Int(v) => serializer.serialize_i64(v),
+            Data::UInt(v) => serializer.serialize_u64(v),
+            Data::Float(v) => serializer.serialize_f64(v),
             Data::Vec(ref v) => {
                 let mut seq = serializer.serialize_seq(Some(v.len()))?;
                 for e in v {
@@ -32,14 +129,15 @@ impl serde::Serialize for Data {
                 seq.end()
             }
             Data::Map(ref v) => {
-                let v: BTreeMap<_, _> = v.iter().collect();
                 let mut map = serializer.serialize_map(Some(v.len()))?;
                 for (k, va) in v {
                     map.serialize_entry(k, va)?;
                 }
                 map.end()
             }
-            Data::Fun(_) => serializer.serialize_unit(),
+            Data::Fun(_) | Data::Lambda(_) => serializer.serialize_unit(),
+            Data::Computed(ref c) => c.get().serialize(serializer),
+            Data::Stream(ref s) => serializer.collect_seq(s.iter()),
         }
     }
 }
@@ -51,12 +149,15 @@ impl PartialEq for Data {
             (Data::Null, Data::Null) => true,
             (Data::String(v0), Data::String(v1)) => v0 == v1,
             (Data::Bool(v0), Data::Bool(v1)) => v0 == v1,
+            (Data::Int(v0), Data::Int(v1)) => v0 == v1,
+            (Data::UInt(v0), Data::UInt(v1)) => v0 == v1,
+            (Data::Float(v0), Data::Float(v1)) => v0 == v1,
             (Data::Vec(v0), Data::Vec(v1)) => v0 == v1,
             (Data::Map(v0), Data::Map(v1)) => v0 == v1,
-            (Data::Fun(_), &Data::Fun(_)) => {
-                bug!("Cannot compare closures");
-                false
-            }
+            (Data::Fun(f0), Data::Fun(f1)) => Arc::ptr_eq(f0, f1),
+            (Data::Lambda(f0), Data::Lambda(f1)) => Arc::ptr_eq(f0, f1),
+            (Data::Computed(c0), Data::Computed(c1)) => Arc::ptr_eq(c0, c1),
+            (Data::Stream(s0), Data::Stream(s1)) => Arc::ptr_eq(s0, s1),
             (_, _) => false,
         }
     }
@@ -68,9 +169,18 @@ impl fmt::Debug for Data {
             Data::Null => write!(f, "Null"),
             Data::String(ref v) => write!(f, "StrVal({v})"),
             Data::Bool(v) => write!(f, "Bool({v:?})"),
+            Data::Int(v) => write!(f, "Int({v})"),
+            Data::UInt(v) => write!(f, "UInt({v})"),
+            Data::Float(v) => write!(f, "Float({v:?})"),
             Data::Vec(ref v) => write!(f, "VecVal({v:?})"),
             Data::Map(ref v) => write!(f, "Map({v:?})"),
             Data::Fun(_) => write!(f, "Fun(...)"),
+            Data::Lambda(_) => write!(f, "Lambda(...)"),
+            Data::Computed(ref c) => match c.value.get() {
+                Some(v) => write!(f, "Computed({v:?})"),
+                None => write!(f, "Computed(...)"),
+            },
+            Data::Stream(_) => write!(f, "Stream(...)"),
         }
     }
 }
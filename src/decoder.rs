@@ -0,0 +1,230 @@
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::result::Result as StdResult;
+use std::str::FromStr;
+
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Unexpected, Visitor};
+use serde::forward_to_deserialize_any;
+
+use super::Data;
+
+/// Error type to represent decoding failure.
+///
+/// This type is not intended to be matched exhaustively as new variants
+/// may be added in future without a version bump.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Lambdas can't be turned into other types.
+    UnsupportedType,
+    Message(String),
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+/// Alias for a `Result` with the error type `mustache::decoder::Error`.
+pub type Result<T> = StdResult<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match *self {
+                Error::UnsupportedType => "unsupported type",
+                Error::Message(ref s) => s,
+            }
+        )
+    }
+}
+
+impl StdError for Error {}
+
+// Numbers may also have been given as strings, as `MapBuilder::insert_str`
+// does, so those are parsed when a number is asked for.
+macro_rules! deserialize_numbers {
+    ($($deserialize:ident => $visit:ident,)*) => {
+        $(
+            fn $deserialize<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                match self {
+                    Data::String(s) => {
+                        let n = parse(&s, &visitor)?;
+                        visitor.$visit(n)
+                    }
+                    Data::Computed(c) => c.get().clone().$deserialize(visitor),
+                    data => data.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+fn parse<'de, T: FromStr, V: Visitor<'de>>(s: &str, visitor: &V) -> Result<T> {
+    s.trim()
+        .parse()
+        .map_err(|_| de::Error::invalid_value(Unexpected::Str(s), visitor))
+}
+
+impl<'de> de::Deserializer<'de> for Data {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Data::Null => visitor.visit_unit(),
+            Data::String(s) => visitor.visit_string(s),
+            Data::Bool(b) => visitor.visit_bool(b),
+            Data::Int(n) => visitor.visit_i64(n),
+            Data::UInt(n) => visitor.visit_u64(n),
+            Data::Float(n) => visitor.visit_f64(n),
+            Data::Vec(v) => {
+                let mut seq = SeqDeserializer::new(v.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Data::Map(m) => {
+                // Keys go through `Data` too, so maps with number keys work.
+                let mut map =
+                    MapDeserializer::new(m.into_iter().map(|(k, v)| (Data::String(k), v)));
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+            Data::Fun(_) | Data::Lambda(_) => Err(Error::UnsupportedType),
+            Data::Computed(c) => c.get().clone().deserialize_any(visitor),
+            Data::Stream(s) => Data::Vec(s.iter().collect()).deserialize_any(visitor),
+        }
+    }
+
+    deserialize_numbers! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Data::Null => visitor.visit_none(),
+            Data::Computed(c) => c.get().clone().deserialize_option(visitor),
+            data => visitor.visit_some(data),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self {
+            // Unit variants are encoded as their name, and the others as a
+            // map from their name to their contents.
+            Data::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Data::Computed(c) => c.get().clone().deserialize_enum(name, variants, visitor),
+            Data::Map(m) if m.len() == 1 => {
+                let (variant, value) = m.into_iter().next().unwrap();
+                visitor.visit_enum(Enum { variant, value })
+            }
+            data => Err(de::Error::invalid_type(data.unexpected(), &"an enum")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool char str string bytes byte_buf unit seq tuple tuple_struct map
+        struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Data {
+    type Deserializer = Data;
+
+    fn into_deserializer(self) -> Data {
+        self
+    }
+}
+
+impl Data {
+    fn unexpected(&self) -> Unexpected<'_> {
+        match *self {
+            Data::Null => Unexpected::Unit,
+            Data::String(ref s) => Unexpected::Str(s),
+            Data::Bool(b) => Unexpected::Bool(b),
+            Data::Int(n) => Unexpected::Signed(n),
+            Data::UInt(n) => Unexpected::Unsigned(n),
+            Data::Float(n) => Unexpected::Float(n),
+            Data::Vec(_) | Data::Stream(_) => Unexpected::Seq,
+            Data::Map(_) => Unexpected::Map,
+            Data::Fun(_) | Data::Lambda(_) => Unexpected::Other("lambda"),
+            Data::Computed(ref c) => c.get().unexpected(),
+        }
+    }
+}
+
+struct Enum {
+    variant: String,
+    value: Data,
+}
+
+impl<'de> de::EnumAccess<'de> for Enum {
+    type Error = Error;
+    type Variant = Data;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Data)> {
+        let variant = seed.deserialize(Data::String(self.variant))?;
+        Ok((variant, self.value))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Data {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
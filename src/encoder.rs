@@ -1,8 +1,8 @@
-use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::fmt::{self, Display};
 use std::result::Result as StdResult;
 
+use indexmap::IndexMap;
 use serde::{self, Serialize, ser};
 
 use super::{Data, to_data};
@@ -40,7 +40,7 @@ impl fmt::Display for Error {
                 Error::NestedOptions => "nested Option types are not supported",
                 Error::UnsupportedType => "unsupported type",
                 Error::MissingElements => "no elements in value",
-                Error::KeyIsNotString => "key is not a string",
+                Error::KeyIsNotString => "key is not a string, number, bool or char",
                 Error::NoDataToEncode => "the encodable type created no data",
                 Error::Message(ref s) => s,
             }
@@ -80,43 +80,46 @@ impl serde::Serializer for Encoder {
     }
 
     fn serialize_u8(self, v: u8) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        Ok(Data::Int(v.into()))
     }
 
     fn serialize_i8(self, v: i8) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        Ok(Data::Int(v.into()))
     }
 
     fn serialize_u16(self, v: u16) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        Ok(Data::Int(v.into()))
     }
 
     fn serialize_i16(self, v: i16) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        Ok(Data::Int(v.into()))
     }
 
     fn serialize_u32(self, v: u32) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        Ok(Data::Int(v.into()))
     }
 
     fn serialize_i32(self, v: i32) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        Ok(Data::Int(v.into()))
     }
 
     fn serialize_i64(self, v: i64) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        Ok(Data::Int(v))
     }
 
     fn serialize_u64(self, v: u64) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        match i64::try_from(v) {
+            Ok(v) => Ok(Data::Int(v)),
+            Err(_) => Ok(Data::UInt(v)),
+        }
     }
 
     fn serialize_f32(self, v: f32) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        Ok(Data::Float(f32_to_f64(v)))
     }
 
     fn serialize_f64(self, v: f64) -> Result<Data> {
-        Ok(Data::String(v.to_string()))
+        Ok(Data::Float(v))
     }
 
     fn serialize_str(self, v: &str) -> Result<Data> {
@@ -166,7 +169,7 @@ impl serde::Serializer for Encoder {
     ) -> Result<Self::SerializeStructVariant> {
         Ok(SerializeStructVariant {
             name: String::from(variant),
-            map: HashMap::with_capacity(len),
+            map: IndexMap::with_capacity(len),
         })
     }
 
@@ -193,7 +196,7 @@ impl serde::Serializer for Encoder {
     }
 
     fn serialize_bytes(self, value: &[u8]) -> Result<Data> {
-        let vec = value.iter().map(|&b| Data::String(b.to_string())).collect();
+        let vec = value.iter().map(|&b| Data::Int(b.into())).collect();
 
         Ok(Data::Vec(vec))
     }
@@ -231,12 +234,18 @@ impl serde::Serializer for Encoder {
 
     fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap> {
         Ok(SerializeMap {
-            map: HashMap::with_capacity(len.unwrap_or(0)),
+            map: IndexMap::with_capacity(len.unwrap_or(0)),
             next_key: None,
         })
     }
 }
 
+// Widens an `f32` to the `f64` with the same shortest representation, so
+// `0.1f32` renders as `0.1` rather than `0.10000000149011612`.
+pub(crate) fn f32_to_f64(v: f32) -> f64 {
+    v.to_string().parse().unwrap_or(v.into())
+}
+
 #[doc(hidden)]
 pub struct SerializeVec {
     vec: Vec<Data>,
@@ -250,14 +259,14 @@ pub struct SerializeTupleVariant {
 
 #[doc(hidden)]
 pub struct SerializeMap {
-    map: HashMap<String, Data>,
+    map: IndexMap<String, Data>,
     next_key: Option<String>,
 }
 
 #[doc(hidden)]
 pub struct SerializeStructVariant {
     name: String,
-    map: HashMap<String, Data>,
+    map: IndexMap<String, Data>,
 }
 
 impl ser::SerializeSeq for SerializeVec {
@@ -322,7 +331,7 @@ impl ser::SerializeTupleVariant for SerializeTupleVariant {
     }
 
     fn end(self) -> Result<Data> {
-        let mut object = HashMap::new();
+        let mut object = IndexMap::new();
 
         object.insert(self.name, Data::Vec(self.vec));
 
@@ -338,13 +347,17 @@ impl ser::SerializeMap for SerializeMap {
     where
         T: Serialize + ?Sized,
     {
-        match to_data(key)? {
-            Data::String(s) => {
-                self.next_key = Some(s);
-                Ok(())
-            }
-            _ => Err(Error::KeyIsNotString),
-        }
+        // Scalar keys are turned into strings, the way they render, as they
+        // were when every scalar was encoded as a string.
+        self.next_key = Some(match to_data(key)? {
+            Data::String(s) => s,
+            Data::Int(n) => n.to_string(),
+            Data::UInt(n) => n.to_string(),
+            Data::Float(n) => n.to_string(),
+            Data::Bool(b) => b.to_string(),
+            _ => return Err(Error::KeyIsNotString),
+        });
+        Ok(())
     }
 
     fn serialize_value<T>(&mut self, value: &T) -> Result<()>
@@ -393,7 +406,7 @@ impl ser::SerializeStructVariant for SerializeStructVariant {
     }
 
     fn end(self) -> Result<Data> {
-        let mut object = HashMap::new();
+        let mut object = IndexMap::new();
 
         object.insert(self.name, Data::Map(self.map));
 
@@ -3,8 +3,9 @@ use std::fmt;
 use std::io::Error as StdIoError;
 use std::result::Result as StdResult;
 
-use crate::encoder;
-use crate::parser;
+use crate::parser::{self, Span};
+use crate::value::LambdaError;
+use crate::{decoder, encoder};
 
 /// Error type for any error within this library.
 ///
@@ -17,8 +18,36 @@ pub enum Error {
     NoFilename,
     IncompleteSection,
     Io(StdIoError),
+    Fmt(fmt::Error),
     Parser(parser::Error),
     Encoder(encoder::Error),
+    Decoder(decoder::Error),
+    /// An error that occurred in the named template or partial file.
+    Template(String, Box<Error>),
+    /// A name or partial that couldn't be resolved in strict mode, with the
+    /// tag that used it, where that tag is, and the template or partial it
+    /// is in, when that has a name.
+    Unresolved(String, String, Span, Option<String>),
+    /// The formatter couldn't reformat a template without changing what it
+    /// renders.
+    Unformattable,
+    /// A `TemplateSet` has no template with this name.
+    UnknownTemplate(String),
+    /// A partial name that could reach outside of the template directories,
+    /// such as `../secrets`.
+    InvalidPartialName(String),
+    /// Rendering nested sections and partials deeper than `Limits::max_depth`.
+    TooDeep(usize),
+    /// Rendering more than `Limits::max_output` bytes.
+    OutputTooLarge(usize),
+    /// Rendering section contents more than `Limits::max_iterations` times.
+    TooManyIterations(usize),
+    /// A template or partial longer than `Limits::max_source` bytes.
+    SourceTooLarge(usize),
+    /// A lambda that failed, with the name it was found by, where the tag
+    /// that called it is, the template or partial it is in, when that has a
+    /// name, and the lambda's error.
+    Lambda(String, Span, Option<String>, LambdaError),
 }
 
 pub type Result<T> = StdResult<T, Error>;
@@ -33,14 +62,45 @@ impl fmt::Display for Error {
                 Error::NoFilename => "a filename must be provided".to_string(),
                 Error::IncompleteSection => "a section wasn't completed".to_string(), // Is there a better way to put this?
                 Error::Io(ref err) => err.to_string(),
+                Error::Fmt(ref err) => err.to_string(),
                 Error::Parser(ref err) => err.to_string(),
                 Error::Encoder(ref err) => err.to_string(),
+                Error::Decoder(ref err) => err.to_string(),
+                Error::Template(ref name, ref err) => format!("{name}: {err}"),
+                Error::Unresolved(ref name, ref tag, ref span, None) => {
+                    format!("unresolved name \"{name}\" in {tag} at {span}")
+                }
+                Error::Unresolved(ref name, ref tag, ref span, Some(ref template)) => {
+                    format!("{template}: unresolved name \"{name}\" in {tag} at {span}")
+                }
+                Error::Unformattable => "the template can't be formatted safely".to_string(),
+                Error::UnknownTemplate(ref name) => format!("no template named {name:?}"),
+                Error::InvalidPartialName(ref name) => format!("invalid partial name {name:?}"),
+                Error::TooDeep(max) => format!("templates nested more than {max} levels deep"),
+                Error::OutputTooLarge(max) => format!("rendered output exceeds {max} bytes"),
+                Error::TooManyIterations(max) => {
+                    format!("section contents rendered more than {max} times")
+                }
+                Error::SourceTooLarge(max) => format!("template source exceeds {max} bytes"),
+                Error::Lambda(ref name, ref span, None, ref err) => {
+                    format!("lambda \"{name}\" at {span} failed: {err}")
+                }
+                Error::Lambda(ref name, ref span, Some(ref template), ref err) => {
+                    format!("{template}: lambda \"{name}\" at {span} failed: {err}")
+                }
             }
         )
     }
 }
 
-impl StdError for Error {}
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::Lambda(_, _, _, ref err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
 
 impl From<StdIoError> for Error {
     fn from(err: StdIoError) -> Error {
@@ -59,3 +119,9 @@ impl From<encoder::Error> for Error {
         Error::Encoder(err)
     }
 }
+
+impl From<decoder::Error> for Error {
+    fn from(err: decoder::Error) -> Error {
+        Error::Decoder(err)
+    }
+}
@@ -0,0 +1,241 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+/// `Escaper` escapes the values interpolated by `{{name}}` tags. Triple
+/// mustaches and `{{&name}}` tags are never escaped.
+pub trait Escaper: Send + Sync {
+    /// Escapes `value`, borrowing it when nothing needs escaping.
+    fn escape<'a>(&self, value: &'a str) -> Cow<'a, str>;
+}
+
+/// Escapes HTML special characters. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HtmlEscaper;
+
+/// Leaves values untouched, for plain text output.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoEscaper;
+
+/// Escapes values for use inside a JSON string literal.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonEscaper;
+
+/// Escapes XML special characters, using only the predefined XML entities.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XmlEscaper;
+
+/// Quotes values as a single shell word.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShellEscaper;
+
+/// Escapes LaTeX special characters.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LatexEscaper;
+
+// Replaces the characters `replace` has a replacement for, only allocating
+// when there is one.
+fn escape_with<'a, F>(value: &'a str, replace: F) -> Cow<'a, str>
+where
+    F: Fn(char) -> Option<&'static str>,
+{
+    match value.find(|c| replace(c).is_some()) {
+        None => Cow::Borrowed(value),
+        Some(pos) => {
+            let mut escaped = String::with_capacity(value.len() + 8);
+            escaped.push_str(&value[..pos]);
+            for c in value[pos..].chars() {
+                match replace(c) {
+                    Some(s) => escaped.push_str(s),
+                    None => escaped.push(c),
+                }
+            }
+            Cow::Owned(escaped)
+        }
+    }
+}
+
+impl Escaper for HtmlEscaper {
+    fn escape<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        escape_with(value, |c| match c {
+            '<' => Some("&lt;"),
+            '>' => Some("&gt;"),
+            '&' => Some("&amp;"),
+            '"' => Some("&quot;"),
+            '\'' => Some("&#39;"),
+            _ => None,
+        })
+    }
+}
+
+impl Escaper for NoEscaper {
+    fn escape<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(value)
+    }
+}
+
+impl Escaper for JsonEscaper {
+    fn escape<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        if !value.contains(|c: char| c == '"' || c == '\\' || c.is_control()) {
+            return Cow::Borrowed(value);
+        }
+
+        let mut escaped = String::with_capacity(value.len() + 8);
+        for c in value.chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                c if c.is_control() => {
+                    let _ = write!(escaped, "\\u{:04x}", c as u32);
+                }
+                c => escaped.push(c),
+            }
+        }
+        Cow::Owned(escaped)
+    }
+}
+
+impl Escaper for XmlEscaper {
+    fn escape<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        escape_with(value, |c| match c {
+            '<' => Some("&lt;"),
+            '>' => Some("&gt;"),
+            '&' => Some("&amp;"),
+            '"' => Some("&quot;"),
+            '\'' => Some("&apos;"),
+            _ => None,
+        })
+    }
+}
+
+impl Escaper for ShellEscaper {
+    fn escape<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        let safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./-_".contains(c);
+        if !value.is_empty() && value.chars().all(safe) {
+            return Cow::Borrowed(value);
+        }
+
+        // Single quotes keep everything literal, except single quotes
+        // themselves, which have to be closed, escaped and reopened.
+        Cow::Owned(format!("'{}'", value.replace('\'', "'\\''")))
+    }
+}
+
+impl Escaper for LatexEscaper {
+    fn escape<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        escape_with(value, |c| match c {
+            '&' => Some("\\&"),
+            '%' => Some("\\%"),
+            '$' => Some("\\$"),
+            '#' => Some("\\#"),
+            '_' => Some("\\_"),
+            '{' => Some("\\{"),
+            '}' => Some("\\}"),
+            '~' => Some("\\textasciitilde{}"),
+            '^' => Some("\\textasciicircum{}"),
+            '\\' => Some("\\textbackslash{}"),
+            _ => None,
+        })
+    }
+}
+
+/// Picks the escaper for files with the extension `ext`, if it is one we know
+/// about.
+pub fn for_extension(ext: &str) -> Option<Arc<dyn Escaper>> {
+    let escaper: Arc<dyn Escaper> = match &*ext.to_ascii_lowercase() {
+        "html" | "htm" | "xhtml" => Arc::new(HtmlEscaper),
+        "xml" | "svg" | "rss" | "atom" => Arc::new(XmlEscaper),
+        "json" | "js" => Arc::new(JsonEscaper),
+        "sh" | "bash" | "zsh" => Arc::new(ShellEscaper),
+        "tex" | "latex" => Arc::new(LatexEscaper),
+        "txt" | "text" | "md" | "yml" | "yaml" | "toml" | "ini" | "conf" | "cfg" => {
+            Arc::new(NoEscaper)
+        }
+        _ => return None,
+    };
+    Some(escaper)
+}
+
+/// Picks the escaper for the template at `path` from its extension. A
+/// template extension in front of another one is skipped, so
+/// `page.html.mustache` is treated as HTML.
+pub fn for_path(path: &Path, template_extension: &str) -> Option<Arc<dyn Escaper>> {
+    let ext = path.extension()?.to_str()?;
+
+    if ext == template_extension {
+        let stem = Path::new(path.file_stem()?);
+        if let Some(inner) = stem.extension().and_then(|ext| ext.to_str()) {
+            return for_extension(inner);
+        }
+    }
+
+    for_extension(ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_borrows_when_unchanged() {
+        assert!(matches!(
+            HtmlEscaper.escape("plain"),
+            Cow::Borrowed("plain")
+        ));
+        assert!(matches!(
+            JsonEscaper.escape("plain"),
+            Cow::Borrowed("plain")
+        ));
+        assert!(matches!(
+            ShellEscaper.escape("a.txt"),
+            Cow::Borrowed("a.txt")
+        ));
+    }
+
+    #[test]
+    fn test_escapers() {
+        assert_eq!(
+            HtmlEscaper.escape("<a href='x'>&"),
+            "&lt;a href=&#39;x&#39;&gt;&amp;"
+        );
+        assert_eq!(XmlEscaper.escape("\"it's\""), "&quot;it&apos;s&quot;");
+        assert_eq!(NoEscaper.escape("<&>"), "<&>");
+        assert_eq!(
+            JsonEscaper.escape("say \"hi\"\n\\\u{1}"),
+            "say \\\"hi\\\"\\n\\\\\\u0001"
+        );
+        assert_eq!(ShellEscaper.escape("it's $HOME"), "'it'\\''s $HOME'");
+        assert_eq!(ShellEscaper.escape(""), "''");
+        assert_eq!(
+            LatexEscaper.escape("50% of $x_1 ~ \\"),
+            "50\\% of \\$x\\_1 \\textasciitilde{} \\textbackslash{}"
+        );
+    }
+
+    #[test]
+    fn test_for_path() {
+        let escape = |path: &str| {
+            for_path(Path::new(path), "mustache").map(|e| e.escape("<'>").into_owned())
+        };
+        assert_eq!(
+            escape("page.html.mustache").as_deref(),
+            Some("&lt;&#39;&gt;")
+        );
+        assert_eq!(escape("feed.xml").as_deref(), Some("&lt;&apos;&gt;"));
+        assert_eq!(escape("notes.TXT.mustache").as_deref(), Some("<'>"));
+        assert_eq!(escape("run.sh").as_deref(), Some("'<'\\''>'"));
+        assert_eq!(escape("page.mustache"), None);
+        assert_eq!(escape("page"), None);
+        assert_eq!(
+            for_path(Path::new("report.tex"), "tex").map(|e| e.escape("$").into_owned()),
+            Some("\\$".to_string())
+        );
+    }
+}
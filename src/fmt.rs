@@ -0,0 +1,444 @@
+//! Reformats template source to a house style without changing how it
+//! renders.
+//!
+//! ```rust
+//! use mustache::fmt::{format, Options, Spacing};
+//!
+//! let options = Options {
+//!     spacing: Spacing::Padded,
+//!     indent: Some("  ".to_string()),
+//!     ..Options::default()
+//! };
+//! let source = "{{^items}}\n{{^empty}}\n{{!none}}\n{{/empty}}\n{{/items}}\n";
+//! assert_eq!(
+//!     format(source, &options).unwrap(),
+//!     "{{^ items }}\n  {{^ empty }}\n    {{!none}}\n  {{/ empty }}\n{{/ items }}\n"
+//! );
+//! ```
+//!
+//! Only tags are touched: text, including the whitespace around tags that
+//! aren't alone on their line, is part of the output and stays as is.
+//! Sections that aren't inverted pass their contents to lambdas as written,
+//! so those contents are left exactly as they are.
+
+use std::ops::Range;
+
+use crate::parser::{Parser, Token, Trivia};
+use crate::{Error, Result};
+
+/// How to space the name inside tags. Comments are left as written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Spacing {
+    /// Keep the spacing of each tag.
+    #[default]
+    Preserve,
+    /// `{{name}}`, `{{#name}}`.
+    Tight,
+    /// `{{ name }}`, `{{# name }}`.
+    Padded,
+}
+
+/// What the formatter changes. The default changes nothing.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub spacing: Spacing,
+    /// Rewrite tags using alternative delimiters with `{{` and `}}` and
+    /// drop the tags setting them. Templates whose text contains `{{` keep
+    /// their delimiters.
+    pub normalize_delimiters: bool,
+    /// Indent inverted section and comment tags that stand alone on their
+    /// line with this string once per enclosing section or block.
+    ///
+    /// Block and parent tags are left where they are, as their indentation
+    /// changes how they render, and so are the tags of sections that aren't
+    /// inverted, whose contents are kept as written.
+    pub indent: Option<String>,
+}
+
+/// Reformats `source` according to `options`. Fails with
+/// `Error::Unformattable` if the result wouldn't render the same, which
+/// should never happen.
+pub fn format(source: &str, options: &Options) -> Result<String> {
+    let (tokens, trivia) = Parser::new(&mut source.chars(), "{{", "}}").parse_with_trivia()?;
+
+    let mut marks = Vec::new();
+    let mut kept = Vec::new();
+    flatten(&tokens, source, &mut marks, &mut kept);
+    for trivia in trivia.iter() {
+        let span = trivia.span();
+        let kind = match *trivia {
+            Trivia::Comment(..) => Mark::Comment,
+            Trivia::SetDelimiters(ref otag, ref ctag, _, _) => Mark::SetDelimiters(otag, ctag),
+        };
+        marks.push((span.start, span.end, kind));
+    }
+    marks.sort_by_key(|&(start, _, _)| start);
+
+    if options.normalize_delimiters {
+        let formatted = Formatter::new(source, options, true).run(&marks, &kept)?;
+        if renders_same(&tokens, &formatted) {
+            return Ok(formatted);
+        }
+    }
+
+    let formatted = Formatter::new(source, options, false).run(&marks, &kept)?;
+    if renders_same(&tokens, &formatted) {
+        Ok(formatted)
+    } else {
+        Err(Error::Unformattable)
+    }
+}
+
+// A piece of the source: text to copy, or a tag to reformat.
+enum Mark<'a> {
+    Text,
+    Tag,
+    Comment,
+    SetDelimiters(&'a str, &'a str),
+    // The opening and closing tags of sections, blocks and parents. Only the
+    // tags of inverted sections get reindented, as blocks and parents are
+    // indented where they stand, and the indentation of the closing tag of
+    // other sections is part of what lambdas get.
+    Open(bool),
+    Close(bool),
+}
+
+// Also collects the contents of the outermost sections that aren't
+// inverted into `kept`, in order. The source is only needed to find the
+// tags of CFEngine top sections.
+#[cfg_attr(not(feature = "CFEngine"), allow(clippy::only_used_in_recursion))]
+fn flatten<'a>(
+    tokens: &'a [Token],
+    source: &str,
+    marks: &mut Vec<(usize, usize, Mark<'a>)>,
+    kept: &mut Vec<Range<usize>>,
+) {
+    for token in tokens.iter() {
+        match *token {
+            Token::Text(_, span) => marks.push((span.start, span.end, Mark::Text)),
+            Token::Section(_, false, ref children, ref otag, ref ctag, ref fdata, span) => {
+                if kept.last().is_none_or(|range| range.end <= span.start) {
+                    kept.push(fdata.range.clone());
+                }
+                marks.push((span.start, span.start + otag.len(), Mark::Open(false)));
+                flatten(children, source, marks, kept);
+                marks.push((span.end - ctag.len(), span.end, Mark::Close(false)));
+            }
+            Token::Section(_, true, ref children, ref otag, ref ctag, _, span)
+            | Token::Parent(_, _, ref children, ref otag, ref ctag, span)
+            | Token::Block(_, _, ref children, ref otag, ref ctag, span) => {
+                let reindent = matches!(*token, Token::Section(..));
+                marks.push((span.start, span.start + otag.len(), Mark::Open(reindent)));
+                flatten(children, source, marks, kept);
+                marks.push((span.end - ctag.len(), span.end, Mark::Close(reindent)));
+            }
+            #[cfg(feature = "CFEngine")]
+            Token::TopSection(ref children, span) => {
+                // These don't keep their tags, so find them in the source.
+                let tags = &source[span.start..span.end];
+                let open_end = tags.find("}}").map_or(0, |pos| pos + 2);
+                let close_start = tags.rfind("{{").unwrap_or(tags.len());
+                marks.push((span.start, span.start + open_end, Mark::Open(true)));
+                flatten(children, source, marks, kept);
+                marks.push((span.start + close_start, span.end, Mark::Close(true)));
+            }
+            ref token => {
+                if let Some(span) = tag_span(token) {
+                    marks.push((span.0, span.1, Mark::Tag));
+                }
+            }
+        }
+    }
+}
+
+fn tag_span(token: &Token) -> Option<(usize, usize)> {
+    match *token {
+        Token::EscapedTag(_, _, span)
+        | Token::UnescapedTag(_, _, span)
+        | Token::Partial(_, _, _, span)
+        | Token::DynamicPartial(_, _, _, span) => Some((span.start, span.end)),
+        #[cfg(feature = "CFEngine")]
+        Token::At(span)
+        | Token::JSON(_, _, span)
+        | Token::JSONMulti(_, _, span)
+        | Token::TopJSON(_, _, span)
+        | Token::TopJSONMulti(_, _, span) => Some((span.start, span.end)),
+        _ => None,
+    }
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    options: &'a Options,
+    normalize_delimiters: bool,
+    // The delimiters in the source, and the ones written out.
+    delimiters: (String, String),
+    out_delimiters: (String, String),
+    out: String,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str, options: &'a Options, normalize_delimiters: bool) -> Formatter<'a> {
+        Formatter {
+            source,
+            options,
+            normalize_delimiters,
+            delimiters: ("{{".to_string(), "}}".to_string()),
+            out_delimiters: ("{{".to_string(), "}}".to_string()),
+            out: String::new(),
+        }
+    }
+
+    fn run(mut self, marks: &[(usize, usize, Mark)], kept: &[Range<usize>]) -> Result<String> {
+        let source = self.source;
+        let mut pos = 0;
+        let mut depth = 0usize;
+        // Whether to drop the rest of the line of a tag we dropped.
+        let mut skip_line = false;
+        let mut kept = kept.iter().peekable();
+
+        for (i, &(start, end, ref mark)) in marks.iter().enumerate() {
+            while kept.next_if(|range| range.end < end).is_some() {}
+            if kept.peek().is_some_and(|range| range.start <= start) {
+                // Section contents lambdas get are copied as they are, only
+                // keeping track of the delimiters.
+                self.out.push_str(&source[pos..end]);
+                match *mark {
+                    Mark::SetDelimiters(otag, ctag) => {
+                        self.delimiters = (otag.to_string(), ctag.to_string());
+                        self.out_delimiters = self.delimiters.clone();
+                    }
+                    Mark::Open(_) => depth += 1,
+                    Mark::Close(_) => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                pos = end;
+                continue;
+            }
+
+            // Anything between marks is whitespace the parser stripped from
+            // around standalone tags.
+            let mut gap = &source[pos..start];
+            if !gap.chars().all(char::is_whitespace) {
+                return Err(Error::Unformattable);
+            }
+            if skip_line {
+                gap = skip_line_end(gap);
+                skip_line = false;
+            }
+
+            if let Mark::Close(_) = *mark {
+                depth = depth.saturating_sub(1);
+            }
+
+            let next = marks.get(i + 1).map_or(source.len(), |m| m.0);
+            let standalone = !matches!(*mark, Mark::Text)
+                && starts_line(source, pos, start)
+                && ends_line(&source[end..next], next == source.len());
+
+            let dropped = self.normalize_delimiters && matches!(*mark, Mark::SetDelimiters(..));
+            let reindent = match *mark {
+                Mark::Comment | Mark::SetDelimiters(..) => true,
+                Mark::Open(reindent) | Mark::Close(reindent) => reindent,
+                _ => false,
+            };
+
+            if standalone && (dropped || (reindent && self.options.indent.is_some())) {
+                let line = gap.trim_end_matches([' ', '\t']);
+                self.out.push_str(line);
+                if dropped {
+                    skip_line = true;
+                } else if let Some(ref indent) = self.options.indent {
+                    self.out.push_str(&indent.repeat(depth));
+                }
+            } else {
+                self.out.push_str(gap);
+            }
+
+            let tag = &source[start..end];
+            match *mark {
+                Mark::Text => self.out.push_str(tag),
+                Mark::SetDelimiters(otag, ctag) => {
+                    if !dropped {
+                        let (ref o, ref c) = self.out_delimiters;
+                        self.out.push_str(&format!("{o}={otag} {ctag}={c}"));
+                        self.out_delimiters = (otag.to_string(), ctag.to_string());
+                    }
+                    self.delimiters = (otag.to_string(), ctag.to_string());
+                }
+                _ => {
+                    let inner = self.inner(tag);
+                    let inner = normalize(inner, self.options.spacing);
+                    self.out.push_str(&self.out_delimiters.0);
+                    self.out.push_str(&inner);
+                    self.out.push_str(&self.out_delimiters.1);
+                }
+            }
+
+            if let Mark::Open(_) = *mark {
+                depth += 1;
+            }
+            pos = end;
+        }
+
+        let mut rest = &source[pos..];
+        if skip_line {
+            rest = skip_line_end(rest);
+        }
+        self.out.push_str(rest);
+
+        Ok(self.out)
+    }
+
+    // The tag without its delimiters.
+    fn inner<'t>(&self, tag: &'t str) -> &'t str {
+        let (ref otag, ref ctag) = self.delimiters;
+        tag.strip_prefix(otag.as_str())
+            .and_then(|tag| tag.strip_suffix(ctag.as_str()))
+            .unwrap_or(tag)
+    }
+}
+
+// Tags that start with one of these are only recognized when it comes first.
+#[cfg(not(feature = "CFEngine"))]
+const SIGILS: &str = "#^/>&<$!";
+#[cfg(feature = "CFEngine")]
+const SIGILS: &str = "#^/>&<$!%@";
+
+fn normalize(inner: &str, spacing: Spacing) -> String {
+    let pad = match spacing {
+        Spacing::Preserve => return inner.to_string(),
+        Spacing::Tight => "",
+        Spacing::Padded => " ",
+    };
+    let padded = |content: &str| {
+        if content.is_empty() {
+            String::new()
+        } else {
+            format!("{pad}{content}{pad}")
+        }
+    };
+
+    if inner.len() >= 2 && inner.starts_with('{') && inner.ends_with('}') {
+        return format!("{{{}}}", padded(inner[1..inner.len() - 1].trim()));
+    }
+
+    match inner.chars().next() {
+        // The whitespace in a comment may be part of what it says.
+        Some('!') => inner.to_string(),
+        Some(sigil) if SIGILS.contains(sigil) => {
+            let mut content = inner[1..].trim().to_string();
+            if sigil == '>' {
+                if let Some(name) = content.strip_prefix('*') {
+                    content = format!("*{}", name.trim_start());
+                }
+            }
+            format!("{sigil}{}", padded(&content))
+        }
+        _ => padded(inner.trim()),
+    }
+}
+
+// Whether only whitespace the parser stripped comes between the start of the
+// line and `start`.
+fn starts_line(source: &str, gap_start: usize, start: usize) -> bool {
+    let before = source[gap_start..start].trim_end_matches([' ', '\t']);
+    let line_start = gap_start + before.len();
+    line_start == 0 || source[..line_start].ends_with('\n')
+}
+
+// Whether `after`, the whitespace following a tag, ends its line.
+fn ends_line(after: &str, at_end: bool) -> bool {
+    let after = after.trim_start_matches([' ', '\t']);
+    after.starts_with('\n') || after.starts_with("\r\n") || (after.is_empty() && at_end)
+}
+
+// Drops the whitespace and newline ending a line from the start of `gap`.
+fn skip_line_end(gap: &str) -> &str {
+    let gap = gap.trim_start_matches([' ', '\t']);
+    gap.strip_prefix("\r\n")
+        .or_else(|| gap.strip_prefix('\n'))
+        .unwrap_or(gap)
+}
+
+// Compares what two templates render, ignoring where things are and how
+// their tags are written.
+fn renders_same(tokens: &[Token], formatted: &str) -> bool {
+    match Parser::new(&mut formatted.chars(), "{{", "}}").parse() {
+        Ok((formatted, _)) => shape(tokens) == shape(&formatted),
+        Err(_) => false,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Shape {
+    Text(String),
+    Leaf(String),
+    Open(String),
+    Close,
+}
+
+fn shape(tokens: &[Token]) -> Vec<Shape> {
+    let mut shapes = Vec::new();
+    add_shapes(tokens, &mut shapes);
+    shapes
+}
+
+fn add_shapes(tokens: &[Token], shapes: &mut Vec<Shape>) {
+    for token in tokens.iter() {
+        let (open, children) = match *token {
+            Token::Text(ref text, _) => {
+                // Text split around tags that were dropped renders the same.
+                match shapes.last_mut() {
+                    _ if text.is_empty() => {}
+                    Some(Shape::Text(last)) => last.push_str(text),
+                    _ => shapes.push(Shape::Text(text.clone())),
+                }
+                continue;
+            }
+            Token::EscapedTag(ref path, _, _) => {
+                shapes.push(Shape::Leaf(format!("escaped {path:?}")));
+                continue;
+            }
+            Token::UnescapedTag(ref path, _, _) => {
+                shapes.push(Shape::Leaf(format!("unescaped {path:?}")));
+                continue;
+            }
+            Token::Partial(ref name, ref indent, _, _) => {
+                shapes.push(Shape::Leaf(format!("partial {name:?} {indent:?}")));
+                continue;
+            }
+            Token::DynamicPartial(ref path, ref indent, _, _) => {
+                shapes.push(Shape::Leaf(format!("dynamic {path:?} {indent:?}")));
+                continue;
+            }
+            Token::Section(ref path, true, ref children, _, _, _, _) => {
+                (format!("inverted {path:?}"), children)
+            }
+            // Lambdas get the source and delimiters of sections.
+            Token::Section(ref path, false, ref children, _, _, ref fdata, _) => {
+                let (source, otag, ctag) = (fdata.as_str(), &fdata.otag, &fdata.ctag);
+                (
+                    format!("section {path:?} {source:?} {otag:?} {ctag:?}"),
+                    children,
+                )
+            }
+            Token::Parent(ref name, ref indent, ref children, _, _, _) => {
+                (format!("parent {name:?} {indent:?}"), children)
+            }
+            Token::Block(ref name, ref indent, ref children, _, _, _) => {
+                (format!("block {name:?} {indent:?}"), children)
+            }
+            #[cfg(feature = "CFEngine")]
+            Token::TopSection(ref children, _) => ("top".to_string(), children),
+            ref token => {
+                shapes.push(Shape::Leaf(format!("{token:?}")));
+                continue;
+            }
+        };
+
+        shapes.push(Shape::Open(open));
+        add_shapes(children, shapes);
+        shapes.push(Shape::Close);
+    }
+}
@@ -1,6 +1,7 @@
 #![cfg_attr(feature = "unstable", feature(external_doc))]
 #![cfg_attr(feature = "unstable", doc(include = "../README.md"))]
 
+extern crate indexmap;
 extern crate log;
 extern crate serde;
 extern crate serde_json;
@@ -12,26 +13,46 @@ use std::str;
 #[macro_use]
 mod macros;
 
+pub mod ast;
 mod builder;
 mod compiler;
 mod context;
 mod data;
+mod decoder;
 mod encoder;
 mod error;
+pub mod escape;
+pub mod fmt;
+mod limits;
+mod loader;
 mod parser;
+mod references;
 mod template;
+mod template_set;
+mod value;
 
 pub use crate::builder::{MapBuilder, VecBuilder};
 pub use crate::context::Context;
-pub use crate::data::Data;
+pub use crate::data::{Computed, Data, Stream};
+pub use crate::decoder::Error as DecoderError;
 pub use crate::encoder::Encoder;
 pub use crate::encoder::Error as EncoderError;
 pub use crate::encoder::{
     SerializeMap, SerializeStructVariant, SerializeTupleVariant, SerializeVec,
 };
 pub use crate::error::{Error, Result};
+pub use crate::escape::Escaper;
+pub use crate::limits::Limits;
+pub use crate::loader::{
+    ChainLoader, FileSystemLoader, PartialLoader, PartialSource, check_partial_name,
+};
 pub use crate::parser::Error as ParserError;
-pub use crate::template::Template;
+pub use crate::parser::Span;
+pub use crate::references::{Reference, ReferenceKind, References};
+pub use crate::template::{LambdaContext, Template};
+pub use crate::template_set::TemplateSet;
+pub use crate::value::{ContextLambda, ContextValue, Lambda, LambdaError, Value};
+pub use indexmap::IndexMap;
 
 pub fn to_data<T>(value: T) -> result::Result<Data, encoder::Error>
 where
@@ -40,6 +61,15 @@ where
     value.serialize(Encoder)
 }
 
+/// Turns `Data` back into a Rust type. Strings are parsed when a number is
+/// expected, so numbers inserted with `MapBuilder::insert_str` work too.
+pub fn from_data<T>(data: Data) -> result::Result<T, decoder::Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    T::deserialize(data)
+}
+
 /// Compiles a template from an `Iterator<char>`.
 pub fn compile_iter<T: Iterator<Item = char>>(iter: T) -> Result<Template> {
     Context::new(PathBuf::from(".")).compile(iter)
@@ -60,16 +90,20 @@ pub fn compile_path<U: AsRef<Path>>(path: U) -> Result<Template> {
                 .and_then(|ext| ext.to_str())
                 .unwrap_or("mustache");
 
-            let context = Context {
-                template_path: template_dir.to_path_buf(),
-                template_extension: extension.to_string(),
-            };
+            let mut context = Context::new(template_dir.to_path_buf());
+            context.template_extension = extension.to_string();
             context.compile_path(filename)
         }
         None => Err(Error::NoFilename),
     }
 }
 
+/// Compiles every template in the directory at `path` and its
+/// subdirectories.
+pub fn compile_dir<U: AsRef<Path>>(path: U) -> Result<TemplateSet> {
+    Context::new(path.as_ref().to_path_buf()).compile_dir()
+}
+
 /// Compiles a template from a string.
 pub fn compile_str(template: &str) -> Result<Template> {
     compile_iter(template.chars())
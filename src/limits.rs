@@ -0,0 +1,33 @@
+/// Caps on the work compiling and rendering templates can do, for templates
+/// that come from untrusted sources. Every limit is off by default, and going
+/// over one fails with its own `Error` variant.
+///
+/// ```rust
+/// use std::path::PathBuf;
+/// use mustache::{Context, Error, Limits};
+///
+/// let ctx = Context::new(PathBuf::from(".")).with_limits(Limits {
+///     max_iterations: Some(2),
+///     ..Limits::default()
+/// });
+/// let template = ctx.compile("{{#items}}.{{/items}}".chars()).unwrap();
+/// let err = template
+///     .render_to_string(&serde_json::json!({ "items": [1, 2, 3] }))
+///     .unwrap_err();
+/// assert!(matches!(err, Error::TooManyIterations(2)));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// How deeply sections, partials, blocks and lambda output can nest.
+    /// The top of the template is at depth 0. Templates with sections nested
+    /// deeper than this fail to compile.
+    pub max_depth: Option<usize>,
+    /// How many bytes a render can write. Strings lambdas render for
+    /// themselves can't grow past what's left either.
+    pub max_output: Option<usize>,
+    /// How many times a render can render section contents, counting every
+    /// item of a list.
+    pub max_iterations: Option<usize>,
+    /// How many bytes of source a template, partial or lambda result can have.
+    pub max_source: Option<usize>,
+}
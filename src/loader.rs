@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::ErrorKind::NotFound;
+use std::io::Read;
+use std::path::PathBuf;
+
+use crate::{Error, Result};
+
+/// The source of a partial found by a `PartialLoader`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialSource {
+    /// Where the partial was found, such as a file path. Errors in the
+    /// partial are reported against this name.
+    pub origin: String,
+    /// The template source of the partial.
+    pub source: String,
+    /// The search root the partial was found in, for loaders that read
+    /// from directories.
+    pub root: Option<PathBuf>,
+}
+
+/// `PartialLoader` looks up the source of partials by name when templates
+/// are compiled.
+///
+/// ```rust
+/// use std::collections::HashMap;
+/// use std::path::PathBuf;
+/// use mustache::Context;
+///
+/// let mut partials = HashMap::new();
+/// partials.insert("user".to_string(), "<b>{{name}}</b>".to_string());
+///
+/// let ctx = Context::new(PathBuf::from(".")).with_loader(partials);
+/// let template = ctx.compile("{{#users}}{{>user}}{{/users}}".chars()).unwrap();
+/// ```
+pub trait PartialLoader: Send + Sync {
+    /// Returns the source of the named partial, or `None` if this loader
+    /// doesn't know about it.
+    fn load(&self, name: &str) -> Result<Option<PartialSource>>;
+}
+
+/// Checks that `name` is a valid partial name.
+///
+/// Partial names are logical names made of segments separated by `/`, such
+/// as `components/button`, which loaders reading from directories map onto
+/// subdirectories. Segments can't be empty, `.` or `..`, or contain `\`, `:`
+/// or NUL characters, so names can't reach outside of the directory they are
+/// looked up in.
+pub fn check_partial_name(name: &str) -> Result<()> {
+    let valid = name.split('/').all(|segment| {
+        !segment.is_empty()
+            && segment != "."
+            && segment != ".."
+            && !segment.contains(['\\', ':', '\0'])
+    });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidPartialName(name.to_string()))
+    }
+}
+
+/// Loads partials from files in a directory, as `<path>/<name>.<extension>`.
+/// Names with `/` are looked up in subdirectories. Symlinks are followed as
+/// long as they lead to a file inside the directory; partials found through
+/// one that leads outside of it fail with `Error::InvalidPartialName`.
+#[derive(Clone, Debug)]
+pub struct FileSystemLoader {
+    pub path: PathBuf,
+    pub extension: String,
+}
+
+impl FileSystemLoader {
+    /// Construct a `FileSystemLoader` for `.mustache` files in `path`.
+    pub fn new(path: PathBuf) -> FileSystemLoader {
+        FileSystemLoader {
+            path,
+            extension: "mustache".to_string(),
+        }
+    }
+}
+
+impl PartialLoader for FileSystemLoader {
+    fn load(&self, name: &str) -> Result<Option<PartialSource>> {
+        check_partial_name(name)?;
+        let path = self.path.join(&(name.to_string() + "." + &self.extension));
+
+        // Symlinks could lead out of the directory, so check where the file
+        // really is.
+        let (root, real) = match (self.path.canonicalize(), path.canonicalize()) {
+            (Ok(root), Ok(real)) => (root, real),
+            (Err(ref e), _) | (_, Err(ref e)) if e.kind() == NotFound => return Ok(None),
+            (Err(e), _) | (_, Err(e)) => return Err(e.into()),
+        };
+        if !real.starts_with(&root) {
+            return Err(Error::InvalidPartialName(name.to_string()));
+        }
+
+        match File::open(&real) {
+            Ok(mut file) => {
+                let mut source = String::new();
+                file.read_to_string(&mut source)?;
+
+                Ok(Some(PartialSource {
+                    origin: path.display().to_string(),
+                    source,
+                    root: Some(self.path.clone()),
+                }))
+            }
+            // Missing files are simply unknown partials.
+            Err(ref e) if e.kind() == NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Serves partials from memory, keyed by name.
+impl PartialLoader for HashMap<String, String> {
+    fn load(&self, name: &str) -> Result<Option<PartialSource>> {
+        Ok(self.get(name).map(|source| PartialSource {
+            origin: name.to_string(),
+            source: source.clone(),
+            root: None,
+        }))
+    }
+}
+
+/// Tries a list of loaders in order, using the first one that knows about a
+/// partial.
+#[derive(Default)]
+pub struct ChainLoader {
+    loaders: Vec<Box<dyn PartialLoader>>,
+}
+
+impl ChainLoader {
+    /// Create an empty `ChainLoader`.
+    #[inline]
+    pub fn new() -> ChainLoader {
+        ChainLoader::default()
+    }
+
+    /// Add a loader to try after the ones already in the chain.
+    ///
+    /// ```rust
+    /// use std::collections::HashMap;
+    /// use std::path::PathBuf;
+    /// use mustache::{ChainLoader, FileSystemLoader};
+    ///
+    /// let overrides: HashMap<String, String> = HashMap::new();
+    /// let loader = ChainLoader::new()
+    ///     .push(overrides)
+    ///     .push(FileSystemLoader::new(PathBuf::from("templates")));
+    /// ```
+    #[inline]
+    pub fn push<L: PartialLoader + 'static>(mut self, loader: L) -> ChainLoader {
+        self.loaders.push(Box::new(loader));
+        self
+    }
+}
+
+impl PartialLoader for ChainLoader {
+    fn load(&self, name: &str) -> Result<Option<PartialSource>> {
+        for loader in self.loaders.iter() {
+            if let Some(partial) = loader.load(name)? {
+                return Ok(Some(partial));
+            }
+        }
+
+        Ok(None)
+    }
+}
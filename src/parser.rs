@@ -1,31 +1,115 @@
 use std::error::Error as StdError;
 use std::fmt;
 use std::mem;
+use std::ops::Range;
+use std::sync::Arc;
 
 // for bug!
 use log::{error, log};
 
+/// `Span` is the location of a token or an error in the template source.
+///
+/// `start` and `end` are byte offsets into the source, while `line` and `col`
+/// are the 1-based line and column of `start`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
+
 /// `Token` is a section of a compiled mustache string.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
-    Text(String),
-    EscapedTag(Vec<String>, String),
-    UnescapedTag(Vec<String>, String),
-    Section(Vec<String>, bool, Vec<Token>, String, String, Vec<String>),
-    IncompleteSection(Vec<String>, bool, String, bool),
-    Partial(String, String, String),
+    Text(String, Span),
+    EscapedTag(Vec<String>, String, Span),
+    UnescapedTag(Vec<String>, String, Span),
+    Section(
+        Vec<String>,
+        bool,
+        Vec<Token>,
+        String,
+        String,
+        SectionSource,
+        Span,
+    ),
+    IncompleteSection(Vec<String>, bool, String, bool, Span),
+    Partial(String, String, String, Span),
+    DynamicPartial(Vec<String>, String, String, Span),
+    Parent(String, String, Vec<Token>, String, String, Span),
+    IncompleteParent(String, String, String, Span),
+    Block(String, String, Vec<Token>, String, String, Span),
+    // Blocks can't be opened when CFEngine claims the `$` tag.
+    #[cfg_attr(feature = "CFEngine", allow(dead_code))]
+    IncompleteBlock(String, String, bool, String, Span),
     #[cfg(feature = "CFEngine")]
-    At,
+    At(Span),
     #[cfg(feature = "CFEngine")]
-    JSON(Vec<String>, String),
+    JSON(Vec<String>, String, Span),
     #[cfg(feature = "CFEngine")]
-    JSONMulti(Vec<String>, String),
+    JSONMulti(Vec<String>, String, Span),
     #[cfg(feature = "CFEngine")]
-    TopJSON(Vec<String>, String),
+    TopJSON(Vec<String>, String, Span),
     #[cfg(feature = "CFEngine")]
-    TopJSONMulti(Vec<String>, String),
+    TopJSONMulti(Vec<String>, String, Span),
     #[cfg(feature = "CFEngine")]
-    TopSection(Vec<Token>),
+    TopSection(Vec<Token>, Span),
+}
+
+/// The source of a section's contents as written, which lambdas are given,
+/// and the delimiters in effect in it. Sections nest, so rather than holding
+/// a copy this is a range into the source of the whole template.
+#[derive(Clone, Debug)]
+pub struct SectionSource {
+    pub source: Arc<str>,
+    pub range: Range<usize>,
+    pub otag: String,
+    pub ctag: String,
+}
+
+impl SectionSource {
+    #[cfg(test)]
+    pub fn new(source: &str, otag: &str, ctag: &str) -> SectionSource {
+        SectionSource {
+            source: source.into(),
+            range: 0..source.len(),
+            otag: otag.to_string(),
+            ctag: ctag.to_string(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source[self.range.clone()]
+    }
+}
+
+impl PartialEq for SectionSource {
+    fn eq(&self, other: &SectionSource) -> bool {
+        self.as_str() == other.as_str() && self.otag == other.otag && self.ctag == other.ctag
+    }
+}
+
+/// Tags that don't produce tokens, kept for tools that need to reproduce the
+/// template source.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trivia {
+    Comment(String, Span),
+    SetDelimiters(String, String, String, Span),
+}
+
+impl Trivia {
+    pub fn span(&self) -> Span {
+        match *self {
+            Trivia::Comment(_, span) | Trivia::SetDelimiters(_, _, _, span) => span,
+        }
+    }
 }
 
 /// Error type to represent parsing failure.
@@ -35,14 +119,32 @@ pub enum Token {
 #[derive(Debug, PartialEq)]
 #[non_exhaustive]
 pub enum Error {
-    BadClosingTag(char, char),
-    UnclosedTag,
-    UnclosedSection(String),
-    UnbalancedUnescapeTag,
-    EmptyTag,
-    EarlySectionClose(String),
-    MissingSetDelimeterClosingTag,
-    InvalidSetDelimeterSyntax,
+    BadClosingTag(char, char, Span),
+    UnclosedTag(Span),
+    UnclosedSection(String, Span),
+    UnbalancedUnescapeTag(Span),
+    EmptyTag(Span),
+    EarlySectionClose(String, Span),
+    MissingSetDelimeterClosingTag(Span),
+    InvalidSetDelimeterSyntax(Span),
+    TooDeep(usize, Span),
+}
+
+impl Error {
+    /// Returns the location in the template source where the error occurred.
+    pub fn span(&self) -> Span {
+        match *self {
+            Error::BadClosingTag(_, _, span)
+            | Error::UnclosedTag(span)
+            | Error::UnclosedSection(_, span)
+            | Error::UnbalancedUnescapeTag(span)
+            | Error::EmptyTag(span)
+            | Error::EarlySectionClose(_, span)
+            | Error::MissingSetDelimeterClosingTag(span)
+            | Error::InvalidSetDelimeterSyntax(span)
+            | Error::TooDeep(_, span) => span,
+        }
+    }
 }
 
 impl StdError for Error {}
@@ -51,22 +153,27 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // Provide more information where possible
         match *self {
-            Error::BadClosingTag(actual, expected) => write!(
+            Error::BadClosingTag(actual, expected, _) => write!(
                 f,
                 "character {actual:?} was unexpected in the closing tag, expected {expected:?}",
             ),
-            Error::UnclosedSection(ref name) => write!(f, "found an unclosed section: {name:?}"),
-            Error::EarlySectionClose(ref name) => {
+            Error::UnclosedSection(ref name, _) => {
+                write!(f, "found an unclosed section: {name:?}")
+            }
+            Error::EarlySectionClose(ref name, _) => {
                 write!(f, "found a closing tag for an unopened section {name:?}")
             }
-            Error::UnclosedTag => write!(f, "found an unclosed tag"),
-            Error::UnbalancedUnescapeTag => write!(f, "found an unbalanced unescape tag"),
-            Error::EmptyTag => write!(f, "found an empty tag",),
-            Error::MissingSetDelimeterClosingTag => {
+            Error::UnclosedTag(_) => write!(f, "found an unclosed tag"),
+            Error::UnbalancedUnescapeTag(_) => write!(f, "found an unbalanced unescape tag"),
+            Error::EmptyTag(_) => write!(f, "found an empty tag",),
+            Error::MissingSetDelimeterClosingTag(_) => {
                 write!(f, "missing the new closing tag in set delimeter tag")
             }
-            Error::InvalidSetDelimeterSyntax => write!(f, "invalid set delimeter tag syntax"),
-        }
+            Error::InvalidSetDelimeterSyntax(_) => write!(f, "invalid set delimeter tag syntax"),
+            Error::TooDeep(max, _) => write!(f, "sections nested more than {max} levels deep"),
+        }?;
+
+        write!(f, " at {}", self.span())
     }
 }
 
@@ -81,9 +188,16 @@ pub struct Parser<'a, T: 'a> {
     reader: &'a mut T,
     ch: Option<char>,
     lookahead: Option<char>,
+    offset: usize,
     line: usize,
     col: usize,
     content: String,
+    text_start: Span,
+    tag_start: Span,
+    after_standalone: bool,
+    // Whether the last text token came right after a standalone tag.
+    text_after_standalone: bool,
+    ate_line: bool,
     state: ParserState,
     opening_tag: String,
     closing_tag: String,
@@ -91,7 +205,14 @@ pub struct Parser<'a, T: 'a> {
     closing_tag_chars: Vec<char>,
     tag_position: usize,
     tokens: Vec<Token>,
+    // Where the sections, parents and blocks that are still open are in
+    // `tokens`, and how many can be open at once.
+    open: Vec<usize>,
+    max_depth: Option<usize>,
+    // The source read so far, which sections point into once it is complete.
+    source: String,
     partials: Vec<String>,
+    trivia: Vec<Trivia>,
 }
 
 enum ParserState {
@@ -107,9 +228,15 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
             reader,
             ch: None,
             lookahead: None,
+            offset: 0,
             line: 1,
             col: 1,
             content: String::new(),
+            text_start: Span::default(),
+            tag_start: Span::default(),
+            after_standalone: false,
+            text_after_standalone: false,
+            ate_line: false,
             state: ParserState::Text,
             opening_tag: opening_tag.to_string(),
             closing_tag: closing_tag.to_string(),
@@ -117,14 +244,38 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
             closing_tag_chars: closing_tag.chars().collect(),
             tag_position: 0,
             tokens: Vec::new(),
+            open: Vec::new(),
+            max_depth: None,
+            source: String::new(),
             partials: Vec::new(),
+            trivia: Vec::new(),
         };
 
         parser.bump();
+        parser.text_start = parser.here();
         parser
     }
 
+    /// Fails with `Error::TooDeep` on sections, parents and blocks nested
+    /// more than `max_depth` levels deep.
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Parser<'a, T> {
+        self.max_depth = max_depth;
+        self
+    }
+
     fn bump(&mut self) {
+        // Advance the position past the character we are leaving behind.
+        if let Some(ch) = self.ch {
+            self.source.push(ch);
+            self.offset += ch.len_utf8();
+            if ch == '\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+        }
+
         match self.lookahead.take() {
             None => {
                 self.ch = self.reader.next();
@@ -133,14 +284,23 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
                 self.ch = Some(ch);
             }
         }
+    }
 
-        if let Some(ch) = self.ch {
-            if ch == '\n' {
-                self.line += 1;
-                self.col = 1;
-            } else {
-                self.col += 1;
-            }
+    /// Returns an empty span at the current character.
+    fn here(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset,
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    /// Returns a span from the start of `start` up to the current character.
+    fn span_from(&self, start: Span) -> Span {
+        Span {
+            end: self.offset,
+            ..start
         }
     }
 
@@ -163,17 +323,30 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
 
     /// Parse the template into tokens and a list of partial files.
     pub fn parse(mut self) -> Result<(Vec<Token>, Vec<String>), Error> {
+        self.parse_all()?;
+        Ok((self.tokens, self.partials))
+    }
+
+    /// Parse the template into tokens, also returning the comments and
+    /// delimiter changes, which don't produce tokens.
+    pub fn parse_with_trivia(mut self) -> Result<(Vec<Token>, Vec<Trivia>), Error> {
+        self.parse_all()?;
+        Ok((self.tokens, self.trivia))
+    }
+
+    fn parse_all(&mut self) -> Result<(), Error> {
         let mut curly_brace_tag = false;
 
         while let Some(ch) = self.ch {
             match self.state {
                 ParserState::Text => {
                     if ch == self.opening_tag_chars[0] {
+                        self.tag_start = self.here();
                         if self.opening_tag_chars.len() > 1 {
                             self.tag_position = 1;
                             self.state = ParserState::OpeningTag;
                         } else {
-                            self.add_text();
+                            self.add_text(self.tag_start.start);
                             self.state = ParserState::Tag;
                         }
                     } else {
@@ -184,7 +357,7 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
                 ParserState::OpeningTag => {
                     if ch == self.opening_tag_chars[self.tag_position] {
                         if self.tag_position == self.opening_tag_chars.len() - 1 {
-                            self.add_text();
+                            self.add_text(self.tag_start.start);
                             curly_brace_tag = false;
                             self.state = ParserState::Tag;
                         } else {
@@ -235,7 +408,11 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
                         }
                     } else {
                         let expected = self.closing_tag_chars[self.tag_position];
-                        return Err(Error::BadClosingTag(ch, expected));
+                        let span = Span {
+                            end: self.offset + ch.len_utf8(),
+                            ..self.here()
+                        };
+                        return Err(Error::BadClosingTag(ch, expected, span));
                     }
                 }
             }
@@ -243,39 +420,63 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
 
         match self.state {
             ParserState::Text => {
-                self.add_text();
+                self.add_text(self.offset);
             }
             ParserState::OpeningTag => {
                 self.not_otag();
-                self.add_text();
+                self.add_text(self.offset);
             }
             ParserState::ClosingTag => {
                 self.not_ctag();
-                self.add_text();
+                self.add_text(self.offset);
             }
-            ParserState::Tag => return Err(Error::UnclosedTag),
+            ParserState::Tag => return Err(Error::UnclosedTag(self.span_from(self.tag_start))),
         }
 
         // Check that we don't have any incomplete sections.
-        for token in self.tokens.iter().rev() {
-            if let Token::IncompleteSection(ref path, _, _, _) = *token {
-                return Err(Error::UnclosedSection(path.join(".")));
+        if let Some(&last) = self.open.last() {
+            match self.tokens[last] {
+                Token::IncompleteSection(ref path, _, _, _, span) => {
+                    return Err(Error::UnclosedSection(path.join("."), span));
+                }
+                Token::IncompleteParent(ref name, _, _, span)
+                | Token::IncompleteBlock(ref name, _, _, _, span) => {
+                    return Err(Error::UnclosedSection(name.clone(), span));
+                }
+                _ => {}
             }
         }
 
-        let Parser {
-            tokens, partials, ..
-        } = self;
+        // Now that the source is complete, point the sections into it.
+        let source: Arc<str> = mem::take(&mut self.source).into();
+        share_source(&mut self.tokens, &source);
 
-        Ok((tokens, partials))
+        Ok(())
     }
 
-    fn add_text(&mut self) {
+    fn add_text(&mut self, end: usize) {
         if !self.content.is_empty() {
             let mut content = String::new();
             mem::swap(&mut content, &mut self.content);
 
-            self.tokens.push(Token::Text(content));
+            let span = Span {
+                end,
+                ..self.text_start
+            };
+            self.tokens.push(Token::Text(content, span));
+            self.text_after_standalone = self.after_standalone;
+            self.after_standalone = false;
+        }
+    }
+
+    // Replace the last token, which must be text, with its first `pos` bytes.
+    fn trim_last_text(&mut self, pos: usize) {
+        if let Some(Token::Text(s, span)) = self.tokens.pop() {
+            let span = Span {
+                end: span.start + pos,
+                ..span
+            };
+            self.tokens.push(Token::Text(s[0..pos].to_string(), span));
         }
     }
 
@@ -292,20 +493,33 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
             }
         }
 
-        match self.tokens.last() {
+        // A parent tag that shares its line with the next tag doesn't count
+        // as content on that line.
+        let mut last = self.tokens.len();
+        while last > 0 && matches!(self.tokens[last - 1], Token::IncompleteParent(..)) {
+            last -= 1;
+        }
+        let skipped_parent = last < self.tokens.len();
+
+        match self.tokens[..last].last() {
             // If the last token ends with a newline (or there is no previous
             // token), then this token is standalone.
             None => TokenClass::StandAlone,
 
-            Some(Token::IncompleteSection(_, _, _, true)) => TokenClass::StandAlone,
+            // Likewise if the last token was a standalone tag, as it ate the
+            // newline that ended its line.
+            Some(_) if self.after_standalone => TokenClass::StandAlone,
+
+            Some(Token::IncompleteSection(_, _, _, true, _)) => TokenClass::StandAlone,
 
-            Some(Token::Text(s)) if !s.is_empty() => {
+            Some(Token::Text(s, _)) if !s.is_empty() => {
                 // Look for the last newline character that may have whitespace
                 // following it.
                 match s.rfind(|c: char| c == '\n' || !c.is_whitespace()) {
-                    // It's all whitespace.
+                    // It's all whitespace. We can only trim it if it starts
+                    // the template or follows a standalone tag.
                     None => {
-                        if self.tokens.len() == 1 {
+                        if (last == 1 || self.text_after_standalone) && !skipped_parent {
                             TokenClass::WhiteSpace(s.clone(), 0)
                         } else {
                             TokenClass::Normal
@@ -315,8 +529,10 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
                         if s.as_bytes()[pos] == b'\n' {
                             if pos == s.len() - 1 {
                                 TokenClass::StandAlone
-                            } else {
+                            } else if !skipped_parent {
                                 TokenClass::WhiteSpace(s.clone(), pos + 1)
+                            } else {
+                                TokenClass::Normal
                             }
                         } else {
                             TokenClass::Normal
@@ -339,18 +555,19 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
                     self.bump();
                 }
                 self.bump();
+                self.ate_line = true;
                 true
             }
-            TokenClass::WhiteSpace(s, pos) => {
+            TokenClass::WhiteSpace(_, pos) => {
                 if self.ch_is('\r') {
                     self.bump();
                 }
                 self.bump();
 
                 // Trim the whitespace from the last token.
-                self.tokens.pop();
-                self.tokens.push(Token::Text(s[0..pos].to_string()));
+                self.trim_last_text(pos);
 
+                self.ate_line = true;
                 true
             }
         }
@@ -359,170 +576,140 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
     fn add_tag(&mut self) -> Result<(), Error> {
         self.bump();
 
+        let span = self.span_from(self.tag_start);
+        self.ate_line = false;
+        self.add_tag_content(span)?;
+        self.after_standalone = self.ate_line;
+
+        // Whatever comes next, including any whitespace we didn't eat, starts
+        // after this tag.
+        self.text_start = self.here();
+
+        Ok(())
+    }
+
+    fn add_tag_content(&mut self, span: Span) -> Result<(), Error> {
         let tag = self.opening_tag.clone() + &self.content + &self.closing_tag;
 
         // Move the content to avoid a copy.
         let mut content = String::new();
         mem::swap(&mut content, &mut self.content);
         let len = content.len();
-        deny_blank(&content)?;
+        deny_blank(&content, span)?;
         let content = content;
 
         match content.as_bytes()[0] as char {
             '!' => {
                 // ignore comments
                 self.eat_whitespace();
+                self.trivia.push(Trivia::Comment(tag, span));
             }
             #[cfg(feature = "CFEngine")]
             '%' => {
                 // Data to be rendered as multi-line JSON representation
                 let name = &content[1..len];
-                let name = get_name_or_implicit(name)?;
+                let name = get_name_or_implicit(name, span)?;
 
                 self.tokens
                     .push(if name.first() == Some(&"-top-".to_string()) {
-                        Token::TopJSONMulti(name, tag)
+                        Token::TopJSONMulti(name, tag, span)
                     } else {
-                        Token::JSONMulti(name, tag)
+                        Token::JSONMulti(name, tag, span)
                     });
             }
             #[cfg(feature = "CFEngine")]
             '$' => {
                 // Data to be rendered as compact JSON representation
-                let name = get_name_or_implicit(&content[1..len])?;
+                let name = get_name_or_implicit(&content[1..len], span)?;
 
                 self.tokens
                     .push(if name.first() == Some(&"-top-".to_string()) {
-                        Token::TopJSON(name, tag)
+                        Token::TopJSON(name, tag, span)
                     } else {
-                        Token::JSON(name, tag)
+                        Token::JSON(name, tag, span)
                     });
             }
             '&' => {
-                let name = get_name_or_implicit(&content[1..len])?;
-                self.tokens.push(Token::UnescapedTag(name, tag));
+                let name = get_name_or_implicit(&content[1..len], span)?;
+                self.tokens.push(Token::UnescapedTag(name, tag, span));
             }
             '{' => {
                 if content.ends_with('}') {
-                    let name = get_name_or_implicit(&content[1..len - 1])?;
-                    self.tokens.push(Token::UnescapedTag(name, tag));
+                    let name = get_name_or_implicit(&content[1..len - 1], span)?;
+                    self.tokens.push(Token::UnescapedTag(name, tag, span));
                 } else {
-                    return Err(Error::UnbalancedUnescapeTag);
+                    return Err(Error::UnbalancedUnescapeTag(span));
                 }
             }
             '#' => {
                 let newlined = self.eat_whitespace();
 
-                let name = get_name_or_implicit(&content[1..len])?;
-                self.tokens
-                    .push(Token::IncompleteSection(name, false, tag, newlined));
+                let name = get_name_or_implicit(&content[1..len], span)?;
+                self.open(
+                    Token::IncompleteSection(name, false, tag, newlined, span),
+                    span,
+                )?;
             }
             '^' => {
                 let newlined = self.eat_whitespace();
 
-                let name = get_name_or_implicit(&content[1..len])?;
-                self.tokens
-                    .push(Token::IncompleteSection(name, true, tag, newlined));
+                let name = get_name_or_implicit(&content[1..len], span)?;
+                self.open(
+                    Token::IncompleteSection(name, true, tag, newlined, span),
+                    span,
+                )?;
             }
             #[cfg(feature = "CFEngine")]
             '@' => {
-                self.tokens.push(Token::At);
+                self.tokens.push(Token::At(span));
             }
             '/' => {
-                self.eat_whitespace();
-
-                let name = get_name_or_implicit(&content[1..len])?;
-                let mut children: Vec<Token> = Vec::new();
-
-                loop {
-                    if self.tokens.is_empty() {
-                        return Err(Error::EarlySectionClose(name.join(".")));
-                    }
-
-                    let last = self.tokens.pop();
-
-                    match last {
-                        Some(Token::IncompleteSection(section_name, inverted, osection, _)) => {
-                            children.reverse();
-
-                            // Collect all the children's sources.
-                            let mut srcs = Vec::new();
-                            for child in children.iter() {
-                                match *child {
-                                    #[cfg(feature = "CFEngine")]
-                                    Token::JSON(_, ref s)
-                                    | Token::JSONMulti(_, ref s)
-                                    | Token::TopJSON(_, ref s)
-                                    | Token::TopJSONMulti(_, ref s) => srcs.push(s.clone()),
-
-                                    Token::Text(ref s)
-                                    | Token::EscapedTag(_, ref s)
-                                    | Token::UnescapedTag(_, ref s)
-                                    | Token::Partial(_, _, ref s) => srcs.push(s.clone()),
-                                    Token::Section(
-                                        _,
-                                        _,
-                                        _,
-                                        ref osection,
-                                        ref csection,
-                                        ref fdata,
-                                    ) => {
-                                        srcs.push(osection.clone());
-                                        srcs.push(fdata[1].clone());
-                                        srcs.push(csection.clone());
-                                    }
-                                    _ => bug!("Incomplete sections should not be nested"),
-                                }
-                            }
-
-                            if section_name == name {
-                                // Cache the combination of all the sources in the
-                                // section. It's unfortunate, but we need to do this in
-                                // case the user uses a function to instantiate the
-                                // tag.
-                                let mut src = String::new();
-                                for s in srcs.iter() {
-                                    src.push_str(s);
-                                }
-
-                                let fdata =
-                                    vec![self.opening_tag.clone(), src, self.closing_tag.clone()];
-
-                                #[cfg(feature = "CFEngine")]
-                                self.tokens
-                                    .push(if name.first() == Some(&"-top-".to_string()) {
-                                        Token::TopSection(children)
-                                    } else {
-                                        Token::Section(
-                                            name, inverted, children, osection, tag, fdata,
-                                        )
-                                    });
-
-                                #[cfg(not(feature = "CFEngine"))]
-                                self.tokens.push(Token::Section(
-                                    name, inverted, children, osection, tag, fdata,
-                                ));
-
-                                break;
-                            } else {
-                                return Err(Error::UnclosedSection(section_name.join(".")));
-                            }
-                        }
-                        Some(last_token) => children.push(last_token),
-                        None => (),
+                let empty_parent = matches!(self.tokens.last(), Some(Token::IncompleteParent(..)));
+
+                if empty_parent && !self.after_standalone {
+                    // `{{<parent}}{{/parent}}` alone on a line is standalone
+                    // as a whole, just like a partial.
+                    if let Some(Token::IncompleteParent(name, _, oparent, ospan)) =
+                        self.tokens.pop()
+                    {
+                        let indent = self.eat_indent().unwrap_or_default();
+                        self.tokens
+                            .push(Token::IncompleteParent(name, indent, oparent, ospan));
                     }
+                } else {
+                    self.eat_whitespace();
                 }
+
+                let name = get_name_or_implicit(&content[1..len], span)?;
+                self.close_section(name, tag, span)?;
+            }
+            '<' => self.add_parent(&content, tag, span)?,
+            // CFEngine already uses `$` for its compact JSON tag.
+            #[cfg(not(feature = "CFEngine"))]
+            '$' => {
+                // Until we know what the block holds, keep the whitespace in
+                // front of its tag.
+                let (indent, standalone) = match self.eat_indent() {
+                    Some(indent) => (indent, true),
+                    None => (self.line_indent().unwrap_or_default(), false),
+                };
+
+                let name = deny_blank(&content[1..len], span)?;
+                self.open(
+                    Token::IncompleteBlock(name.into(), indent, standalone, tag, span),
+                    span,
+                )?;
             }
-            '>' => self.add_partial(&content, tag)?,
+            '>' => self.add_partial(&content, tag, span)?,
             '=' => {
                 self.eat_whitespace();
 
                 if len > 2usize && content.ends_with('=') {
-                    let s = deny_blank(&content[1..len - 1])?;
+                    let s = deny_blank(&content[1..len - 1], span)?;
 
                     let pos = s.find(char::is_whitespace);
                     let pos = match pos {
-                        None => return Err(Error::MissingSetDelimeterClosingTag),
+                        None => return Err(Error::MissingSetDelimeterClosingTag(span)),
                         Some(pos) => pos,
                     };
 
@@ -532,36 +719,194 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
                     let s2 = &s[pos..];
                     let pos = s2.find(|c: char| !c.is_whitespace());
                     let pos = match pos {
-                        None => return Err(Error::MissingSetDelimeterClosingTag),
+                        None => return Err(Error::MissingSetDelimeterClosingTag(span)),
                         Some(pos) => pos,
                     };
 
                     self.closing_tag = s2[pos..].to_string();
                     self.closing_tag_chars = self.closing_tag.chars().collect();
+
+                    self.trivia.push(Trivia::SetDelimiters(
+                        self.opening_tag.clone(),
+                        self.closing_tag.clone(),
+                        tag,
+                        span,
+                    ));
                 } else {
-                    return Err(Error::InvalidSetDelimeterSyntax);
+                    return Err(Error::InvalidSetDelimeterSyntax(span));
                 }
             }
             _ => {
                 // If the name is "." then we want the top element, which we represent with
                 // an empty name.
-                let name = get_name_or_implicit(&content)?;
-                self.tokens.push(Token::EscapedTag(name, tag));
+                let name = get_name_or_implicit(&content, span)?;
+                self.tokens.push(Token::EscapedTag(name, tag, span));
             }
         };
 
         Ok(())
     }
 
-    fn add_partial(&mut self, content: &str, tag: String) -> Result<(), Error> {
-        let indent = match self.classify_token() {
-            TokenClass::Normal => "".to_string(),
+    fn open(&mut self, token: Token, span: Span) -> Result<(), Error> {
+        if let Some(max) = self.max_depth {
+            if self.open.len() >= max {
+                return Err(Error::TooDeep(max, span));
+            }
+        }
+
+        self.open.push(self.tokens.len());
+        self.tokens.push(token);
+        Ok(())
+    }
+
+    fn close_section(&mut self, name: Vec<String>, tag: String, span: Span) -> Result<(), Error> {
+        let Some(start) = self.open.pop() else {
+            return Err(Error::EarlySectionClose(name.join("."), span));
+        };
+        let mut children = self.tokens.split_off(start + 1);
+
+        match self.tokens.pop() {
+            Some(last) => match last {
+                Token::IncompleteSection(section_name, inverted, osection, _, ospan) => {
+                    if section_name != name {
+                        return Err(Error::UnclosedSection(section_name.join("."), ospan));
+                    }
+
+                    // Keep where the section's source is in case the user
+                    // uses a function to instantiate the tag. The source
+                    // itself is filled in once the template has been parsed.
+                    let fdata = SectionSource {
+                        source: Arc::from(""),
+                        range: ospan.end..span.start,
+                        otag: self.opening_tag.clone(),
+                        ctag: self.closing_tag.clone(),
+                    };
+
+                    // The section spans from its opening tag to its closing tag.
+                    let span = Span {
+                        end: span.end,
+                        ..ospan
+                    };
+
+                    #[cfg(feature = "CFEngine")]
+                    self.tokens
+                        .push(if name.first() == Some(&"-top-".to_string()) {
+                            Token::TopSection(children, span)
+                        } else {
+                            Token::Section(name, inverted, children, osection, tag, fdata, span)
+                        });
+
+                    #[cfg(not(feature = "CFEngine"))]
+                    self.tokens.push(Token::Section(
+                        name, inverted, children, osection, tag, fdata, span,
+                    ));
+                }
+                Token::IncompleteParent(parent_name, indent, oparent, ospan) => {
+                    if parent_name != name.join(".") {
+                        return Err(Error::UnclosedSection(parent_name, ospan));
+                    }
+
+                    let span = Span {
+                        end: span.end,
+                        ..ospan
+                    };
+                    self.tokens.push(Token::Parent(
+                        parent_name,
+                        indent,
+                        children,
+                        oparent,
+                        tag,
+                        span,
+                    ));
+                }
+                Token::IncompleteBlock(block_name, indent, standalone, oblock, ospan) => {
+                    if block_name != name.join(".") {
+                        return Err(Error::UnclosedSection(block_name, ospan));
+                    }
+
+                    // A block's indentation is that of its first line when
+                    // its tag stands alone, or of its tags when they share a
+                    // line with nothing else.
+                    let first = children
+                        .iter()
+                        .find(|token| !matches!(token, Token::Text(text, _) if text.is_empty()));
+                    let indent = match first {
+                        Some(Token::Text(text, _)) if standalone => {
+                            let rest = text.trim_start_matches([' ', '\t']);
+                            text[..text.len() - rest.len()].to_string()
+                        }
+                        Some(_) if standalone => indent,
+                        None if standalone || self.at_line_end() => indent,
+                        _ => "".to_string(),
+                    };
+
+                    // Overrides lose their indentation, and get the one of the
+                    // block they replace when they are rendered.
+                    let in_parent = self
+                        .open
+                        .last()
+                        .is_some_and(|&i| matches!(self.tokens[i], Token::IncompleteParent(..)));
+                    if in_parent && !indent.is_empty() {
+                        dedent(&mut children, &indent);
+                    }
+
+                    let span = Span {
+                        end: span.end,
+                        ..ospan
+                    };
+                    self.tokens.push(Token::Block(
+                        block_name, indent, children, oblock, tag, span,
+                    ));
+                }
+                _ => bug!("open sections should be incomplete"),
+            },
+            None => bug!("open sections should be in the tokens"),
+        }
+
+        Ok(())
+    }
+
+    // Whether the next character ends the line, or the template.
+    fn at_line_end(&mut self) -> bool {
+        match self.ch {
+            None | Some('\n') => true,
+            Some('\r') => self.peek() == Some('\n'),
+            Some(_) => false,
+        }
+    }
+
+    // Returns the whitespace in front of the tag we just read if nothing
+    // else comes before it on its line.
+    #[cfg_attr(feature = "CFEngine", allow(dead_code))]
+    fn line_indent(&self) -> Option<String> {
+        match self.tokens.last() {
+            None => Some("".to_string()),
+            Some(_) if self.after_standalone => Some("".to_string()),
+            Some(Token::Text(s, _)) => {
+                let rest = s.trim_end_matches([' ', '\t']);
+                if rest.ends_with('\n') || (rest.is_empty() && self.tokens.len() == 1) {
+                    Some(s[rest.len()..].to_string())
+                } else {
+                    None
+                }
+            }
+            Some(_) => None,
+        }
+    }
+
+    // If the tag we just read is standalone, eat the rest of its line and
+    // return the whitespace that preceded it, which becomes the indentation
+    // of the included template.
+    fn eat_indent(&mut self) -> Option<String> {
+        match self.classify_token() {
+            TokenClass::Normal => None,
             TokenClass::StandAlone => {
                 if self.ch_is('\r') {
                     self.bump();
                 }
                 self.bump();
-                "".to_string()
+                self.ate_line = true;
+                Some("".to_string())
             }
             TokenClass::WhiteSpace(s, pos) => {
                 if self.ch_is('\r') {
@@ -569,23 +914,57 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
                 }
                 self.bump();
 
-                let ws = &s[pos..];
-
                 // Trim the whitespace from the last token.
-                self.tokens.pop();
-                self.tokens.push(Token::Text(s[0..pos].to_string()));
+                self.trim_last_text(pos);
 
-                ws.to_string()
+                self.ate_line = true;
+                Some(s[pos..].to_string())
+            }
+        }
+    }
+
+    fn add_parent(&mut self, content: &str, tag: String, span: Span) -> Result<(), Error> {
+        let indent = match self.eat_indent() {
+            Some(indent) => indent,
+            None => {
+                // The next tag on the line may still be standalone.
+                self.ate_line = self.after_standalone;
+                "".to_string()
             }
         };
 
+        // Parents are looked up just like partials.
+        let name = deny_blank(&content[1..content.len()], span)?;
+
+        self.open(
+            Token::IncompleteParent(name.into(), indent, tag, span),
+            span,
+        )?;
+        self.partials.push(name.into());
+
+        Ok(())
+    }
+
+    fn add_partial(&mut self, content: &str, tag: String, span: Span) -> Result<(), Error> {
+        let indent = self.eat_indent().unwrap_or_default();
+
         // We can't inline the tokens directly as we may have a recursive
         // partial. So instead, we'll cache the partials we used and look them
         // up later.
         let name = &content[1..content.len()];
-        let name = deny_blank(name)?;
+        let name = deny_blank(name, span)?;
+
+        // A `*` means the partial's name is looked up in the data at render
+        // time, so there's nothing to compile ahead of time.
+        if let Some(path) = name.strip_prefix('*') {
+            let path = get_name_or_implicit(path, span)?;
+            self.tokens
+                .push(Token::DynamicPartial(path, indent, tag, span));
+            return Ok(());
+        }
 
-        self.tokens.push(Token::Partial(name.into(), indent, tag));
+        self.tokens
+            .push(Token::Partial(name.into(), indent, tag, span));
         self.partials.push(name.into());
 
         Ok(())
@@ -610,10 +989,63 @@ impl<'a, T: Iterator<Item = char>> Parser<'a, T> {
     }
 }
 
-fn get_name_or_implicit(name: &str) -> Result<Vec<String>, Error> {
+// Removes `indent` from the start of the lines of `tokens`, including the
+// indentation of the partials and blocks among them.
+fn dedent(tokens: &mut [Token], indent: &str) {
+    let strip = |s: &mut String| {
+        if s.starts_with(indent) {
+            s.replace_range(..indent.len(), "");
+        }
+    };
+
+    for token in tokens.iter_mut() {
+        match *token {
+            Token::Text(ref mut text, span) => {
+                let mut lines: Vec<String> = text.split_inclusive('\n').map(String::from).collect();
+                // Only text right after a newline starts a line.
+                for (i, line) in lines.iter_mut().enumerate() {
+                    if i > 0 || span.col == 1 {
+                        strip(line);
+                    }
+                }
+                *text = lines.concat();
+            }
+            Token::Partial(_, ref mut own_indent, _, _)
+            | Token::DynamicPartial(_, ref mut own_indent, _, _) => strip(own_indent),
+            Token::Parent(_, ref mut own_indent, ref mut children, _, _, _)
+            | Token::Block(_, ref mut own_indent, ref mut children, _, _, _) => {
+                strip(own_indent);
+                dedent(children, indent);
+            }
+            Token::Section(_, _, ref mut children, _, _, _, _) => dedent(children, indent),
+            #[cfg(feature = "CFEngine")]
+            Token::TopSection(ref mut children, _) => dedent(children, indent),
+            _ => {}
+        }
+    }
+}
+
+// Points the sections in `tokens` into `source`, the template they came from.
+fn share_source(tokens: &mut [Token], source: &Arc<str>) {
+    for token in tokens.iter_mut() {
+        match *token {
+            Token::Section(_, _, ref mut children, _, _, ref mut fdata, _) => {
+                fdata.source = source.clone();
+                share_source(children, source);
+            }
+            Token::Parent(_, _, ref mut children, _, _, _)
+            | Token::Block(_, _, ref mut children, _, _, _) => share_source(children, source),
+            #[cfg(feature = "CFEngine")]
+            Token::TopSection(ref mut children, _) => share_source(children, source),
+            _ => {}
+        }
+    }
+}
+
+fn get_name_or_implicit(name: &str, span: Span) -> Result<Vec<String>, Error> {
     // If the name is "." then we want the top element, which we represent with
     // an empty name.
-    let name = deny_blank(name)?;
+    let name = deny_blank(name, span)?;
     Ok(if name == "." {
         Vec::new()
     } else {
@@ -621,10 +1053,10 @@ fn get_name_or_implicit(name: &str) -> Result<Vec<String>, Error> {
     })
 }
 
-fn deny_blank(content: &str) -> Result<&str, Error> {
+fn deny_blank(content: &str, span: Span) -> Result<&str, Error> {
     let trimmed = content.trim();
     if trimmed.is_empty() {
-        Err(Error::EmptyTag)
+        Err(Error::EmptyTag(span))
     } else {
         Ok(trimmed)
     }
@@ -644,7 +1076,17 @@ mod tests {
     }
 
     pub fn assert_parse(input: &str) -> (Vec<Token>, Vec<String>) {
-        parse(input).expect(&format!("Failed to parse: {}", input))
+        parse(input).unwrap_or_else(|_| panic!("Failed to parse: {}", input))
+    }
+
+    // A span on the first line of the input.
+    pub fn span(start: usize, end: usize) -> Span {
+        Span {
+            start,
+            end,
+            line: 1,
+            col: start + 1,
+        }
     }
 
     #[test]
@@ -654,22 +1096,25 @@ mod tests {
 
     #[test]
     fn empty_tag() {
-        assert_eq!(parse("{{}}"), Err(Error::EmptyTag));
+        assert_eq!(parse("{{}}"), Err(Error::EmptyTag(span(0, 4))));
     }
 
     #[test]
     fn whitespace_only_tag() {
-        assert_eq!(parse("{{ }}"), Err(Error::EmptyTag));
+        assert_eq!(parse("{{ }}"), Err(Error::EmptyTag(span(0, 5))));
     }
 
     #[test]
     fn bad_closing_tag() {
-        assert_eq!(parse("{{hello}?"), Err(Error::BadClosingTag('?', '}')))
+        assert_eq!(
+            parse("{{hello}?"),
+            Err(Error::BadClosingTag('?', '}', span(8, 9)))
+        )
     }
 
     #[test]
     fn unclosed_tag() {
-        assert_eq!(parse("{{hi"), Err(Error::UnclosedTag))
+        assert_eq!(parse("{{hi"), Err(Error::UnclosedTag(span(0, 4))))
     }
 
     mod sections {
@@ -684,7 +1129,7 @@ mod tests {
         fn unclosed() {
             assert_eq!(
                 parse("{{#world}}hi"),
-                Err(Error::UnclosedSection("world".into()))
+                Err(Error::UnclosedSection("world".into(), span(0, 10)))
             )
         }
 
@@ -692,7 +1137,7 @@ mod tests {
         fn unclosed_nested_with_wrong_closing_tag() {
             assert_eq!(
                 parse("{{#universe}} {{#world}} {{/universe}}"),
-                Err(Error::UnclosedSection("world".into()))
+                Err(Error::UnclosedSection("world".into(), span(14, 24)))
             )
         }
 
@@ -700,7 +1145,7 @@ mod tests {
         fn unclosed_nested() {
             assert_eq!(
                 parse("{{#universe}} {{#world}}"),
-                Err(Error::UnclosedSection("world".into()))
+                Err(Error::UnclosedSection("world".into(), span(14, 24)))
             )
         }
 
@@ -708,7 +1153,10 @@ mod tests {
         fn unclosed_with_path() {
             assert_eq!(
                 parse("{{#universe}} {{#world.and.stuff}} {{/universe}}"),
-                Err(Error::UnclosedSection("world.and.stuff".into()))
+                Err(Error::UnclosedSection(
+                    "world.and.stuff".into(),
+                    span(14, 34)
+                ))
             )
         }
 
@@ -716,7 +1164,7 @@ mod tests {
         fn early_close() {
             assert_eq!(
                 parse("{{/world}}"),
-                Err(Error::EarlySectionClose("world".into()))
+                Err(Error::EarlySectionClose("world".into(), span(0, 10)))
             )
         }
     }
@@ -733,7 +1181,7 @@ mod tests {
         fn unclosed() {
             assert_eq!(
                 parse("{{^world}}hi"),
-                Err(Error::UnclosedSection("world".into()))
+                Err(Error::UnclosedSection("world".into(), span(0, 10)))
             )
         }
 
@@ -741,12 +1189,12 @@ mod tests {
         fn unclosed_nested_with_wrong_closing_tag() {
             assert_eq!(
                 parse("{{#universe}} {{^world}} {{/universe}}"),
-                Err(Error::UnclosedSection("world".into()))
+                Err(Error::UnclosedSection("world".into(), span(14, 24)))
             );
 
             assert_eq!(
                 parse("{{^universe}} {{^world}} {{/universe}}"),
-                Err(Error::UnclosedSection("world".into()))
+                Err(Error::UnclosedSection("world".into(), span(14, 24)))
             )
         }
 
@@ -754,7 +1202,7 @@ mod tests {
         fn unclosed_nested() {
             assert_eq!(
                 parse("{{#universe}} {{^world}}"),
-                Err(Error::UnclosedSection("world".into()))
+                Err(Error::UnclosedSection("world".into(), span(14, 24)))
             )
         }
 
@@ -762,7 +1210,10 @@ mod tests {
         fn unclosed_with_path() {
             assert_eq!(
                 parse("{{#universe}} {{^world.and.stuff}} {{/universe}}"),
-                Err(Error::UnclosedSection("world.and.stuff".into()))
+                Err(Error::UnclosedSection(
+                    "world.and.stuff".into(),
+                    span(14, 34)
+                ))
             )
         }
     }
@@ -779,18 +1230,24 @@ mod tests {
         fn closing_tag_is_whitespace() {
             assert_eq!(
                 parse("{{=<% =}}"),
-                Err(Error::MissingSetDelimeterClosingTag)
+                Err(Error::MissingSetDelimeterClosingTag(span(0, 9)))
             )
         }
 
         #[test]
         fn missing_closing_tag() {
-            assert_eq!(parse("{{=<%=}}"), Err(Error::MissingSetDelimeterClosingTag))
+            assert_eq!(
+                parse("{{=<%=}}"),
+                Err(Error::MissingSetDelimeterClosingTag(span(0, 8)))
+            )
         }
 
         #[test]
         fn missing_closing_equals() {
-            assert_eq!(parse("{{=<% %>}}"), Err(Error::InvalidSetDelimeterSyntax))
+            assert_eq!(
+                parse("{{=<% %>}}"),
+                Err(Error::InvalidSetDelimeterSyntax(span(0, 10)))
+            )
         }
     }
 
@@ -799,9 +1256,274 @@ mod tests {
         // use the set delimiter tag to change the brace type. Currently this error will
         // not trigger with "{{{ }}"
         let input = "{{=<% %>=}} <%{ %>";
-        assert_eq!(parse(input), Err(Error::UnbalancedUnescapeTag))
+        assert_eq!(
+            parse(input),
+            Err(Error::UnbalancedUnescapeTag(span(12, 18)))
+        )
+    }
+
+    mod spans {
+        use super::*;
+
+        #[test]
+        fn tokens_across_lines() {
+            let (tokens, _) = assert_parse("hello\n{{name}}!");
+            assert_eq!(
+                tokens,
+                vec![
+                    Token::Text("hello\n".into(), span(0, 6)),
+                    Token::EscapedTag(
+                        vec!["name".into()],
+                        "{{name}}".into(),
+                        Span {
+                            start: 6,
+                            end: 14,
+                            line: 2,
+                            col: 1
+                        }
+                    ),
+                    Token::Text(
+                        "!".into(),
+                        Span {
+                            start: 14,
+                            end: 15,
+                            line: 2,
+                            col: 9
+                        }
+                    ),
+                ]
+            );
+        }
+
+        #[test]
+        fn standalone_section() {
+            let (tokens, _) = assert_parse("a\n  {{#s}}\nb\n{{/s}}\n");
+            assert_eq!(tokens.len(), 2);
+            assert_eq!(tokens[0], Token::Text("a\n".into(), span(0, 2)));
+            let Token::Section(_, _, ref children, _, _, _, section_span) = tokens[1] else {
+                panic!("expected a section, found {:?}", tokens[1]);
+            };
+            assert_eq!(
+                section_span,
+                Span {
+                    start: 4,
+                    end: 19,
+                    line: 2,
+                    col: 3
+                }
+            );
+            assert_eq!(
+                children[..],
+                [Token::Text(
+                    "b\n".into(),
+                    Span {
+                        start: 11,
+                        end: 13,
+                        line: 3,
+                        col: 1
+                    }
+                )]
+            );
+        }
+
+        #[test]
+        fn indented_standalone_after_standalone() {
+            let (tokens, _) = assert_parse("{{#a}}\n  {{#b}}\nx\n  {{/b}}\n{{/a}}\n");
+            let Token::Section(_, _, ref children, _, _, _, _) = tokens[0] else {
+                panic!("expected a section, found {:?}", tokens[0]);
+            };
+            let Some(Token::Section(_, _, children, _, _, _, _)) = children.last() else {
+                panic!("expected a section, found {children:?}");
+            };
+            assert_eq!(
+                children[..],
+                [Token::Text(
+                    "x\n".into(),
+                    Span {
+                        start: 16,
+                        end: 18,
+                        line: 3,
+                        col: 1
+                    }
+                )]
+            );
+        }
+
+        #[test]
+        fn multibyte_text() {
+            let (tokens, _) = assert_parse("\u{e9}{{x}}");
+            assert_eq!(
+                tokens[1],
+                Token::EscapedTag(
+                    vec!["x".into()],
+                    "{{x}}".into(),
+                    Span {
+                        start: 2,
+                        end: 7,
+                        line: 1,
+                        col: 2
+                    }
+                )
+            );
+        }
+
+        #[test]
+        fn errors_report_their_position() {
+            let err = parse("one\ntwo {{#three}}\n").unwrap_err();
+            assert_eq!(
+                err,
+                Error::UnclosedSection(
+                    "three".into(),
+                    Span {
+                        start: 8,
+                        end: 18,
+                        line: 2,
+                        col: 5
+                    }
+                )
+            );
+            assert_eq!(
+                err.to_string(),
+                "found an unclosed section: \"three\" at line 2, column 5"
+            );
+
+            assert_eq!(
+                parse("one\n{{hello}?").unwrap_err().span(),
+                Span {
+                    start: 12,
+                    end: 13,
+                    line: 2,
+                    col: 9
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_dynamic_partial() {
+        let (tokens, partials) = assert_parse("{{> *widget.kind }}");
+        assert!(partials.is_empty());
+        assert_eq!(
+            tokens,
+            vec![Token::DynamicPartial(
+                vec!["widget".into(), "kind".into()],
+                "".into(),
+                "{{> *widget.kind }}".into(),
+                span(0, 19),
+            )]
+        );
+
+        assert_eq!(parse("{{>*}}"), Err(Error::EmptyTag(span(0, 6))));
     }
 
+    #[cfg(not(feature = "CFEngine"))]
+    mod inheritance {
+        use super::*;
+
+        #[test]
+        fn sanity() {
+            let (tokens, partials) =
+                assert_parse("{{<layout}}ignored{{$title}}Hello{{/title}}{{/layout}}");
+            assert_eq!(partials, vec!["layout".to_string()]);
+
+            let Token::Parent(ref name, _, ref children, _, _, parent_span) = tokens[0] else {
+                panic!("expected a parent, found {:?}", tokens[0]);
+            };
+            assert_eq!(name, "layout");
+            assert_eq!(parent_span, span(0, 54));
+            assert_eq!(
+                children[1],
+                Token::Block(
+                    "title".into(),
+                    "".into(),
+                    vec![Token::Text("Hello".into(), span(28, 33))],
+                    "{{$title}}".into(),
+                    "{{/title}}".into(),
+                    span(18, 43),
+                )
+            );
+        }
+
+        #[test]
+        fn unclosed_parent() {
+            assert_eq!(
+                parse("{{<layout}}{{$title}}{{/title}}"),
+                Err(Error::UnclosedSection("layout".into(), span(0, 11)))
+            )
+        }
+
+        #[test]
+        fn unclosed_block() {
+            assert_eq!(
+                parse("{{<layout}}{{$title}}{{/layout}}"),
+                Err(Error::UnclosedSection("title".into(), span(11, 21)))
+            )
+        }
+
+        #[test]
+        fn standalone_tags() {
+            let (tokens, _) =
+                assert_parse("{{<layout}}\n{{$title}}\nHello\n{{/title}}\n{{/layout}}\n");
+            assert_eq!(tokens.len(), 1);
+            let Token::Parent(_, _, ref children, _, _, _) = tokens[0] else {
+                panic!("expected a parent, found {:?}", tokens[0]);
+            };
+            let Token::Block(_, _, ref block, _, _, _) = children[0] else {
+                panic!("expected a block, found {:?}", children[0]);
+            };
+            assert_eq!(children.len(), 1);
+            assert_text(&block[0], "Hello\n");
+        }
+
+        #[test]
+        fn block_indentation() {
+            let indent = |template: &str| {
+                let (tokens, _) = assert_parse(template);
+                let Token::Block(_, ref indent, _, _, _, _) = tokens[1] else {
+                    panic!("expected a block, found {:?}", tokens[1]);
+                };
+                indent.clone()
+            };
+
+            // Standalone blocks are indented like their first line.
+            assert_eq!(indent("Hi,\n  {{$b}}\n    one\n  {{/b}}\n"), "    ");
+            assert_eq!(indent("Hi,\n  {{$b}}\none\n  {{/b}}\n"), "");
+            // Empty ones like their tags.
+            assert_eq!(indent("Hi,\n  {{$b}}\n  {{/b}}\n"), "  ");
+            assert_eq!(indent("Hi,\n  {{$b}}{{/b}}\n"), "  ");
+            assert_eq!(indent("Hi,\n  {{$b}}{{/b}} there\n"), "");
+            assert_eq!(indent("Hi,\n  {{$b}}one\ntwo{{/b}}\n"), "");
+        }
+
+        #[test]
+        fn overrides_lose_their_indentation() {
+            let (tokens, _) = assert_parse(
+                "{{<layout}}\n  {{$b}}\n    one\n      {{>p}}\n    two {{x}}\n  {{/b}}\n{{/layout}}",
+            );
+            let Token::Parent(_, _, ref children, _, _, _) = tokens[0] else {
+                panic!("expected a parent, found {:?}", tokens[0]);
+            };
+            let Token::Block(_, ref indent, ref block, _, _, _) = children[1] else {
+                panic!("expected a block, found {:?}", children[1]);
+            };
+            assert_eq!(indent, "    ");
+            assert_text(&block[0], "one\n");
+            let Token::Partial(_, ref indent, _, _) = block[1] else {
+                panic!("expected a partial, found {:?}", block[1]);
+            };
+            assert_eq!(indent, "  ");
+            assert_text(&block[2], "two ");
+        }
+
+        fn assert_text(token: &Token, expected: &str) {
+            let Token::Text(ref text, _) = *token else {
+                panic!("expected text, found {:?}", token);
+            };
+            assert_eq!(text, expected);
+        }
+    }
+
+    #[cfg(feature = "CFEngine")]
     mod cfengine {
         use super::*;
 
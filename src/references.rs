@@ -0,0 +1,156 @@
+use std::collections::BTreeSet;
+
+use crate::compiler::PartialsMap;
+use crate::parser::{Span, Token};
+
+/// What a name is used for in a template.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferenceKind {
+    /// An escaped `{{name}}` interpolation.
+    Variable,
+    /// An unescaped `{{{name}}}` or `{{&name}}` interpolation.
+    UnescapedVariable,
+    /// A `{{#name}}` section.
+    Section,
+    /// A `{{^name}}` inverted section.
+    InvertedSection,
+    /// The name a `{{>*name}}` dynamic partial is looked up with.
+    DynamicPartial,
+}
+
+/// A name a template looks up in its data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reference {
+    /// The dotted path, split on dots. `{{.}}` is the empty path.
+    pub path: Vec<String>,
+    pub kind: ReferenceKind,
+    /// The paths of the sections the reference is nested in, outermost
+    /// first. Partials start from an empty context.
+    pub context: Vec<Vec<String>>,
+    /// The partial the reference is in, or `None` for the template itself.
+    pub partial: Option<String>,
+    /// Where the tag is in the template or partial source.
+    pub span: Span,
+}
+
+impl Reference {
+    /// The path joined with dots, as written in the template.
+    pub fn dotted(&self) -> String {
+        if self.path.is_empty() {
+            ".".to_string()
+        } else {
+            self.path.join(".")
+        }
+    }
+}
+
+/// Everything a template and its partials reference.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct References {
+    /// The names looked up in the data, in source order, followed by the
+    /// ones in each partial.
+    pub names: Vec<Reference>,
+    /// The partials and parents used, directly or through other partials.
+    /// Dynamic partials can't be known ahead of time and aren't included.
+    pub partials: BTreeSet<String>,
+}
+
+/// Collects the references in `tokens` and, transitively, in the partials
+/// they use.
+pub fn collect(tokens: &[Token], partials: &PartialsMap) -> References {
+    let mut refs = References::default();
+    let mut pending = Vec::new();
+
+    let mut walker = Walker {
+        refs: &mut refs,
+        pending: &mut pending,
+        context: Vec::new(),
+        partial: None,
+    };
+    walker.walk(tokens);
+
+    // Partials are walked once each, in the order they were first used.
+    let mut next = 0;
+    while next < pending.len() {
+        let name = pending[next].clone();
+        next += 1;
+
+        if let Some(tokens) = partials.get(&name) {
+            let mut walker = Walker {
+                refs: &mut refs,
+                pending: &mut pending,
+                context: Vec::new(),
+                partial: Some(name),
+            };
+            walker.walk(tokens);
+        }
+    }
+
+    refs
+}
+
+struct Walker<'a> {
+    refs: &'a mut References,
+    pending: &'a mut Vec<String>,
+    context: Vec<Vec<String>>,
+    partial: Option<String>,
+}
+
+impl Walker<'_> {
+    fn walk(&mut self, tokens: &[Token]) {
+        for token in tokens.iter() {
+            match *token {
+                Token::EscapedTag(ref path, _, span) => {
+                    self.push(path, ReferenceKind::Variable, span)
+                }
+                Token::UnescapedTag(ref path, _, span) => {
+                    self.push(path, ReferenceKind::UnescapedVariable, span)
+                }
+                Token::Section(ref path, inverted, ref children, _, _, _, span) => {
+                    let kind = if inverted {
+                        ReferenceKind::InvertedSection
+                    } else {
+                        ReferenceKind::Section
+                    };
+                    self.push(path, kind, span);
+
+                    self.context.push(path.clone());
+                    self.walk(children);
+                    self.context.pop();
+                }
+                Token::Partial(ref name, _, _, _) => self.use_partial(name),
+                Token::DynamicPartial(ref path, _, _, span) => {
+                    self.push(path, ReferenceKind::DynamicPartial, span)
+                }
+                Token::Parent(ref name, _, ref children, _, _, _) => {
+                    self.use_partial(name);
+                    self.walk(children);
+                }
+                Token::Block(_, _, ref children, _, _, _) => self.walk(children),
+                #[cfg(feature = "CFEngine")]
+                Token::JSON(ref path, _, span) | Token::JSONMulti(ref path, _, span) => {
+                    self.push(path, ReferenceKind::UnescapedVariable, span)
+                }
+                #[cfg(feature = "CFEngine")]
+                Token::TopSection(ref children, _) => self.walk(children),
+                _ => {}
+            }
+        }
+    }
+
+    fn push(&mut self, path: &[String], kind: ReferenceKind, span: Span) {
+        self.refs.names.push(Reference {
+            path: path.to_vec(),
+            kind,
+            context: self.context.clone(),
+            partial: self.partial.clone(),
+            span,
+        });
+    }
+
+    fn use_partial(&mut self, name: &str) {
+        if self.refs.partials.insert(name.to_string()) {
+            self.pending.push(name.to_string());
+        }
+    }
+}
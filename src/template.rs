@@ -1,38 +1,108 @@
-#[cfg(feature = "CFEngine")]
-use std::collections::BTreeMap;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::io::Write;
 use std::mem;
+use std::rc::Rc;
 use std::str;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::vec;
 
-use crate::compiler::Compiler;
+use crate::ast::{self, Ast};
+use crate::compiler::{self, Compiler, PartialsMap};
 // for bug!
-use crate::parser::Token;
+use crate::parser::{SectionSource, Span, Token};
+use crate::references::{self, References};
+#[cfg(feature = "CFEngine")]
+use crate::value::Serialized;
+use crate::value::{ContextLambda, ContextValue, Lambda, Value};
 use log::{error, log};
 use serde::Serialize;
 
-use super::{Context, Data, Error, Result, to_data};
+use super::{Context, Data, Error, Escaper, Result, to_data};
 
 /// `Template` represents a compiled mustache file.
 #[derive(Debug, Clone)]
 pub struct Template {
     ctx: Context,
+    name: Option<String>,
     tokens: Vec<Token>,
-    partials: HashMap<String, Vec<Token>>,
+    partials: Arc<PartialsMap>,
+    // Partials compiled while rendering, for dynamic partial tags and lambda
+    // output, so that each is only compiled once.
+    loaded: Arc<RwLock<Loaded>>,
+}
+
+#[derive(Debug, Default)]
+struct Loaded {
+    partials: HashMap<String, Arc<[Token]>>,
+    missing: HashSet<String>,
 }
 
 /// Construct a `Template`. This is not part of the impl of Template so it is
 /// not exported outside of mustache.
-pub fn new(ctx: Context, tokens: Vec<Token>, partials: HashMap<String, Vec<Token>>) -> Template {
+pub fn new(
+    ctx: Context,
+    name: Option<String>,
+    tokens: Vec<Token>,
+    partials: Arc<PartialsMap>,
+) -> Template {
     Template {
         ctx,
+        name,
         tokens,
         partials,
+        loaded: Arc::default(),
     }
 }
 
 impl Template {
+    /// Makes rendering fail on names and partials that can't be resolved,
+    /// instead of rendering nothing for them.
+    pub fn with_strict(mut self, strict: bool) -> Template {
+        self.ctx.strict = strict;
+        self
+    }
+
+    /// Returns the template and its partials as nodes, for walking or
+    /// rewriting them.
+    pub fn ast(&self) -> Ast {
+        // Templates in a set share their partials with the rest of the set,
+        // so only take the ones this template reaches.
+        let mut partials = HashMap::new();
+        let mut pending: Vec<String> = compiler::dependencies(&self.tokens).into_iter().collect();
+        while let Some(name) = pending.pop() {
+            if partials.contains_key(&name) {
+                continue;
+            }
+            if let Some(tokens) = self.partials.get(&name) {
+                pending.extend(compiler::dependencies(tokens));
+                partials.insert(name, ast::from_tokens(tokens));
+            }
+        }
+
+        Ast {
+            nodes: ast::from_tokens(&self.tokens),
+            partials,
+        }
+    }
+
+    /// Lists the names the template looks up in its data and the partials it
+    /// uses, including the ones used by its partials.
+    pub fn references(&self) -> References {
+        references::collect(&self.tokens, &self.partials)
+    }
+
+    // A panic while the cache is held can't leave it half updated, so a
+    // poisoned lock is still fine to use.
+    fn loaded(&self) -> RwLockReadGuard<'_, Loaded> {
+        self.loaded.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn loaded_mut(&self) -> RwLockWriteGuard<'_, Loaded> {
+        self.loaded.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
     /// Renders the template with the `Encodable` data.
     pub fn render<W, T>(&self, wr: &mut W, data: &T) -> Result<()>
     where
@@ -45,95 +115,449 @@ impl Template {
 
     /// Renders the template with the `Data`.
     pub fn render_data<W: Write>(&self, wr: &mut W, data: &Data) -> Result<()> {
-        let mut render_ctx = RenderContext::new(self);
-        let mut stack = vec![data];
+        self.render_data_with_escaper(wr, data, &*self.ctx.escaper)
+    }
 
-        render_ctx.render(wr, &mut stack, &self.tokens)
+    /// Renders the template with the `Encodable` data, escaping `{{name}}`
+    /// tags with `escaper` instead of the context's escaper.
+    pub fn render_with_escaper<W, T>(
+        &self,
+        wr: &mut W,
+        data: &T,
+        escaper: &dyn Escaper,
+    ) -> Result<()>
+    where
+        W: Write,
+        T: Serialize,
+    {
+        let data = to_data(data)?;
+        self.render_data_with_escaper(wr, &data, escaper)
+    }
+
+    /// Renders the template with the `Data`, escaping `{{name}}` tags with
+    /// `escaper` instead of the context's escaper.
+    pub fn render_data_with_escaper<W: Write>(
+        &self,
+        wr: &mut W,
+        data: &Data,
+        escaper: &dyn Escaper,
+    ) -> Result<()> {
+        self.render_output(&mut IoOutput(wr), data, escaper)
+    }
+
+    /// Renders the template into a `fmt::Write`, such as a `String` or a
+    /// `Formatter`, with the `Encodable` data.
+    pub fn render_fmt<W, T>(&self, wr: &mut W, data: &T) -> Result<()>
+    where
+        W: fmt::Write,
+        T: Serialize,
+    {
+        let data = to_data(data)?;
+        self.render_data_fmt(wr, &data)
+    }
+
+    /// Renders the template into a `fmt::Write` with the `Data`.
+    pub fn render_data_fmt<W: fmt::Write>(&self, wr: &mut W, data: &Data) -> Result<()> {
+        self.render_output(&mut FmtOutput(wr), data, &*self.ctx.escaper)
     }
 
     /// Renders the template to a `String` with the `Encodable` data.
     pub fn render_to_string<T: Serialize>(&self, data: &T) -> Result<String> {
-        let mut output = Vec::new();
-        self.render(&mut output, data)?;
-        String::from_utf8(output).map_err(|_| Error::InvalidStr)
+        let mut output = String::new();
+        self.render_fmt(&mut output, data)?;
+        Ok(output)
     }
 
     /// Renders the template to a `String` with the `Data`.
     pub fn render_data_to_string(&self, data: &Data) -> Result<String> {
-        let mut output = Vec::new();
-        self.render_data(&mut output, data)?;
-        String::from_utf8(output).map_err(|_| Error::InvalidStr)
+        let mut output = String::new();
+        self.render_data_fmt(&mut output, data)?;
+        Ok(output)
+    }
+
+    /// Renders the template with data that is looked up in place, such as a
+    /// `serde_json::Value`, instead of being copied into a `Data` first.
+    pub fn render_value<W, V>(&self, wr: &mut W, value: &V) -> Result<()>
+    where
+        W: Write,
+        V: ContextValue + ?Sized,
+    {
+        self.render_output(&mut IoOutput(wr), &value, &*self.ctx.escaper)
+    }
+
+    /// Renders the template into a `fmt::Write` with data that is looked up
+    /// in place.
+    pub fn render_value_fmt<W, V>(&self, wr: &mut W, value: &V) -> Result<()>
+    where
+        W: fmt::Write,
+        V: ContextValue + ?Sized,
+    {
+        self.render_output(&mut FmtOutput(wr), &value, &*self.ctx.escaper)
+    }
+
+    /// Renders the template to a `String` with data that is looked up in
+    /// place.
+    pub fn render_value_to_string<V: ContextValue + ?Sized>(&self, value: &V) -> Result<String> {
+        let mut output = String::new();
+        self.render_value_fmt(&mut output, value)?;
+        Ok(output)
+    }
+
+    /// Pairs the template with the `Encodable` data, so it can be rendered
+    /// with `format!`, `write!` or `to_string`.
+    ///
+    /// ```rust
+    /// let template = mustache::compile_str("Hello, {{name}}!").unwrap();
+    /// let data = serde_json::json!({ "name": "world" });
+    /// assert_eq!(format!("<{}>", template.display(&data).unwrap()), "<Hello, world!>");
+    /// ```
+    ///
+    /// `Display` can't report why rendering failed, so use `render_fmt` when
+    /// the error matters.
+    pub fn display<T: Serialize>(&self, data: &T) -> Result<Rendered<'_>> {
+        Ok(Rendered {
+            template: self,
+            data: RenderedData::Owned(to_data(data)?),
+        })
+    }
+
+    /// Pairs the template with the `Data`, so it can be rendered with
+    /// `format!`, `write!` or `to_string`.
+    pub fn display_data<'a>(&'a self, data: &'a Data) -> Rendered<'a> {
+        Rendered {
+            template: self,
+            data: RenderedData::Borrowed(data),
+        }
+    }
+
+    fn render_output<W: Output>(
+        &self,
+        wr: &mut W,
+        data: &dyn ContextValue,
+        escaper: &dyn Escaper,
+    ) -> Result<()> {
+        let mut render_ctx = RenderContext::new(self, escaper);
+        let mut stack = vec![data];
+        let mut wr = LimitedOutput {
+            inner: wr,
+            max: self.ctx.limits.max_output,
+            written: render_ctx.written.clone(),
+        };
+
+        render_ctx.render(&mut wr, &mut stack, &self.tokens)
+    }
+}
+
+/// A template paired with its data, rendered when formatted. Created by
+/// `Template::display` and `Template::display_data`.
+pub struct Rendered<'a> {
+    template: &'a Template,
+    data: RenderedData<'a>,
+}
+
+enum RenderedData<'a> {
+    Borrowed(&'a Data),
+    Owned(Data),
+}
+
+impl fmt::Display for Rendered<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let data = match self.data {
+            RenderedData::Borrowed(data) => data,
+            RenderedData::Owned(ref data) => data,
+        };
+
+        self.template
+            .render_data_fmt(f, data)
+            .map_err(|_| fmt::Error)
+    }
+}
+
+/// What a `ContextLambda` is given: the section it was called for, the data
+/// it was called with, and a way to render text with them.
+///
+/// ```rust
+/// use mustache::MapBuilder;
+///
+/// let data = MapBuilder::new()
+///     .insert_str("name", "world")
+///     .insert_lambda("loud", |ctx| Ok(ctx.render_section()?.to_uppercase()))
+///     .build();
+/// let template = mustache::compile_str("{{#loud}}hello {{name}}{{/loud}}").unwrap();
+/// assert_eq!(template.render_data_to_string(&data).unwrap(), "HELLO WORLD");
+/// ```
+pub struct LambdaContext<'a, 'r> {
+    render: &'a mut RenderContext<'r>,
+    stack: &'a [&'a dyn ContextValue],
+    children: &'a [Token],
+    source: &'a str,
+    otag: &'a str,
+    ctag: &'a str,
+}
+
+impl LambdaContext<'_, '_> {
+    /// Returns the contents of the section exactly as written, or `""` for a
+    /// `{{name}}` tag.
+    pub fn source(&self) -> &str {
+        self.source
+    }
+
+    /// Returns the opening and closing delimiters in effect where the
+    /// lambda was used.
+    pub fn delimiters(&self) -> (&str, &str) {
+        (self.otag, self.ctag)
+    }
+
+    /// Looks up a name the way a tag would, with `.` for the current value.
+    pub fn get(&self, name: &str) -> Option<&dyn ContextValue> {
+        let path: Vec<String> = match name {
+            "." => Vec::new(),
+            _ => name.split('.').map(|part| part.to_string()).collect(),
+        };
+        self.render.find(&path, self.stack)
+    }
+
+    /// Renders the contents of the section with the current data.
+    pub fn render_section(&mut self) -> Result<String> {
+        let mut stack = self.stack.to_vec();
+        self.render_tokens(&mut stack, self.children)
+    }
+
+    /// Renders the contents of the section with `data` on top of the current
+    /// data.
+    pub fn render_section_with(&mut self, data: &dyn ContextValue) -> Result<String> {
+        let mut stack = self.stack.to_vec();
+        stack.push(data);
+        self.render_tokens(&mut stack, self.children)
+    }
+
+    /// Compiles `source` with the current delimiters and renders it with the
+    /// current data.
+    pub fn render(&mut self, source: &str) -> Result<String> {
+        let tokens = self.render.compile_fun(source, self.otag, self.ctag)?;
+        let mut stack = self.stack.to_vec();
+        self.render_tokens(&mut stack, &tokens)
+    }
+
+    /// Compiles `source` with the current delimiters and renders it with
+    /// `data` on top of the current data.
+    pub fn render_with(&mut self, source: &str, data: &dyn ContextValue) -> Result<String> {
+        let tokens = self.render.compile_fun(source, self.otag, self.ctag)?;
+        let mut stack = self.stack.to_vec();
+        stack.push(data);
+        self.render_tokens(&mut stack, &tokens)
+    }
+
+    fn render_tokens(
+        &mut self,
+        stack: &mut Vec<&dyn ContextValue>,
+        tokens: &[Token],
+    ) -> Result<String> {
+        // The string can't grow past what's left of the limit, but only what
+        // the lambda returns reaches the output, so that's all that counts.
+        let written = self.render.written.get();
+        let mut output = String::new();
+        let mut wr = LimitedOutput {
+            inner: &mut output,
+            max: self.render.template.ctx.limits.max_output,
+            written: self.render.written.clone(),
+        };
+        self.render.render(&mut wr, stack, tokens)?;
+        self.render.written.set(written);
+        Ok(output)
+    }
+}
+
+/// Where rendered text goes. Rendering writes `str`s only, so it can target
+/// both `io::Write` and `fmt::Write` without going through bytes.
+trait Output {
+    fn write_str(&mut self, value: &str) -> Result<()>;
+}
+
+struct IoOutput<W>(W);
+
+impl<W: Write> Output for IoOutput<&mut W> {
+    fn write_str(&mut self, value: &str) -> Result<()> {
+        self.0.write_all(value.as_bytes())?;
+        Ok(())
+    }
+}
+
+struct FmtOutput<W>(W);
+
+impl<W: fmt::Write> Output for FmtOutput<&mut W> {
+    fn write_str(&mut self, value: &str) -> Result<()> {
+        self.0.write_str(value).map_err(Error::Fmt)
+    }
+}
+
+impl Output for String {
+    fn write_str(&mut self, value: &str) -> Result<()> {
+        self.push_str(value);
+        Ok(())
+    }
+}
+
+// Fails once more than `max` bytes have been written, counting what was
+// written to other outputs sharing `written`.
+struct LimitedOutput<'a, W> {
+    inner: &'a mut W,
+    max: Option<usize>,
+    written: Rc<Cell<usize>>,
+}
+
+impl<W: Output> Output for LimitedOutput<'_, W> {
+    fn write_str(&mut self, value: &str) -> Result<()> {
+        let written = self.written.get() + value.len();
+        self.written.set(written);
+        if let Some(max) = self.max {
+            if written > max {
+                return Err(Error::OutputTooLarge(max));
+            }
+        }
+
+        self.inner.write_str(value)
     }
 }
 
 struct RenderContext<'a> {
     template: &'a Template,
+    escaper: &'a dyn Escaper,
     indent: String,
     line_start: bool,
     at: String,
+    blocks: HashMap<String, Rc<[Token]>>,
+    // What lambdas returned, compiled, by source and delimiters, most
+    // recently used first.
+    lambdas: VecDeque<CompiledLambda>,
+    // The template or partial currently being rendered.
+    name: Option<String>,
+    // How deeply nested the tokens being rendered are, and how many times
+    // section contents have been rendered so far.
+    depth: usize,
+    iterations: usize,
+    // How many bytes have been written to the output, plus those in strings
+    // lambdas are rendering into.
+    written: Rc<Cell<usize>>,
 }
 
 impl<'a> RenderContext<'a> {
-    fn new(template: &'a Template) -> RenderContext<'a> {
+    fn new(template: &'a Template, escaper: &'a dyn Escaper) -> RenderContext<'a> {
         RenderContext {
             template,
+            escaper,
             indent: "".to_string(),
             line_start: true,
             at: "".to_string(),
+            blocks: HashMap::new(),
+            lambdas: VecDeque::new(),
+            name: template.name.clone(),
+            depth: 0,
+            iterations: 0,
+            written: Rc::default(),
         }
     }
 
-    fn render<W: Write>(
+    fn render<W: Output>(
         &mut self,
         wr: &mut W,
-        stack: &mut Vec<&Data>,
+        stack: &mut Vec<&dyn ContextValue>,
         tokens: &[Token],
     ) -> Result<()> {
-        for token in tokens.iter() {
-            self.render_token(wr, stack, token)?;
+        if let Some(max) = self.template.ctx.limits.max_depth {
+            if self.depth > max {
+                return Err(Error::TooDeep(max));
+            }
         }
 
-        Ok(())
+        self.depth += 1;
+        let result = tokens
+            .iter()
+            .try_for_each(|token| self.render_token(wr, stack, token));
+        self.depth -= 1;
+
+        result
+    }
+
+    // Renders the contents of a section, counting it against the iteration
+    // limit.
+    fn render_contents<W: Output>(
+        &mut self,
+        wr: &mut W,
+        stack: &mut Vec<&dyn ContextValue>,
+        children: &[Token],
+    ) -> Result<()> {
+        self.iterations += 1;
+        if let Some(max) = self.template.ctx.limits.max_iterations {
+            if self.iterations > max {
+                return Err(Error::TooManyIterations(max));
+            }
+        }
+
+        self.render(wr, stack, children)
     }
 
-    fn render_token<W: Write>(
+    fn render_token<W: Output>(
         &mut self,
         wr: &mut W,
-        stack: &mut Vec<&Data>,
+        stack: &mut Vec<&dyn ContextValue>,
         token: &Token,
     ) -> Result<()> {
         match *token {
             #[cfg(feature = "CFEngine")]
-            Token::At => self.render_at(wr),
+            Token::At(_) => self.render_at(wr),
             #[cfg(feature = "CFEngine")]
-            Token::JSON(ref path, _) => self.render_json(wr, stack, path, false),
+            Token::JSON(ref path, _, span) => self.render_json(wr, stack, path, span, false),
             #[cfg(feature = "CFEngine")]
-            Token::JSONMulti(ref path, _) => self.render_json(wr, stack, path, true),
+            Token::JSONMulti(ref path, _, span) => self.render_json(wr, stack, path, span, true),
             #[cfg(feature = "CFEngine")]
-            Token::TopJSON(ref path, _) => self.render_json(wr, stack, path, false),
+            Token::TopJSON(ref path, _, span) => self.render_json(wr, stack, path, span, false),
             #[cfg(feature = "CFEngine")]
-            Token::TopJSONMulti(ref path, _) => self.render_json(wr, stack, path, true),
+            Token::TopJSONMulti(ref path, _, span) => self.render_json(wr, stack, path, span, true),
             #[cfg(feature = "CFEngine")]
-            Token::TopSection(ref children) => self.render_section_top(wr, stack, children),
-            Token::Text(ref value) => self.render_text(wr, value),
-            Token::EscapedTag(ref path, _) => self.render_etag(wr, stack, path),
-            Token::UnescapedTag(ref path, _) => self.render_utag(wr, stack, path),
-            Token::Section(ref path, true, ref children, _, _, _) => {
+            Token::TopSection(ref children, _) => self.render_section_top(wr, stack, children),
+            Token::Text(ref value, _) => self.render_text(wr, value),
+            Token::EscapedTag(ref path, ref tag, span) => {
+                self.check_resolved(stack, path, tag, span)?;
+                self.render_etag(wr, stack, path, span)
+            }
+            Token::UnescapedTag(ref path, ref tag, span) => {
+                self.check_resolved(stack, path, tag, span)?;
+                self.render_utag(wr, stack, path, span)
+            }
+            Token::Section(ref path, true, ref children, ref tag, _, _, span) => {
+                self.check_resolved(stack, path, tag, span)?;
                 self.render_inverted_section(wr, stack, path, children)
             }
-            Token::Section(ref path, false, ref children, _, _, ref fdata) => {
-                self.render_section(wr, stack, path, children, fdata)
+            Token::Section(ref path, false, ref children, ref tag, _, ref fdata, span) => {
+                self.check_resolved(stack, path, tag, span)?;
+                self.render_section(wr, stack, path, children, fdata, span)
+            }
+            Token::Partial(ref name, ref indent, ref tag, span) => {
+                self.check_partial(name, tag, span)?;
+                self.render_partial(wr, stack, name, indent)
+            }
+            Token::DynamicPartial(ref path, ref indent, ref tag, span) => {
+                self.render_dynamic_partial(wr, stack, path, indent, tag, span)
+            }
+            Token::Parent(ref name, ref indent, ref children, ref tag, _, span) => {
+                self.check_partial(name, tag, span)?;
+                self.render_parent(wr, stack, name, indent, children)
             }
-            Token::Partial(ref name, ref indent, _) => self.render_partial(wr, stack, name, indent),
-            Token::IncompleteSection(..) => {
+            Token::Block(ref name, ref indent, ref children, _, _, _) => {
+                self.render_block(wr, stack, name, indent, children)
+            }
+            Token::IncompleteSection(..)
+            | Token::IncompleteParent(..)
+            | Token::IncompleteBlock(..) => {
                 bug!("render_token should not encounter IncompleteSections");
                 Err(Error::IncompleteSection)
             }
         }
     }
 
-    fn write_tracking_newlines<W: Write>(&mut self, wr: &mut W, value: &str) -> Result<()> {
-        wr.write_all(value.as_bytes())?;
+    fn write_tracking_newlines<W: Output>(&mut self, wr: &mut W, value: &str) -> Result<()> {
+        wr.write_str(value)?;
         self.line_start = match value.chars().last() {
             None => self.line_start, // None == ""
             Some('\n') => true,
@@ -143,16 +567,16 @@ impl<'a> RenderContext<'a> {
         Ok(())
     }
 
-    fn write_indent<W: Write>(&mut self, wr: &mut W) -> Result<()> {
+    fn write_indent<W: Output>(&mut self, wr: &mut W) -> Result<()> {
         if self.line_start {
-            wr.write_all(self.indent.as_bytes())?;
+            wr.write_str(&self.indent)?;
         }
 
         Ok(())
     }
 
     #[cfg(feature = "CFEngine")]
-    fn render_at<W: Write>(&mut self, wr: &mut W) -> Result<()> {
+    fn render_at<W: Output>(&mut self, wr: &mut W) -> Result<()> {
         if !self.at.is_empty() {
             let at = self.at.clone();
             self.write_tracking_newlines(wr, &at)?;
@@ -160,7 +584,7 @@ impl<'a> RenderContext<'a> {
         Ok(())
     }
 
-    fn render_text<W: Write>(&mut self, wr: &mut W, value: &str) -> Result<()> {
+    fn render_text<W: Output>(&mut self, wr: &mut W, value: &str) -> Result<()> {
         // Indent the lines.
         if self.indent.is_empty() {
             return self.write_tracking_newlines(wr, value);
@@ -194,35 +618,39 @@ impl<'a> RenderContext<'a> {
         Ok(())
     }
 
-    fn render_etag<W: Write>(
+    fn render_etag<W: Output>(
         &mut self,
         wr: &mut W,
-        stack: &mut Vec<&Data>,
+        stack: &mut Vec<&dyn ContextValue>,
         path: &[String],
+        span: Span,
     ) -> Result<()> {
-        let mut bytes = vec![];
+        let mut value = String::new();
+
+        let line_start = self.line_start;
+        self.render_utag(&mut value, stack, path, span)?;
 
-        self.render_utag(&mut bytes, stack, path)?;
+        let mut value = value.as_str();
 
-        for b in bytes {
-            match b {
-                b'<' => wr.write_all(b"&lt;")?,
-                b'>' => wr.write_all(b"&gt;")?,
-                b'&' => wr.write_all(b"&amp;")?,
-                b'"' => wr.write_all(b"&quot;")?,
-                b'\'' => wr.write_all(b"&#39;")?,
-                _ => wr.write_all(&[b])?,
+        // The partial indentation isn't part of the value, so leave it alone.
+        if line_start && !self.indent.is_empty() {
+            if let Some(rest) = value.strip_prefix(self.indent.as_str()) {
+                wr.write_str(&self.indent)?;
+                value = rest;
             }
         }
 
+        wr.write_str(&self.escaper.escape(value))?;
+
         Ok(())
     }
 
-    fn render_utag<W: Write>(
+    fn render_utag<W: Output>(
         &mut self,
         wr: &mut W,
-        stack: &mut Vec<&Data>,
+        stack: &mut Vec<&dyn ContextValue>,
         path: &[String],
+        span: Span,
     ) -> Result<()> {
         match self.find(path, stack) {
             None => {}
@@ -233,28 +661,42 @@ impl<'a> RenderContext<'a> {
                 // would be un-nameable in the view anyway, so I'm unsure if it's
                 // a real problem. Having {{foo}} render only when `foo = Some(Some(val))`
                 // seems unintuitive and may be surprising in practice.
-                if let Data::Null = *value {
-                    return Ok(());
-                }
+                match value.value() {
+                    Value::Null => {}
 
-                match *value {
-                    Data::String(ref value) => {
-                        self.write_tracking_newlines(wr, value)?;
+                    Value::String(value) => {
+                        self.write_tracking_newlines(wr, &value)?;
                     }
 
                     // etags and utags use the default delimiter.
-                    Data::Fun(ref fcell) => {
-                        let f = &mut *fcell.borrow_mut();
+                    Value::Lambda(f) => {
                         let tokens = self.render_fun("", "{{", "}}", f)?;
                         self.render(wr, stack, &tokens)?;
                     }
 
-                    Data::Bool(ref b) => {
+                    Value::ContextLambda(f) => {
+                        let value = self.call_lambda(stack, path, span, None, f)?;
+                        self.write_tracking_newlines(wr, &value)?;
+                    }
+
+                    Value::Bool(b) => {
                         self.write_tracking_newlines(wr, &b.to_string())?;
                     }
 
-                    ref value => {
-                        bug!("render_utag: unexpected value {:?}", value);
+                    Value::Int(n) => {
+                        self.write_tracking_newlines(wr, &n.to_string())?;
+                    }
+
+                    Value::UInt(n) => {
+                        self.write_tracking_newlines(wr, &n.to_string())?;
+                    }
+
+                    Value::Float(n) => {
+                        self.write_tracking_newlines(wr, &n.to_string())?;
+                    }
+
+                    Value::List(_) | Value::Stream(_) | Value::Map => {
+                        bug!("render_utag: unexpected list or map at {:?}", path);
                     }
                 }
             }
@@ -264,56 +706,65 @@ impl<'a> RenderContext<'a> {
     }
 
     #[cfg(feature = "CFEngine")]
-    fn write_tracking_newlines_json<T: serde::Serialize, W: Write>(
+    fn write_tracking_newlines_json<W: Output>(
         &mut self,
         wr: &mut W,
-        data: T,
+        data: &dyn ContextValue,
         pretty: bool,
     ) -> Result<()> {
         let json = match pretty {
-            true => serde_json::to_string_pretty(&data),
-            false => serde_json::to_string(&data),
+            true => serde_json::to_string_pretty(&Serialized(data)),
+            false => serde_json::to_string(&Serialized(data)),
         };
         self.write_tracking_newlines(wr, &json.unwrap_or(String::new()))?;
         Ok(())
     }
 
     #[cfg(feature = "CFEngine")]
-    fn render_json<W: Write>(
+    fn render_json<W: Output>(
         &mut self,
         wr: &mut W,
-        stack: &mut Vec<&Data>,
+        stack: &mut Vec<&dyn ContextValue>,
         path: &[String],
+        span: Span,
         pretty: bool,
     ) -> Result<()> {
         if path.first() == Some(&"-top-".to_string()) {
-            if let Some(v) = stack.first() {
-                self.write_tracking_newlines_json(wr, &v, pretty)?;
+            if let Some(&v) = stack.first() {
+                self.write_tracking_newlines_json(wr, v, pretty)?;
             }
         } else {
             match self.find(path, stack) {
                 None => {}
                 Some(value) => {
                     self.write_indent(wr)?;
-                    match *value {
-                        Data::Null => {}
-                        Data::String(ref v) => {
-                            self.write_tracking_newlines(wr, v)?;
+                    match value.value() {
+                        Value::Null => {}
+                        Value::String(v) => {
+                            self.write_tracking_newlines(wr, &v)?;
+                        }
+                        Value::Bool(v) => {
+                            self.write_tracking_newlines(wr, &v.to_string())?;
+                        }
+                        Value::Int(v) => {
+                            self.write_tracking_newlines(wr, &v.to_string())?;
+                        }
+                        Value::UInt(v) => {
+                            self.write_tracking_newlines(wr, &v.to_string())?;
                         }
-                        Data::Bool(ref v) => {
+                        Value::Float(v) => {
                             self.write_tracking_newlines(wr, &v.to_string())?;
                         }
-                        Data::Fun(ref fcell) => {
-                            let f = &mut *fcell.borrow_mut();
+                        Value::Lambda(f) => {
                             let tokens = self.render_fun("", "{{", "}}", f)?;
                             self.render(wr, stack, &tokens)?;
                         }
-                        Data::Vec(ref v) => {
-                            self.write_tracking_newlines_json(wr, v, pretty)?;
+                        Value::ContextLambda(f) => {
+                            let value = self.call_lambda(stack, path, span, None, f)?;
+                            self.write_tracking_newlines(wr, &value)?;
                         }
-                        Data::Map(ref v) => {
-                            let v: BTreeMap<_, _> = v.into_iter().collect();
-                            self.write_tracking_newlines_json(wr, &v, pretty)?;
+                        Value::List(_) | Value::Stream(_) | Value::Map => {
+                            self.write_tracking_newlines_json(wr, value, pretty)?;
                         }
                     }
                 }
@@ -322,227 +773,469 @@ impl<'a> RenderContext<'a> {
         Ok(())
     }
 
-    fn render_inverted_section<W: Write>(
+    fn render_inverted_section<W: Output>(
         &mut self,
         wr: &mut W,
-        stack: &mut Vec<&Data>,
+        stack: &mut Vec<&dyn ContextValue>,
         path: &[String],
         children: &[Token],
     ) -> Result<()> {
-        match self.find(path, stack) {
-            None => {}
-            Some(Data::Null) => {}
-            Some(Data::Bool(false)) => {}
-            Some(Data::Vec(xs)) if xs.is_empty() => {}
-            Some(_) => {
-                return Ok(());
-            }
+        let falsy = match self.find(path, stack).map(|value| value.value()) {
+            None | Some(Value::Null) | Some(Value::Bool(false)) => true,
+            Some(Value::Int(n)) => n == 0,
+            Some(Value::Float(n)) => is_falsy_float(n),
+            Some(Value::List(mut items)) => items.next().is_none(),
+            Some(Value::Stream(mut items)) => items.next().is_none(),
+            Some(_) => false,
+        };
+        if !falsy {
+            return Ok(());
         }
 
-        self.render(wr, stack, children)
+        self.render_contents(wr, stack, children)
     }
 
     #[cfg(feature = "CFEngine")]
-    fn render_section_top<W: Write>(
+    fn render_section_top<W: Output>(
         &mut self,
         wr: &mut W,
-        stack: &mut Vec<&Data>,
+        stack: &mut Vec<&dyn ContextValue>,
         children: &[Token],
     ) -> Result<()> {
-        let i = stack.clone();
-        let nstack = i.iter();
-        for v in nstack {
-            match Some(v) {
-                None => {}
-                Some(value) => match *value {
-                    Data::Map(m) => {
-                        if children.contains(&Token::At) {
-                            let b: BTreeMap<_, _> = m.into_iter().collect();
-                            for (k, v) in b.iter() {
-                                stack.push(v);
-                                self.at = k.to_string();
-                                self.render(wr, stack, children)?;
-                                self.at = "".to_string();
-                                stack.pop();
-                            }
-                        } else {
-                            stack.push(value);
-                            self.render(wr, stack, children)?;
-                            stack.pop();
-                        }
+        let values = stack.clone();
+        for value in values {
+            if let Value::Map = value.value() {
+                if children.iter().any(|t| matches!(t, Token::At(_))) {
+                    for (k, v) in value.entries() {
+                        stack.push(v);
+                        self.at = k.to_string();
+                        self.render_contents(wr, stack, children)?;
+                        self.at = "".to_string();
+                        stack.pop();
                     }
-                    _ => {}
-                },
-            };
+                } else {
+                    stack.push(value);
+                    self.render_contents(wr, stack, children)?;
+                    stack.pop();
+                }
+            }
         }
         Ok(())
     }
 
-    fn render_section<W: Write>(
+    fn render_section<W: Output>(
         &mut self,
         wr: &mut W,
-        stack: &mut Vec<&Data>,
+        stack: &mut Vec<&dyn ContextValue>,
         path: &[String],
         children: &[Token],
-        fdata: &[String],
+        fdata: &SectionSource,
+        span: Span,
     ) -> Result<()> {
-        match self.find(path, stack) {
-            None => {}
-            Some(value) => match value {
-                Data::Null => {}
-                Data::Bool(true) => self.render(wr, stack, children)?,
-                Data::Bool(false) => (),
-                Data::String(val) => {
-                    if !val.is_empty() {
-                        stack.push(value);
-                        self.render(wr, stack, children)?;
-                        stack.pop();
-                    }
+        let Some(value) = self.find(path, stack) else {
+            return Ok(());
+        };
+
+        match value.value() {
+            Value::Null | Value::Bool(false) => {}
+            Value::Bool(true) => self.render_contents(wr, stack, children)?,
+            Value::Int(0) => {}
+            Value::Float(n) if is_falsy_float(n) => {}
+            Value::Int(_) | Value::UInt(_) | Value::Float(_) => {
+                stack.push(value);
+                self.render_contents(wr, stack, children)?;
+                stack.pop();
+            }
+            Value::String(val) => {
+                if !val.is_empty() {
+                    stack.push(value);
+                    self.render_contents(wr, stack, children)?;
+                    stack.pop();
+                }
+            }
+            Value::List(items) => {
+                for (i, v) in items.enumerate() {
+                    stack.push(v);
+                    self.at = i.to_string();
+                    self.render_contents(wr, stack, children)?;
+                    self.at = "".to_string();
+                    stack.pop();
+                }
+            }
+            Value::Stream(items) => {
+                // Each item only lives until the next one is made, so it
+                // goes on a copy of the stack.
+                for (i, v) in items.enumerate() {
+                    let mut stack = stack.clone();
+                    stack.push(&*v);
+                    self.at = i.to_string();
+                    self.render_contents(wr, &mut stack, children)?;
+                    self.at = "".to_string();
                 }
-                Data::Vec(vs) => {
-                    for (i, v) in vs.iter().enumerate() {
+            }
+            Value::Map => {
+                #[cfg(feature = "CFEngine")]
+                if children.iter().any(|t| matches!(t, Token::At(_))) {
+                    for (k, v) in value.entries() {
                         stack.push(v);
-                        self.at = i.to_string();
-                        self.render(wr, stack, children)?;
+                        self.at = k.to_string();
+                        self.render_contents(wr, stack, children)?;
                         self.at = "".to_string();
                         stack.pop();
                     }
+                    return Ok(());
                 }
-                Data::Map(_m) => {
-                    #[cfg(feature = "CFEngine")]
-                    if children.contains(&Token::At) {
-                        let b: BTreeMap<_, _> = _m.into_iter().collect();
-                        for (k, v) in b.iter() {
-                            stack.push(v);
-                            self.at = k.to_string();
-                            self.render(wr, stack, children)?;
-                            self.at = "".to_string();
-                            stack.pop();
-                        }
-                    } else {
-                        stack.push(value);
-                        self.render(wr, stack, children)?;
-                        stack.pop();
-                    }
 
-                    #[cfg(not(feature = "CFEngine"))]
-                    {
-                        stack.push(value);
-                        self.render(wr, stack, children)?;
-                        stack.pop();
-                    }
-                }
-                Data::Fun(fcell) => {
-                    let f = &mut *fcell.borrow_mut();
-                    let tokens = self.render_fun(&fdata[1], &fdata[0], &fdata[2], f)?;
-                    self.render(wr, stack, &tokens)?;
-                }
-            },
-        };
+                stack.push(value);
+                self.render_contents(wr, stack, children)?;
+                stack.pop();
+            }
+            Value::Lambda(f) => {
+                let tokens = self.render_fun(fdata.as_str(), &fdata.otag, &fdata.ctag, f)?;
+                self.render(wr, stack, &tokens)?;
+            }
+            Value::ContextLambda(f) => {
+                let section = Some((children, fdata));
+                let value = self.call_lambda(stack, path, span, section, f)?;
+                self.write_tracking_newlines(wr, &value)?;
+            }
+        }
         Ok(())
     }
 
-    fn render_partial<W: Write>(
+    fn render_partial<W: Output>(
         &mut self,
         wr: &mut W,
-        stack: &mut Vec<&Data>,
+        stack: &mut Vec<&dyn ContextValue>,
         name: &str,
         indent: &str,
     ) -> Result<()> {
-        match self.template.partials.get(name) {
-            None => (),
-            Some(tokens) => {
-                let mut indent = self.indent.clone() + indent;
+        let template = self.template;
+        let loaded;
+        let tokens: &[Token] = match template.partials.get(name) {
+            Some(tokens) => tokens,
+            // Take the partial out of the cache, which rendering may add to.
+            None => match template.loaded().partials.get(name) {
+                Some(tokens) => {
+                    loaded = tokens.clone();
+                    &loaded
+                }
+                None => return Ok(()),
+            },
+        };
+
+        let mut name = Some(name.to_string());
+
+        mem::swap(&mut self.name, &mut name);
+        let result = self.render_indented(wr, stack, indent, tokens);
+        mem::swap(&mut self.name, &mut name);
+
+        result
+    }
 
-                mem::swap(&mut self.indent, &mut indent);
-                self.render(wr, stack, tokens)?;
-                mem::swap(&mut self.indent, &mut indent);
+    fn render_dynamic_partial<W: Output>(
+        &mut self,
+        wr: &mut W,
+        stack: &mut Vec<&dyn ContextValue>,
+        path: &[String],
+        indent: &str,
+        tag: &str,
+        span: Span,
+    ) -> Result<()> {
+        let name = match self.find(path, stack).map(|value| value.value()) {
+            Some(Value::String(name)) => name.into_owned(),
+            _ if self.template.ctx.strict => {
+                return Err(self.unresolved(path.join("."), tag, span));
             }
+            _ => return Ok(()),
         };
 
+        self.load_partial(&name)?;
+        self.check_partial(&name, tag, span)?;
+        self.render_partial(wr, stack, &name, indent)
+    }
+
+    // Compiles a partial that wasn't known when the template was compiled.
+    fn load_partial(&mut self, name: &str) -> Result<()> {
+        let template = self.template;
+        if self.compiled(name) {
+            return Ok(());
+        }
+
+        let mut partials = PartialsMap::new();
+        compiler::compile_partial(
+            &template.ctx,
+            name,
+            &|name| self.compiled(name),
+            &mut partials,
+        )?;
+
+        let mut loaded = template.loaded_mut();
+        if !partials.contains_key(name) {
+            loaded.missing.insert(name.to_string());
+        }
+        for (name, tokens) in partials {
+            loaded.partials.entry(name).or_insert_with(|| tokens.into());
+        }
+
         Ok(())
     }
 
-    fn render_fun(
+    // In strict mode, fails if `path` can't be found in the data.
+    fn check_resolved(
         &self,
-        src: &str,
-        otag: &str,
-        ctag: &str,
-        f: &mut Box<dyn FnMut(String) -> String + Send + 'static>,
-    ) -> Result<Vec<Token>> {
-        let src = f(src.to_string());
+        stack: &mut Vec<&dyn ContextValue>,
+        path: &[String],
+        tag: &str,
+        span: Span,
+    ) -> Result<()> {
+        if self.template.ctx.strict && self.find(path, stack).is_none() {
+            return Err(self.unresolved(path.join("."), tag, span));
+        }
+
+        Ok(())
+    }
+
+    // Whether the partial called `name` was compiled already, or is known to
+    // be missing.
+    fn compiled(&self, name: &str) -> bool {
+        let template = self.template;
+        if template.partials.contains_key(name) {
+            return true;
+        }
+        let loaded = template.loaded();
+        loaded.partials.contains_key(name) || loaded.missing.contains(name)
+    }
+
+    // In strict mode, fails if there is no partial called `name`.
+    fn check_partial(&self, name: &str, tag: &str, span: Span) -> Result<()> {
+        if self.template.ctx.strict
+            && !self.template.partials.contains_key(name)
+            && !self.template.loaded().partials.contains_key(name)
+        {
+            return Err(self.unresolved(name.to_string(), tag, span));
+        }
+
+        Ok(())
+    }
+
+    fn unresolved(&self, name: String, tag: &str, span: Span) -> Error {
+        Error::Unresolved(name, tag.to_string(), span, self.name.clone())
+    }
+
+    fn render_indented<W: Output>(
+        &mut self,
+        wr: &mut W,
+        stack: &mut Vec<&dyn ContextValue>,
+        indent: &str,
+        tokens: &[Token],
+    ) -> Result<()> {
+        let mut indent = self.indent.clone() + indent;
+
+        mem::swap(&mut self.indent, &mut indent);
+        let result = self.render(wr, stack, tokens);
+        mem::swap(&mut self.indent, &mut indent);
+
+        result
+    }
+
+    fn render_parent<W: Output>(
+        &mut self,
+        wr: &mut W,
+        stack: &mut Vec<&dyn ContextValue>,
+        name: &str,
+        indent: &str,
+        children: &[Token],
+    ) -> Result<()> {
+        // Blocks overridden further out take precedence over the ones
+        // overridden here, so only fill in the blocks that aren't set yet.
+        let mut blocks = self.blocks.clone();
+        for child in children.iter() {
+            if let Token::Block(ref block, _, ref tokens, _, _, _) = *child {
+                blocks
+                    .entry(block.clone())
+                    .or_insert_with(|| tokens.as_slice().into());
+            }
+        }
+
+        mem::swap(&mut self.blocks, &mut blocks);
+        let result = self.render_partial(wr, stack, name, indent);
+        mem::swap(&mut self.blocks, &mut blocks);
+
+        result
+    }
+
+    fn render_block<W: Output>(
+        &mut self,
+        wr: &mut W,
+        stack: &mut Vec<&dyn ContextValue>,
+        name: &str,
+        indent: &str,
+        children: &[Token],
+    ) -> Result<()> {
+        // Overrides were stripped of their own indentation, so they take on
+        // the indentation of the block they replace.
+        match self.blocks.get(name) {
+            Some(tokens) => {
+                let tokens = tokens.clone();
+                self.render_indented(wr, stack, indent, &tokens)
+            }
+            None => self.render(wr, stack, children),
+        }
+    }
+
+    fn render_fun(&mut self, src: &str, otag: &str, ctag: &str, f: &Lambda) -> Result<Rc<[Token]>> {
+        self.compile_fun(&f(src.to_string()), otag, ctag)
+    }
+
+    // Lambdas in a section that is rendered many times usually return the
+    // same few texts, so the last ones compiled are kept for the render.
+    fn compile_fun(&mut self, src: &str, otag: &str, ctag: &str) -> Result<Rc<[Token]>> {
+        let found = self
+            .lambdas
+            .iter()
+            .position(|l| l.src == src && l.otag == otag && l.ctag == ctag);
+        if let Some(lambda) = found.and_then(|i| self.lambdas.remove(i)) {
+            let tokens = lambda.tokens.clone();
+            self.lambdas.push_front(lambda);
+            return Ok(tokens);
+        }
 
         let compiler = Compiler::new_with(
             self.template.ctx.clone(),
             src.chars(),
-            self.template.partials.clone(),
             otag.to_string(),
             ctag.to_string(),
         );
 
-        let (tokens, _) = compiler.compile()?;
-        Ok(tokens)
-    }
+        let (tokens, partials) = compiler.compile_with(&|name| self.compiled(name))?;
 
-    fn find<'c>(&self, path: &[String], stack: &mut Vec<&'c Data>) -> Option<&'c Data> {
-        // If we have an empty path, we just want the top value in our stack.
-        if path.is_empty() {
-            match stack.last() {
-                None => {
-                    return None;
-                }
-                Some(data) => {
-                    return Some(*data);
-                }
+        // Keep partials that only the lambda uses, like dynamic ones.
+        if !partials.is_empty() {
+            let mut loaded = self.template.loaded_mut();
+            for (name, tokens) in partials {
+                loaded.partials.entry(name).or_insert_with(|| tokens.into());
             }
         }
 
-        // Otherwise, find the stack that has the first part of our path.
-        let mut value = None;
-
-        for data in stack.iter().rev() {
-            match **data {
-                Data::Map(ref m) => {
-                    if let Some(v) = m.get(&path[0]) {
-                        value = Some(v);
-                        break;
-                    }
-                }
-                _ => { /* continue searching the stack */ }
-            }
-        }
+        let tokens: Rc<[Token]> = tokens.into();
+        self.lambdas.push_front(CompiledLambda {
+            src: src.to_string(),
+            otag: otag.to_string(),
+            ctag: ctag.to_string(),
+            tokens: tokens.clone(),
+        });
+        self.lambdas.truncate(LAMBDA_CACHE);
+        Ok(tokens)
+    }
 
-        // Walk the rest of the path to find our final value.
-        let mut value = match value {
-            Some(value) => value,
-            None => {
-                return None;
-            }
+    // Calls the lambda found at `path` by the tag at `span`. Sections pass
+    // their contents along with `fdata`, their delimiters and source, while
+    // etags and utags use the default delimiter.
+    fn call_lambda(
+        &mut self,
+        stack: &[&dyn ContextValue],
+        path: &[String],
+        span: Span,
+        section: Option<(&[Token], &SectionSource)>,
+        f: &ContextLambda,
+    ) -> Result<String> {
+        let (children, source, otag, ctag) = match section {
+            Some((children, fdata)) => (children, fdata.as_str(), &*fdata.otag, &*fdata.ctag),
+            None => (&[][..], "", "{{", "}}"),
         };
 
-        for part in path[1..].iter() {
-            match *value {
-                Data::Map(ref m) => match m.get(part) {
-                    Some(v) => {
-                        value = v;
-                    }
-                    None => {
-                        return None;
-                    }
-                },
-                _ => {
-                    return None;
-                }
+        let result = f(&mut LambdaContext {
+            render: self,
+            stack,
+            children,
+            source,
+            otag,
+            ctag,
+        });
+
+        result.map_err(|err| match err.downcast::<Error>() {
+            // Errors from rendering inside the lambda already say where they
+            // happened.
+            Ok(err) => *err,
+            Err(err) => {
+                let name = match path.is_empty() {
+                    true => ".".to_string(),
+                    false => path.join("."),
+                };
+                Error::Lambda(name, span, self.name.clone(), err)
             }
+        })
+    }
+
+    fn find<'c>(
+        &self,
+        path: &[String],
+        stack: &[&'c dyn ContextValue],
+    ) -> Option<&'c dyn ContextValue> {
+        // If we have an empty path, we just want the top value in our stack.
+        let (first, rest) = match path.split_first() {
+            None => return stack.last().copied(),
+            Some(split) => split,
+        };
+
+        // Otherwise, find the stack that has the first part of our path.
+        let mut value = stack.iter().rev().find_map(|&data| data.get(first))?;
+
+        // Walk the rest of the path to find our final value.
+        for part in rest.iter() {
+            value = value.get(part)?;
         }
 
         Some(value)
     }
 }
 
+// How many compiled lambda results a render keeps.
+const LAMBDA_CACHE: usize = 16;
+
+// What a lambda returned, compiled with the delimiters in effect.
+struct CompiledLambda {
+    src: String,
+    otag: String,
+    ctag: String,
+    tokens: Rc<[Token]>,
+}
+
+// Zero and NaN are falsy, as they are in JavaScript.
+fn is_falsy_float(n: f64) -> bool {
+    n == 0.0 || n.is_nan()
+}
+
+#[cfg(test)]
+mod lambda_tests {
+    use crate::compile_str;
+
+    use super::*;
+
+    #[test]
+    fn compiled_output_is_bounded() {
+        let template = compile_str("").expect("failed to compile");
+        let mut render = RenderContext::new(&template, &*template.ctx.escaper);
+        for i in 0..100 {
+            render
+                .compile_fun(&format!("<{i}>"), "{{", "}}")
+                .expect("failed to compile");
+            assert!(render.lambdas.len() <= LAMBDA_CACHE);
+        }
+
+        // The oldest output is dropped first.
+        assert_eq!(render.lambdas.len(), LAMBDA_CACHE);
+        assert_eq!(render.lambdas.back().unwrap().src, "<84>");
+        assert_eq!(render.lambdas.front().unwrap().src, "<99>");
+    }
+}
+
 #[cfg(feature = "CFEngine")]
 #[cfg(test)]
 mod tests {
+    use indexmap::IndexMap;
+
     use crate::compile_str;
 
     use super::*;
@@ -558,7 +1251,7 @@ mod tests {
     #[test]
     fn test_json_simple_string() {
         let template = compile_str("Hello, {{$name}}").expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("name".to_string(), Data::String("Ferris".to_string()));
         assert_eq!(
             render_data(&template, &Data::Map(ctx)),
@@ -574,7 +1267,7 @@ mod tests {
             Data::String("B".to_string()),
             Data::String("C".to_string()),
         ];
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("v".to_string(), Data::Vec(v));
         assert_eq!(
             render_data(&template, &Data::Map(ctx)),
@@ -585,10 +1278,10 @@ mod tests {
     #[test]
     fn test_json_simple_map() {
         let template = compile_str("{{$v}}").expect("failed to compile");
-        let mut v = HashMap::new();
+        let mut v = IndexMap::new();
         v.insert("k1".to_string(), Data::String("A".to_string()));
         v.insert("k2".to_string(), Data::String("B".to_string()));
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("v".to_string(), Data::Map(v));
         assert_eq!(
             render_data(&template, &Data::Map(ctx)),
@@ -600,16 +1293,28 @@ mod tests {
     fn test_json_bool() {
         let template = compile_str("{{$b}}").expect("failed to compile");
         let b = true;
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("b".to_string(), Data::Bool(b));
         assert_eq!(render_data(&template, &Data::Map(ctx)), "true".to_string());
     }
 
+    #[test]
+    fn test_json_numbers() {
+        let template = compile_str("{{$v}}").expect("failed to compile");
+        let v = vec![Data::Int(-1), Data::UInt(u64::MAX), Data::Float(0.5)];
+        let mut ctx = IndexMap::new();
+        ctx.insert("v".to_string(), Data::Vec(v));
+        assert_eq!(
+            render_data(&template, &Data::Map(ctx)),
+            "[-1,18446744073709551615,0.5]".to_string()
+        );
+    }
+
     #[test]
     fn test_bool() {
         let template = compile_str("{{b}}").expect("failed to compile");
         let b = true;
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("b".to_string(), Data::Bool(b));
         assert_eq!(render_data(&template, &Data::Map(ctx)), "true".to_string());
     }
@@ -618,7 +1323,7 @@ mod tests {
     fn test_top_json() {
         let template = compile_str("{{$-top-}}").expect("failed to compile");
         let b = true;
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("a".to_string(), Data::String("String".to_string()));
         ctx.insert("b".to_string(), Data::Bool(b));
         assert_eq!(
@@ -631,7 +1336,7 @@ mod tests {
     fn test_dot_json() {
         let template = compile_str("{{$.}}").expect("failed to compile");
         let b = true;
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("a".to_string(), Data::String("String".to_string()));
         ctx.insert("b".to_string(), Data::Bool(b));
         assert_eq!(
@@ -643,7 +1348,7 @@ mod tests {
     #[test]
     fn test_top_json_multi() {
         let template = compile_str("{{%-top-}}").expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("a".to_string(), Data::String("String".to_string()));
         ctx.insert("b".to_string(), Data::Bool(true));
         assert_eq!(
@@ -656,7 +1361,7 @@ mod tests {
     fn test_dot_json_multi() {
         let template = compile_str("{{%.}}").expect("failed to compile");
         let b = true;
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("a".to_string(), Data::String("String".to_string()));
         ctx.insert("b".to_string(), Data::Bool(b));
         assert_eq!(
@@ -668,7 +1373,7 @@ mod tests {
     #[test]
     fn test_section() {
         let template = compile_str("{{#a}}{{$.}} {{/a}}").expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         let v = vec![
             Data::String("String1".to_string()),
             Data::String("String2".to_string()),
@@ -684,7 +1389,7 @@ mod tests {
     #[test]
     fn test_top_section() {
         let template = compile_str("{{#-top-}}{{$.}}{{/-top-}}").expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("a".to_string(), Data::String("String".to_string()));
         ctx.insert("b".to_string(), Data::Bool(true));
         assert_eq!(
@@ -696,7 +1401,7 @@ mod tests {
     #[test]
     fn test_top_section_multi() {
         let template = compile_str("{{#-top-}}{{%.}}{{/-top-}}").expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("a".to_string(), Data::String("String".to_string()));
         ctx.insert("b".to_string(), Data::Bool(true));
         assert_eq!(
@@ -711,7 +1416,7 @@ mod tests {
             "{{#bt}}This text is rendered!{{/bt}}{{#bf}}This text is NOT rendered!{{/bf}}",
         )
         .expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("bt".to_string(), Data::Bool(true));
         ctx.insert("bf".to_string(), Data::Bool(false));
         assert_eq!(
@@ -724,7 +1429,7 @@ mod tests {
     fn test_rendering_vec_map_top() {
         let t = "{{#bf}}This text is NOT rendered{{/bf}}{{#fruits}}- {{$.}}\n{{/fruits}}\n{{$m}}\n{{$m.key3}}\n{{%-top-}}\n{{#bt}}This text is rendered!{{/bt}}";
         let template = compile_str(t).expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         let v = vec![
             Data::String("Apple".to_string()),
             Data::String("Cherry".to_string()),
@@ -737,7 +1442,7 @@ mod tests {
         ];
         ctx.insert("fruits".to_string(), Data::Vec(v));
 
-        let mut m = HashMap::new();
+        let mut m = IndexMap::new();
         m.insert("key1".to_string(), Data::String("Value1".to_string()));
         m.insert("key2".to_string(), Data::Bool(true));
         m.insert("key3".to_string(), Data::Vec(v2));
@@ -746,10 +1451,23 @@ mod tests {
         ctx.insert("bf".to_string(), Data::Bool(false));
         assert_eq!(
             render_data(&template, &Data::Map(ctx)),
-            "- Apple\n- Cherry\n- Orange\n{\"key1\":\"Value1\",\"key2\":true,\"key3\":[true,\"String1\",false]}\n[true,\"String1\",false]\n{\n  \"bf\": false,\n  \"bt\": true,\n  \"fruits\": [\n    \"Apple\",\n    \"Cherry\",\n    \"Orange\"\n  ],\n  \"m\": {\n    \"key1\": \"Value1\",\n    \"key2\": true,\n    \"key3\": [\n      true,\n      \"String1\",\n      false\n    ]\n  }\n}\nThis text is rendered!"
+            "- Apple\n- Cherry\n- Orange\n{\"key1\":\"Value1\",\"key2\":true,\"key3\":[true,\"String1\",false]}\n[true,\"String1\",false]\n{\n  \"fruits\": [\n    \"Apple\",\n    \"Cherry\",\n    \"Orange\"\n  ],\n  \"m\": {\n    \"key1\": \"Value1\",\n    \"key2\": true,\n    \"key3\": [\n      true,\n      \"String1\",\n      false\n    ]\n  },\n  \"bt\": true,\n  \"bf\": false\n}\nThis text is rendered!"
         );
     }
 
+    #[test]
+    fn test_map_at_keeps_order() {
+        let template = compile_str("{{#m}}{{@}}={{.}} {{/m}}").expect("failed to compile");
+        let mut m = IndexMap::new();
+        m.insert("z".to_string(), Data::Int(1));
+        m.insert("a".to_string(), Data::Int(2));
+        m.insert("m".to_string(), Data::Int(3));
+
+        let mut ctx = IndexMap::new();
+        ctx.insert("m".to_string(), Data::Map(m));
+        assert_eq!(render_data(&template, &Data::Map(ctx)), "z=1 a=2 m=3 ");
+    }
+
     #[test]
     fn test_vec_at() {
         let template = compile_str("{{#v}}{{@}} {{/v}}").expect("failed to compile");
@@ -758,7 +1476,7 @@ mod tests {
             Data::String("B".to_string()),
             Data::String("C".to_string()),
         ];
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("v".to_string(), Data::Vec(v));
         assert_eq!(
             render_data(&template, &Data::Map(ctx)),
@@ -769,12 +1487,12 @@ mod tests {
     #[test]
     fn test_map_at() {
         let template = compile_str("{{#m}}{{@}} {{/m}}").expect("failed to compile");
-        let mut m = HashMap::new();
+        let mut m = IndexMap::new();
         m.insert("key1".to_string(), Data::String("Value1".to_string()));
         m.insert("key2".to_string(), Data::Bool(true));
         m.insert("key3".to_string(), Data::String("Value3".to_string()));
 
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("m".to_string(), Data::Map(m));
         assert_eq!(
             render_data(&template, &Data::Map(ctx)),
@@ -782,12 +1500,12 @@ mod tests {
         );
 
         let template = compile_str("{{#m}}{{@}} {{@}} {{.}} {{/m}}").expect("failed to compile");
-        let mut m = HashMap::new();
+        let mut m = IndexMap::new();
         m.insert("key1".to_string(), Data::String("Value1".to_string()));
         m.insert("key2".to_string(), Data::Bool(true));
         m.insert("key3".to_string(), Data::String("Value3".to_string()));
 
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("m".to_string(), Data::Map(m));
         assert_eq!(
             render_data(&template, &Data::Map(ctx)),
@@ -798,13 +1516,13 @@ mod tests {
     #[test]
     fn test_top_section_at() {
         let template = compile_str("{{#-top-}}{{@}} {{/-top-}}").expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("a".to_string(), Data::String("String".to_string()));
         ctx.insert("b".to_string(), Data::Bool(true));
         assert_eq!(render_data(&template, &Data::Map(ctx)), "a b ".to_string());
 
         let template = compile_str("{{#-top-}}{{@}} {{.}} {{/-top-}}").expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("a".to_string(), Data::String("String".to_string()));
         ctx.insert("b".to_string(), Data::Bool(true));
         assert_eq!(
@@ -817,7 +1535,7 @@ mod tests {
     fn test_top_in_top_section_at() {
         let template =
             compile_str("{{#-top-}}{{@}} {{$-top-}} {{/-top-}}").expect("failed to compile");
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("a".to_string(), Data::String("String".to_string()));
         ctx.insert("b".to_string(), Data::Bool(true));
         assert_eq!(
@@ -835,7 +1553,7 @@ mod tests {
             Data::String("B".to_string()),
             Data::String("C".to_string()),
         ];
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("v".to_string(), Data::Vec(v));
         assert_eq!(
             render_data(&template, &Data::Map(ctx)),
@@ -848,11 +1566,11 @@ mod tests {
     fn test_top_section_inside_map_section() {
         let template = compile_str("{{#m}}{{@}} {{#-top-}}{{@}} {{$.}} {{/-top-}} {{/m}}")
             .expect("failed to compile");
-        let mut m = HashMap::new();
+        let mut m = IndexMap::new();
         m.insert("key1".to_string(), Data::String("Value1".to_string()));
         m.insert("key2".to_string(), Data::Bool(true));
         m.insert("key3".to_string(), Data::String("Value3".to_string()));
-        let mut ctx = HashMap::new();
+        let mut ctx = IndexMap::new();
         ctx.insert("m".to_string(), Data::Map(m));
         ctx.insert(
             "s".to_string(),
@@ -0,0 +1,262 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::ErrorKind::NotFound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::compiler::{self, PartialsMap};
+use crate::loader::{FileSystemLoader, PartialLoader, PartialSource, check_partial_name};
+use crate::template::{self, Template};
+use crate::{Context, ContextValue, Data, Error, Result};
+
+/// `TemplateSet` holds every template in a directory tree, compiled once and
+/// looked up by name.
+///
+/// Templates are named after their path relative to the directory, without
+/// the extension and with `/` separators, so `emails/welcome.mustache` is
+/// `emails/welcome`. They can include each other as partials by the same
+/// names, and every partial is compiled once for the whole set. When the
+/// context has several search roots, the set holds the templates of all of
+/// them, with earlier roots overriding later ones.
+///
+/// ```rust,no_run
+/// use std::path::PathBuf;
+/// use mustache::Context;
+///
+/// let set = Context::new(PathBuf::from("templates")).compile_dir().unwrap();
+/// let html = set.render("emails/welcome", &"World").unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct TemplateSet {
+    ctx: Context,
+    partials: Arc<PartialsMap>,
+    templates: BTreeMap<String, Template>,
+    // Where each template file is and when it was last modified, as of the
+    // last reload.
+    files: BTreeMap<String, (PathBuf, Option<SystemTime>)>,
+}
+
+/// Compiles the templates under the search roots of `ctx`.
+pub fn load(ctx: &Context) -> Result<TemplateSet> {
+    // Templates are partials of each other, so they are found in the
+    // directory before asking the context's own loader.
+    let mut ctx = ctx.clone();
+    ctx.partial_loader = Some(Arc::new(SetLoader {
+        roots: ctx
+            .search_roots()
+            .map(|root| FileSystemLoader {
+                path: root.to_path_buf(),
+                extension: ctx.template_extension.clone(),
+            })
+            .collect(),
+        fallback: ctx.partial_loader.take(),
+    }));
+
+    let mut set = TemplateSet {
+        ctx,
+        partials: Arc::default(),
+        templates: BTreeMap::new(),
+        files: BTreeMap::new(),
+    };
+    set.reload()?;
+
+    Ok(set)
+}
+
+impl TemplateSet {
+    /// Returns the named template.
+    pub fn get(&self, name: &str) -> Option<&Template> {
+        self.templates.get(name)
+    }
+
+    /// Returns the names of the templates in the set, in sorted order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(|name| name.as_str())
+    }
+
+    /// Renders the named template to a `String` with the `Encodable` data.
+    pub fn render<T: Serialize>(&self, name: &str, data: &T) -> Result<String> {
+        self.template(name)?.render_to_string(data)
+    }
+
+    /// Renders the named template to a `String` with the `Data`.
+    pub fn render_data(&self, name: &str, data: &Data) -> Result<String> {
+        self.template(name)?.render_data_to_string(data)
+    }
+
+    /// Renders the named template to a `String` with data that is looked up
+    /// in place.
+    pub fn render_value<V: ContextValue + ?Sized>(&self, name: &str, value: &V) -> Result<String> {
+        self.template(name)?.render_value_to_string(value)
+    }
+
+    /// Picks up changes to the template files since they were last compiled,
+    /// and returns the names of the templates and partials that were
+    /// recompiled.
+    ///
+    /// Files are compared by modification time, so this is cheap enough to
+    /// call before every render during development. Added, changed and
+    /// removed files are recompiled along with everything that includes
+    /// them. Partials from the context's own loader aren't watched. If a
+    /// template fails to compile the set is left as it was.
+    pub fn reload(&mut self) -> Result<Vec<String>> {
+        // Templates in earlier roots hide the ones with the same name in
+        // later roots.
+        let mut files = BTreeMap::new();
+        for root in self.ctx.search_roots() {
+            // Roots that don't exist have no templates, as when opening one.
+            if matches!(fs::metadata(root), Err(ref e) if e.kind() == NotFound) {
+                continue;
+            }
+            find_templates(root, &self.ctx.template_extension, "", &mut files)?;
+        }
+
+        let mut changed: BTreeSet<String> = files
+            .iter()
+            .filter(|&(name, file)| self.files.get(name) != Some(file))
+            .map(|(name, _)| name.clone())
+            .collect();
+        changed.extend(
+            self.files
+                .keys()
+                .filter(|name| !files.contains_key(*name))
+                .cloned(),
+        );
+        if changed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Keep what doesn't include anything that changed, and compile
+        // everything else again.
+        let stale = dependents(&self.partials, changed);
+        let mut partials: PartialsMap = self
+            .partials
+            .iter()
+            .filter(|&(name, _)| !stale.contains(name))
+            .map(|(name, tokens)| (name.clone(), tokens.clone()))
+            .collect();
+        for name in files.keys() {
+            compiler::compile_partial(&self.ctx, name, &|_| false, &mut partials)?;
+        }
+        let partials = Arc::new(partials);
+
+        let mut templates = BTreeMap::new();
+        for (name, (path, _)) in files.iter() {
+            // The file may have gone away since we listed the directory.
+            let Some(tokens) = partials.get(name) else {
+                continue;
+            };
+
+            let template = template::new(
+                self.ctx.for_path(path),
+                Some(path.display().to_string()),
+                tokens.clone(),
+                partials.clone(),
+            );
+            templates.insert(name.clone(), template);
+        }
+
+        let recompiled = stale
+            .into_iter()
+            .filter(|name| partials.contains_key(name))
+            .collect();
+
+        self.partials = partials;
+        self.templates = templates;
+        self.files = files;
+
+        Ok(recompiled)
+    }
+
+    fn template(&self, name: &str) -> Result<&Template> {
+        self.get(name)
+            .ok_or_else(|| Error::UnknownTemplate(name.to_string()))
+    }
+}
+
+// Returns `changed` and the names of everything that includes them, directly
+// or through other partials.
+fn dependents(partials: &PartialsMap, changed: BTreeSet<String>) -> BTreeSet<String> {
+    let mut included_by: HashMap<String, Vec<&str>> = HashMap::new();
+    for (name, tokens) in partials.iter() {
+        for dependency in compiler::dependencies(tokens) {
+            included_by.entry(dependency).or_default().push(name);
+        }
+    }
+
+    let mut pending: Vec<String> = changed.iter().cloned().collect();
+    let mut stale = changed;
+    while let Some(name) = pending.pop() {
+        for &parent in included_by.get(&name).into_iter().flatten() {
+            if stale.insert(parent.to_string()) {
+                pending.push(parent.to_string());
+            }
+        }
+    }
+
+    stale
+}
+
+// Collects the names of the templates in `dir` that aren't in `files` yet,
+// prefixing them with `prefix`, along with their paths and when they were last
+// modified.
+fn find_templates(
+    dir: &Path,
+    extension: &str,
+    prefix: &str,
+    files: &mut BTreeMap<String, (PathBuf, Option<SystemTime>)>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        // Names have to be valid partial names, so skip what can't be one.
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        if entry.file_type()?.is_dir() {
+            let prefix = format!("{prefix}{file_name}/");
+            find_templates(&path, extension, &prefix, files)?;
+        } else if path.extension().and_then(|ext| ext.to_str()) == Some(extension) {
+            if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                let name = format!("{prefix}{stem}");
+                if check_partial_name(&name).is_err() {
+                    continue;
+                }
+                if let Entry::Vacant(file) = files.entry(name) {
+                    let modified = entry.metadata()?.modified().ok();
+                    file.insert((path.clone(), modified));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Loads partials from the set's search roots, then from the loader the
+// context had.
+struct SetLoader {
+    roots: Vec<FileSystemLoader>,
+    fallback: Option<Arc<dyn PartialLoader>>,
+}
+
+impl PartialLoader for SetLoader {
+    fn load(&self, name: &str) -> Result<Option<PartialSource>> {
+        for root in self.roots.iter() {
+            if let Some(partial) = root.load(name)? {
+                return Ok(Some(partial));
+            }
+        }
+
+        match self.fallback {
+            Some(ref fallback) => fallback.load(name),
+            None => Ok(None),
+        }
+    }
+}
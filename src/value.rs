@@ -0,0 +1,352 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as StdError;
+
+use indexmap::IndexMap;
+#[cfg(feature = "CFEngine")]
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde_json::Value as Json;
+
+use crate::Data;
+use crate::encoder::f32_to_f64;
+use crate::template::LambdaContext;
+
+/// A lambda, as stored in `Data::Fun`.
+pub type Lambda = dyn Fn(String) -> String + Send + Sync;
+
+/// A lambda that is given a `LambdaContext`, as stored in `Data::Lambda`.
+///
+/// For a section the returned text is written out as is, so it is usually
+/// rendered with the context. For `{{name}}` tags it is escaped like any other
+/// value. Errors the lambda returns end rendering with `Error::Lambda`.
+pub type ContextLambda =
+    dyn Fn(&mut LambdaContext<'_, '_>) -> Result<String, LambdaError> + Send + Sync;
+
+/// An error returned by a lambda.
+pub type LambdaError = Box<dyn StdError + Send + Sync>;
+
+/// What a `ContextValue` is, as far as rendering is concerned.
+pub enum Value<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    /// Unsigned integers too large for an `i64`.
+    UInt(u64),
+    Float(f64),
+    String(Cow<'a, str>),
+    /// The items of a list.
+    List(Box<dyn Iterator<Item = &'a dyn ContextValue> + 'a>),
+    /// The items of a list that are made as they are rendered, rather than
+    /// stored.
+    Stream(Box<dyn Iterator<Item = Box<dyn ContextValue + 'a>> + 'a>),
+    /// A map, whose values are looked up with `ContextValue::get`.
+    Map,
+    Lambda(&'a Lambda),
+    ContextLambda(&'a ContextLambda),
+}
+
+/// `ContextValue` is data that templates can be rendered with in place,
+/// without copying it into a `Data` first. It is implemented for `Data`,
+/// `serde_json::Value` and the standard strings, numbers and collections, and
+/// can be implemented for other data.
+///
+/// ```rust
+/// use mustache::{ContextValue, Value};
+///
+/// struct Row {
+///     id: u32,
+///     tags: Vec<String>,
+/// }
+///
+/// impl ContextValue for Row {
+///     fn value(&self) -> Value<'_> {
+///         Value::Map
+///     }
+///
+///     fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+///         match key {
+///             "id" => Some(&self.id),
+///             "tags" => Some(&self.tags),
+///             _ => None,
+///         }
+///     }
+/// }
+///
+/// let row = Row { id: 7, tags: vec!["a".to_string(), "b".to_string()] };
+/// let template = mustache::compile_str("{{id}}: {{#tags}}<{{.}}>{{/tags}}").unwrap();
+/// assert_eq!(template.render_value_to_string(&row).unwrap(), "7: <a><b>");
+/// ```
+pub trait ContextValue {
+    /// Returns what the value is.
+    fn value(&self) -> Value<'_>;
+
+    /// Looks up `key` in a map. Values that aren't maps have no keys.
+    fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+        let _ = key;
+        None
+    }
+
+    /// Returns the entries of a map in order, or sorted by key for maps that
+    /// have no order. Only the CFEngine `@` and JSON tags iterate over maps.
+    fn entries(&self) -> Vec<(&str, &dyn ContextValue)> {
+        Vec::new()
+    }
+}
+
+impl ContextValue for Data {
+    fn value(&self) -> Value<'_> {
+        match *self {
+            Data::Null => Value::Null,
+            Data::String(ref s) => Value::String(Cow::Borrowed(s)),
+            Data::Bool(b) => Value::Bool(b),
+            Data::Int(n) => Value::Int(n),
+            Data::UInt(n) => Value::UInt(n),
+            Data::Float(n) => Value::Float(n),
+            Data::Vec(ref v) => Value::List(Box::new(v.iter().map(|d| d as &dyn ContextValue))),
+            Data::Map(_) => Value::Map,
+            Data::Fun(ref f) => Value::Lambda(&**f),
+            Data::Lambda(ref f) => Value::ContextLambda(&**f),
+            Data::Computed(ref c) => c.get().value(),
+            Data::Stream(ref s) => Value::Stream(Box::new(
+                s.iter().map(|d| Box::new(d) as Box<dyn ContextValue>),
+            )),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+        match *self {
+            Data::Map(ref m) => m.get(key).map(|d| d as &dyn ContextValue),
+            Data::Computed(ref c) => c.get().get(key),
+            _ => None,
+        }
+    }
+
+    fn entries(&self) -> Vec<(&str, &dyn ContextValue)> {
+        match *self {
+            Data::Map(ref m) => m
+                .iter()
+                .map(|(k, v)| (k.as_str(), v as &dyn ContextValue))
+                .collect(),
+            Data::Computed(ref c) => c.get().entries(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl ContextValue for Json {
+    fn value(&self) -> Value<'_> {
+        match *self {
+            Json::Null => Value::Null,
+            Json::Bool(b) => Value::Bool(b),
+            Json::Number(ref n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+                (Some(n), _, _) => Value::Int(n),
+                (None, Some(n), _) => Value::UInt(n),
+                (None, None, Some(f)) => Value::Float(f),
+                _ => Value::String(Cow::Owned(n.to_string())),
+            },
+            Json::String(ref s) => Value::String(Cow::Borrowed(s)),
+            Json::Array(ref v) => Value::List(Box::new(v.iter().map(|j| j as &dyn ContextValue))),
+            Json::Object(_) => Value::Map,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+        match *self {
+            Json::Object(ref m) => m.get(key).map(|j| j as &dyn ContextValue),
+            _ => None,
+        }
+    }
+
+    fn entries(&self) -> Vec<(&str, &dyn ContextValue)> {
+        match *self {
+            Json::Object(ref m) => m
+                .iter()
+                .map(|(k, v)| (k.as_str(), v as &dyn ContextValue))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<T: ContextValue + ?Sized> ContextValue for &T {
+    fn value(&self) -> Value<'_> {
+        (**self).value()
+    }
+
+    fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+        (**self).get(key)
+    }
+
+    fn entries(&self) -> Vec<(&str, &dyn ContextValue)> {
+        (**self).entries()
+    }
+}
+
+impl ContextValue for str {
+    fn value(&self) -> Value<'_> {
+        Value::String(Cow::Borrowed(self))
+    }
+}
+
+impl ContextValue for String {
+    fn value(&self) -> Value<'_> {
+        Value::String(Cow::Borrowed(self))
+    }
+}
+
+impl ContextValue for bool {
+    fn value(&self) -> Value<'_> {
+        Value::Bool(*self)
+    }
+}
+
+macro_rules! int_values {
+    ($($ty:ty)*) => {
+        $(
+            impl ContextValue for $ty {
+                fn value(&self) -> Value<'_> {
+                    match (i64::try_from(*self), u64::try_from(*self)) {
+                        (Ok(n), _) => Value::Int(n),
+                        (_, Ok(n)) => Value::UInt(n),
+                        _ => Value::String(Cow::Owned(self.to_string())),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+int_values!(i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize);
+
+impl ContextValue for f32 {
+    fn value(&self) -> Value<'_> {
+        Value::Float(f32_to_f64(*self))
+    }
+}
+
+impl ContextValue for f64 {
+    fn value(&self) -> Value<'_> {
+        Value::Float(*self)
+    }
+}
+
+impl<T: ContextValue> ContextValue for Option<T> {
+    fn value(&self) -> Value<'_> {
+        match *self {
+            Some(ref value) => value.value(),
+            None => Value::Null,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+        self.as_ref()?.get(key)
+    }
+
+    fn entries(&self) -> Vec<(&str, &dyn ContextValue)> {
+        self.as_ref().map_or_else(Vec::new, |value| value.entries())
+    }
+}
+
+impl<T: ContextValue> ContextValue for [T] {
+    fn value(&self) -> Value<'_> {
+        Value::List(Box::new(self.iter().map(|v| v as &dyn ContextValue)))
+    }
+}
+
+impl<T: ContextValue> ContextValue for Vec<T> {
+    fn value(&self) -> Value<'_> {
+        self.as_slice().value()
+    }
+}
+
+impl<T: ContextValue> ContextValue for HashMap<String, T> {
+    fn value(&self) -> Value<'_> {
+        Value::Map
+    }
+
+    fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+        HashMap::get(self, key).map(|v| v as &dyn ContextValue)
+    }
+
+    fn entries(&self) -> Vec<(&str, &dyn ContextValue)> {
+        let mut entries: Vec<(&str, &dyn ContextValue)> = self
+            .iter()
+            .map(|(k, v)| (k.as_str(), v as &dyn ContextValue))
+            .collect();
+        entries.sort_by_key(|&(k, _)| k);
+        entries
+    }
+}
+
+impl<T: ContextValue> ContextValue for BTreeMap<String, T> {
+    fn value(&self) -> Value<'_> {
+        Value::Map
+    }
+
+    fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+        BTreeMap::get(self, key).map(|v| v as &dyn ContextValue)
+    }
+
+    fn entries(&self) -> Vec<(&str, &dyn ContextValue)> {
+        self.iter()
+            .map(|(k, v)| (k.as_str(), v as &dyn ContextValue))
+            .collect()
+    }
+}
+
+impl<T: ContextValue> ContextValue for IndexMap<String, T> {
+    fn value(&self) -> Value<'_> {
+        Value::Map
+    }
+
+    fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+        IndexMap::get(self, key).map(|v| v as &dyn ContextValue)
+    }
+
+    fn entries(&self) -> Vec<(&str, &dyn ContextValue)> {
+        self.iter()
+            .map(|(k, v)| (k.as_str(), v as &dyn ContextValue))
+            .collect()
+    }
+}
+
+/// Serializes a `ContextValue`, for the CFEngine JSON tags.
+#[cfg(feature = "CFEngine")]
+pub(crate) struct Serialized<'a>(pub &'a dyn ContextValue);
+
+#[cfg(feature = "CFEngine")]
+impl Serialize for Serialized<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.value() {
+            Value::Null => serializer.serialize_none(),
+            Value::Bool(b) => serializer.serialize_bool(b),
+            Value::Int(n) => serializer.serialize_i64(n),
+            Value::UInt(n) => serializer.serialize_u64(n),
+            Value::Float(n) => serializer.serialize_f64(n),
+            Value::String(s) => serializer.serialize_str(&s),
+            Value::List(items) => {
+                let mut seq = serializer.serialize_seq(None)?;
+                for item in items {
+                    seq.serialize_element(&Serialized(item))?;
+                }
+                seq.end()
+            }
+            Value::Stream(items) => {
+                let mut seq = serializer.serialize_seq(None)?;
+                for item in items {
+                    seq.serialize_element(&Serialized(&*item))?;
+                }
+                seq.end()
+            }
+            Value::Map => {
+                let entries = self.0.entries();
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (k, v) in entries {
+                    map.serialize_entry(k, &Serialized(v))?;
+                }
+                map.end()
+            }
+            Value::Lambda(_) | Value::ContextLambda(_) => serializer.serialize_unit(),
+        }
+    }
+}
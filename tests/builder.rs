@@ -1,28 +1,28 @@
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use mustache::Data;
+use mustache::{Data, IndexMap};
 use mustache::{MapBuilder, VecBuilder};
 
 #[test]
 fn test_empty_builders() {
-    assert_eq!(MapBuilder::new().build(), Data::Map(HashMap::new()));
+    assert_eq!(MapBuilder::new().build(), Data::Map(IndexMap::new()));
 
     assert_eq!(VecBuilder::new().build(), Data::Vec(Vec::new()));
 }
 
 #[test]
 fn test_builders() {
-    let mut pride_and_prejudice = HashMap::new();
+    let mut pride_and_prejudice = IndexMap::new();
     pride_and_prejudice.insert(
         "title".to_string(),
         Data::String("Pride and Prejudice".to_string()),
     );
-    pride_and_prejudice.insert("publish_date".to_string(), Data::String("1813".to_string()));
+    pride_and_prejudice.insert("publish_date".to_string(), Data::Int(1813));
 
-    let mut m = HashMap::new();
+    let mut m = IndexMap::new();
     m.insert("first_name".to_string(), Data::String("Jane".to_string()));
     m.insert("last_name".to_string(), Data::String("Austen".to_string()));
-    m.insert("age".to_string(), Data::String("41".to_string()));
+    m.insert("age".to_string(), Data::Int(41));
     m.insert("died".to_string(), Data::Bool(true));
     m.insert(
         "works".to_string(),
@@ -59,17 +59,15 @@ fn test_map_fn_builder() {
     // We can't directly compare closures, so just make sure we thread
     // through the builder.
 
-    let mut count = 0usize;
+    let count = AtomicUsize::new(0);
     let data = MapBuilder::new()
         .insert_fn("count".to_string(), move |s| {
-            count += 1usize;
-            s.clone() + &count.to_string()
+            s + &(count.fetch_add(1, Ordering::Relaxed) + 1).to_string()
         })
         .build();
 
     assert_let!(Data::Map(m) = data => {
-        assert_let!(Some(&Data::Fun(ref f)) = m.get("count") => {
-            let f = &mut *f.borrow_mut();
+        assert_let!(Some(Data::Fun(f)) = m.get("count") => {
             assert_eq!((*f)("count: ".to_string()), "count: 1".to_string());
             assert_eq!((*f)("count: ".to_string()), "count: 2".to_string());
             assert_eq!((*f)("count: ".to_string()), "count: 3".to_string());
@@ -82,19 +80,15 @@ fn test_vec_fn_builder() {
     // We can't directly compare closures, so just make sure we thread
     // through the builder.
 
-    let mut count = 0usize;
+    let count = AtomicUsize::new(0);
     let data = VecBuilder::new()
-        .push_fn(move |s| {
-            count += 1usize;
-            s + &count.to_string()
-        })
+        .push_fn(move |s| s + &(count.fetch_add(1, Ordering::Relaxed) + 1).to_string())
         .build();
 
     assert_let!(Data::Vec(vs) = data => {
         let mut iter = vs.iter();
 
-        assert_let!(Some(&Data::Fun(ref f)) = iter.next() => {
-            let f = &mut *f.borrow_mut();
+        assert_let!(Some(Data::Fun(f)) = iter.next() => {
             assert_eq!((*f)("count: ".to_string()), "count: 1".to_string());
             assert_eq!((*f)("count: ".to_string()), "count: 2".to_string());
             assert_eq!((*f)("count: ".to_string()), "count: 3".to_string());
@@ -103,3 +97,12 @@ fn test_vec_fn_builder() {
         assert_eq!(iter.next(), None);
     })
 }
+
+#[test]
+fn test_fn_equality() {
+    let data = MapBuilder::new().insert_fn("f", |s| s).build();
+    assert_eq!(data.clone(), data);
+
+    let other = MapBuilder::new().insert_fn("f", |s| s).build();
+    assert_ne!(other, data);
+}
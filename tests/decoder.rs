@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use mustache::{Data, DecoderError, IndexMap, MapBuilder, from_data, to_data};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Status {
+    Draft,
+    Published(u32),
+    Moved { to: String },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Post {
+    title: String,
+    views: u64,
+    rating: f64,
+    offset: i8,
+    draft: bool,
+    tags: Vec<String>,
+    author: Option<String>,
+    status: Status,
+    scores: HashMap<u32, i32>,
+}
+
+#[test]
+fn test_round_trip() {
+    let post = Post {
+        title: "Hello".to_string(),
+        views: 1200,
+        rating: 4.5,
+        offset: -3,
+        draft: false,
+        tags: vec!["a".to_string(), "b".to_string()],
+        author: None,
+        status: Status::Moved {
+            to: "/elsewhere".to_string(),
+        },
+        scores: [(1, -1), (2, 10)].into_iter().collect(),
+    };
+
+    let data = to_data(&post).unwrap();
+    assert_eq!(from_data::<Post>(data).unwrap(), post);
+}
+
+#[test]
+fn test_from_builder() {
+    let data = MapBuilder::new()
+        .insert_str("title", "Built")
+        .insert_str("views", " 7 ")
+        .insert("rating", &1.25f64)
+        .expect("rating")
+        .insert_str("offset", "0")
+        .insert_bool("draft", true)
+        .insert_vec("tags", |builder| builder.push_str("x"))
+        .insert_str("author", "Jane")
+        .insert_str("status", "Draft")
+        .insert_map("scores", |builder| builder.insert_str("3", "4"))
+        .build();
+
+    assert_eq!(
+        from_data::<Post>(data).unwrap(),
+        Post {
+            title: "Built".to_string(),
+            views: 7,
+            rating: 1.25,
+            offset: 0,
+            draft: true,
+            tags: vec!["x".to_string()],
+            author: Some("Jane".to_string()),
+            status: Status::Draft,
+            scores: [(3, 4)].into_iter().collect(),
+        }
+    );
+}
+
+#[test]
+fn test_newtype_variant() {
+    let mut data = IndexMap::new();
+    data.insert("Published".to_string(), Data::String("3".to_string()));
+    assert_eq!(
+        from_data::<Status>(Data::Map(data)).unwrap(),
+        Status::Published(3)
+    );
+}
+
+#[test]
+fn test_invalid_number() {
+    let data = Data::String("lots".to_string());
+    let err = from_data::<u32>(data).unwrap_err();
+    assert!(matches!(err, DecoderError::Message(_)));
+    assert_eq!(
+        err.to_string(),
+        "invalid value: string \"lots\", expected u32"
+    );
+
+    let data = Data::String("300".to_string());
+    assert!(from_data::<u8>(data).is_err());
+}
+
+#[test]
+fn test_lambda() {
+    let data = MapBuilder::new().insert_fn("f", |s| s).build();
+    let err = from_data::<HashMap<String, String>>(data).unwrap_err();
+    assert!(matches!(err, DecoderError::UnsupportedType));
+}
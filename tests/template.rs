@@ -1,18 +1,18 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use tempdir::TempDir;
 
-use mustache::{self, Data, Error, to_data};
-use mustache::{Context, Template};
+use mustache::{self, Data, EncoderError, Error, IndexMap, to_data};
+use mustache::{Context, MapBuilder, Template};
 
 use serde::Serialize;
 use serde_derive::Serialize;
-use serde_json;
-use serde_json::Value as Json;
+use serde_json::{Value as Json, json};
 
 #[derive(Debug, Serialize)]
 struct Planet {
@@ -34,7 +34,7 @@ struct Person {
 }
 
 fn compile_str(s: &str) -> Template {
-    mustache::compile_str(s).expect(&format!("Failed to compile: {}", s))
+    mustache::compile_str(s).unwrap_or_else(|_| panic!("Failed to compile: {}", s))
 }
 
 fn assert_render<T>(template: &str, data: &T) -> String
@@ -63,6 +63,37 @@ where
     Ok(String::from_utf8(bytes).expect("Failed to encode String"))
 }
 
+// Writes `files`, given as paths and sources, to a new temporary directory.
+fn write_tree(files: &[(&str, &str)]) -> TempDir {
+    let tmpdir = TempDir::new("").expect("Failed to make tempdir");
+    for (path, src) in files {
+        let path = tmpdir.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).expect("Failed to make directory");
+        fs::write(path, src).expect("Failed to write template");
+    }
+    tmpdir
+}
+
+// An in-memory partial loader with the given names and sources.
+fn partials_map(partials: &[(&str, &str)]) -> HashMap<String, String> {
+    partials
+        .iter()
+        .map(|&(name, src)| (name.to_string(), src.to_string()))
+        .collect()
+}
+
+fn compile_with(ctx: Context, template: &str, partials: &[(&str, &str)]) -> Template {
+    ctx.with_loader(partials_map(partials))
+        .compile(template.chars())
+        .expect("Failed to compile")
+}
+
+fn render_with_partials(template: &str, partials: &[(&str, &str)], data: &Json) -> String {
+    compile_with(Context::new(PathBuf::from(".")), template, partials)
+        .render_to_string(data)
+        .expect("Failed to render")
+}
+
 #[test]
 fn test_render_texts() {
     let ctx = Planet {
@@ -354,7 +385,7 @@ fn test_render_to_string() {
 
 #[test]
 fn test_render_data_to_string() {
-    let ctx = HashMap::new();
+    let ctx = IndexMap::new();
     let template = compile_str("0{{#a}}1 {{n}} 3{{/a}}5");
 
     let result = template
@@ -363,8 +394,8 @@ fn test_render_data_to_string() {
 
     assert_eq!(&result, "05");
 
-    let mut ctx0 = HashMap::new();
-    let mut ctx1 = HashMap::new();
+    let mut ctx0 = IndexMap::new();
+    let mut ctx1 = IndexMap::new();
     ctx1.insert("n".to_string(), Data::String("a".to_string()));
     ctx0.insert("a".to_string(), Data::Vec(vec![Data::Map(ctx1)]));
 
@@ -375,24 +406,76 @@ fn test_render_data_to_string() {
     assert_eq!(&result, "01 a 35");
 }
 
+#[test]
+fn test_render_fmt() {
+    use std::fmt::Write as _;
+
+    let template = compile_str("{{#items}}<{{.}}>{{/items}}");
+    let data = json!({ "items": ["a", "b & c"] });
+
+    let mut output = "items: ".to_string();
+    template
+        .render_fmt(&mut output, &data)
+        .expect("Failed to render");
+    assert_eq!(output, "items: <a><b &amp; c>");
+
+    let data = to_data(&data).unwrap();
+    output.clear();
+    template
+        .render_data_fmt(&mut output, &data)
+        .expect("Failed to render");
+    write!(output, "!").unwrap();
+    assert_eq!(output, "<a><b &amp; c>!");
+}
+
+#[test]
+fn test_render_fmt_failure() {
+    struct Full;
+
+    impl std::fmt::Write for Full {
+        fn write_str(&mut self, _: &str) -> std::fmt::Result {
+            Err(std::fmt::Error)
+        }
+    }
+
+    let template = compile_str("{{name}}");
+    let result = template.render_fmt(&mut Full, &json!({ "name": "foobar" }));
+    assert!(matches!(result, Err(Error::Fmt(_))));
+}
+
+#[test]
+fn test_display() {
+    let template = compile_str("Hello, {{name}}!");
+    let data = json!({ "name": "world" });
+
+    let rendered = template.display(&data).expect("Failed to encode");
+    assert_eq!(
+        format!("[{rendered}] {rendered}"),
+        "[Hello, world!] Hello, world!"
+    );
+
+    let data = to_data(&data).unwrap();
+    assert_eq!(template.display_data(&data).to_string(), "Hello, world!");
+}
+
 #[test]
 fn test_render_sections() {
-    let ctx = HashMap::new();
+    let ctx = IndexMap::new();
     let template = compile_str("0{{#a}}1 {{n}} 3{{/a}}5");
 
     assert_eq!(render_data(&template, &Data::Map(ctx)), "05".to_string());
 
-    let mut ctx = HashMap::new();
+    let mut ctx = IndexMap::new();
     ctx.insert("a".to_string(), Data::Vec(Vec::new()));
 
     assert_eq!(render_data(&template, &Data::Map(ctx)), "05".to_string());
 
-    let mut ctx = HashMap::new();
+    let mut ctx = IndexMap::new();
     ctx.insert("a".to_string(), Data::Vec(Vec::new()));
     assert_eq!(render_data(&template, &Data::Map(ctx)), "05".to_string());
 
-    let mut ctx0 = HashMap::new();
-    let ctx1 = HashMap::new();
+    let mut ctx0 = IndexMap::new();
+    let ctx1 = IndexMap::new();
     ctx0.insert("a".to_string(), Data::Vec(vec![Data::Map(ctx1)]));
 
     assert_eq!(
@@ -400,8 +483,8 @@ fn test_render_sections() {
         "01  35".to_string()
     );
 
-    let mut ctx0 = HashMap::new();
-    let mut ctx1 = HashMap::new();
+    let mut ctx0 = IndexMap::new();
+    let mut ctx1 = IndexMap::new();
     ctx1.insert("n".to_string(), Data::String("a".to_string()));
     ctx0.insert("a".to_string(), Data::Vec(vec![Data::Map(ctx1)]));
     assert_eq!(
@@ -409,10 +492,10 @@ fn test_render_sections() {
         "01 a 35".to_string()
     );
 
-    let mut ctx = HashMap::new();
+    let mut ctx = IndexMap::new();
     ctx.insert(
         "a".to_string(),
-        Data::Fun(RefCell::new(Box::new(|_text| "foo".to_string()))),
+        Data::Fun(Arc::new(|_text| "foo".to_string())),
     );
     assert_eq!(render_data(&template, &Data::Map(ctx)), "0foo5".to_string());
 }
@@ -421,60 +504,194 @@ fn test_render_sections() {
 fn test_render_inverted_sections() {
     let template = compile_str("0{{^a}}1 3{{/a}}5");
 
-    let ctx = HashMap::new();
+    let ctx = IndexMap::new();
     assert_eq!(render_data(&template, &Data::Map(ctx)), "01 35".to_string());
 
-    let mut ctx = HashMap::new();
+    let mut ctx = IndexMap::new();
     ctx.insert("a".to_string(), Data::Vec(vec![]));
     assert_eq!(render_data(&template, &Data::Map(ctx)), "01 35".to_string());
 
-    let mut ctx0 = HashMap::new();
-    let ctx1 = HashMap::new();
+    let mut ctx0 = IndexMap::new();
+    let ctx1 = IndexMap::new();
     ctx0.insert("a".to_string(), Data::Vec(vec![Data::Map(ctx1)]));
     assert_eq!(render_data(&template, &Data::Map(ctx0)), "05".to_string());
 
-    let mut ctx0 = HashMap::new();
-    let mut ctx1 = HashMap::new();
+    let mut ctx0 = IndexMap::new();
+    let mut ctx1 = IndexMap::new();
     ctx1.insert("n".to_string(), Data::String("a".to_string()));
     ctx0.insert("a".to_string(), Data::Vec(vec![Data::Map(ctx1)]));
     assert_eq!(render_data(&template, &Data::Map(ctx0)), "05".to_string());
 }
 
+#[test]
+fn test_render_numbers() {
+    let template = compile_str("{{n}}:{{#n}}[{{.}}]{{/n}}{{^n}}none{{/n}}");
+
+    for (data, expected) in [
+        (json!({ "n": 0 }), "0:none"),
+        (json!({ "n": 0.0 }), "0:none"),
+        (json!({ "n": -2 }), "-2:[-2]"),
+        (json!({ "n": 1.0 }), "1:[1]"),
+        (json!({ "n": 1.210 }), "1.21:[1.21]"),
+        (
+            json!({ "n": u64::MAX }),
+            "18446744073709551615:[18446744073709551615]",
+        ),
+    ] {
+        assert_eq!(template.render_to_string(&data).unwrap(), expected);
+        assert_eq!(template.render_value_to_string(&data).unwrap(), expected);
+    }
+
+    let data = MapBuilder::new().insert("n", &f64::NAN).unwrap().build();
+    assert_eq!(render_data(&template, &data), "NaN:none");
+
+    let data = MapBuilder::new().insert("n", &0.1f32).unwrap().build();
+    assert_eq!(render_data(&template, &data), "0.1:[0.1]");
+}
+
+#[test]
+fn test_numbers_to_json() {
+    let data = json!({ "count": 3, "ratio": 0.5, "big": u64::MAX, "tags": [1, 2] });
+    let encoded = to_data(&data).unwrap();
+    assert_let!(Data::Map(ref map) = encoded => {
+        assert_eq!(map["big"], Data::UInt(u64::MAX));
+    });
+    assert_eq!(serde_json::to_value(&encoded).unwrap(), data);
+}
+
+#[test]
+fn test_scalar_map_keys() {
+    struct Pairs<K>(Vec<(K, &'static str)>);
+
+    impl<K: Serialize> Serialize for Pairs<K> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+        }
+    }
+
+    let keys =
+        |data: Data| assert_let!(Data::Map(map) = data => map.into_keys().collect::<Vec<_>>());
+    assert_eq!(
+        keys(to_data(Pairs(vec![(1.5, "a"), (2.0, "b")])).unwrap()),
+        ["1.5", "2"]
+    );
+    assert_eq!(
+        keys(to_data(Pairs(vec![(true, "a"), (false, "b")])).unwrap()),
+        ["true", "false"]
+    );
+    assert_eq!(keys(to_data(Pairs(vec![('x', "a")])).unwrap()), ["x"]);
+    assert_eq!(
+        keys(to_data(Pairs(vec![(u64::MAX, "a"), (0, "b")])).unwrap()),
+        [u64::MAX.to_string(), "0".to_string()]
+    );
+    assert_let!(Err(EncoderError::KeyIsNotString) = to_data(Pairs(vec![(vec![1], "a")])) => ());
+}
+
+#[test]
+fn test_map_order() {
+    #[derive(Serialize)]
+    struct Page {
+        zeta: u32,
+        alpha: u32,
+        mid: Json,
+    }
+
+    let page = Page {
+        zeta: 1,
+        alpha: 2,
+        mid: json!({ "y": 1, "x": 2 }),
+    };
+    let data = to_data(&page).unwrap();
+    assert_let!(Data::Map(ref map) = data => {
+        assert_eq!(map.keys().collect::<Vec<_>>(), ["zeta", "alpha", "mid"]);
+    });
+    // `serde_json::Value` maps have serde_json's order, which is sorted.
+    assert_eq!(
+        serde_json::to_string(&data).unwrap(),
+        r#"{"zeta":1,"alpha":2,"mid":{"x":2,"y":1}}"#
+    );
+
+    let data = MapBuilder::new()
+        .insert_str("b", "1")
+        .insert_map("a", |map| map.insert_str("d", "2").insert_str("c", "3"))
+        .build();
+    assert_eq!(
+        serde_json::to_string(&data).unwrap(),
+        r#"{"b":"1","a":{"d":"2","c":"3"}}"#
+    );
+}
+
+#[test]
+fn test_render_shared_data() {
+    fn assert_send_sync<T: Clone + Send + Sync>() {}
+    assert_send_sync::<Data>();
+    assert_send_sync::<Template>();
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let data = MapBuilder::new()
+        .insert_str("name", "world")
+        .insert_fn("shout", move |text| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            text.to_uppercase()
+        })
+        .build();
+    let template = compile_str("{{#shout}}hello {{/shout}}{{name}}");
+
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                assert_eq!(render_data(&template, &data), "HELLO world");
+            });
+        }
+    });
+    assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+    // Clones share their lambdas, and can be changed on their own.
+    let mut copy = data.clone();
+    assert_eq!(copy, data);
+    assert_let!(Data::Map(ref mut map) = copy => {
+        map.insert("name".to_string(), Data::String("there".to_string()));
+    });
+    assert_eq!(render_data(&template, &copy), "HELLO there");
+    assert_ne!(copy, data);
+}
+
 fn assert_partials_data(template: Template) {
-    let ctx = HashMap::new();
+    let ctx = IndexMap::new();
     assert_eq!(
         render_data(&template, &Data::Map(ctx)),
         "<h2>Names</h2>\n".to_string()
     );
 
-    let mut ctx = HashMap::new();
+    let mut ctx = IndexMap::new();
     ctx.insert("names".to_string(), Data::Vec(vec![]));
     assert_eq!(
         render_data(&template, &Data::Map(ctx)),
         "<h2>Names</h2>\n".to_string()
     );
 
-    let mut ctx0 = HashMap::new();
-    let ctx1 = HashMap::new();
+    let mut ctx0 = IndexMap::new();
+    let ctx1 = IndexMap::new();
     ctx0.insert("names".to_string(), Data::Vec(vec![Data::Map(ctx1)]));
     assert_eq!(
         render_data(&template, &Data::Map(ctx0)),
-        "<h2>Names</h2>\n  <strong></strong>\n\n".to_string()
+        "<h2>Names</h2>\n  <strong></strong>\n".to_string()
     );
 
-    let mut ctx0 = HashMap::new();
-    let mut ctx1 = HashMap::new();
+    let mut ctx0 = IndexMap::new();
+    let mut ctx1 = IndexMap::new();
     ctx1.insert("name".to_string(), Data::String("a".to_string()));
     ctx0.insert("names".to_string(), Data::Vec(vec![Data::Map(ctx1)]));
     assert_eq!(
         render_data(&template, &Data::Map(ctx0)),
-        "<h2>Names</h2>\n  <strong>a</strong>\n\n".to_string()
+        "<h2>Names</h2>\n  <strong>a</strong>\n".to_string()
     );
 
-    let mut ctx0 = HashMap::new();
-    let mut ctx1 = HashMap::new();
+    let mut ctx0 = IndexMap::new();
+    let mut ctx1 = IndexMap::new();
     ctx1.insert("name".to_string(), Data::String("a".to_string()));
-    let mut ctx2 = HashMap::new();
+    let mut ctx2 = IndexMap::new();
     ctx2.insert("name".to_string(), Data::String("<b>".to_string()));
     ctx0.insert(
         "names".to_string(),
@@ -482,7 +699,7 @@ fn assert_partials_data(template: Template) {
     );
     assert_eq!(
         render_data(&template, &Data::Map(ctx0)),
-        "<h2>Names</h2>\n  <strong>a</strong>\n\n  <strong>&lt;b&gt;</strong>\n\n".to_string()
+        "<h2>Names</h2>\n  <strong>a</strong>\n  <strong>&lt;b&gt;</strong>\n".to_string()
     );
 }
 
@@ -499,148 +716,2182 @@ fn test_render_partial() {
     assert_partials_data(template);
 }
 
-fn parse_spec_tests(src: &str) -> Vec<Json> {
-    let path = PathBuf::from(src);
-    let file = File::open(&path).expect(&format!("Could not read file {}", path.display()));
-    let json =
-        serde_json::from_reader(file).expect(&format!("Invalid json in file {}", path.display()));
+#[test]
+fn test_parse_error_reports_file_and_position() {
+    let tmpdir = write_tree(&[
+        ("page.mustache", "<h1>{{title}}</h1>\n{{> broken}}\n"),
+        (
+            "broken.mustache",
+            "<ul>\n  {{#items}}<li>{{.}}</li>\n</ul>\n",
+        ),
+    ]);
 
-    assert_let!(Json::Object(mut d) = json => {
-        assert_let!(Some(Json::Array(tests)) = d.remove("tests") => {
-            tests.into_iter().collect()
-        })
-    })
+    let ctx = Context::new(tmpdir.path().to_path_buf());
+    let err = ctx
+        .compile_path("page")
+        .expect_err("Compiled a broken partial");
+
+    let partial_path = tmpdir.path().join("broken.mustache");
+    assert_let!(Error::Template(ref name, ref inner) = err => {
+        assert_eq!(name, &partial_path.display().to_string());
+        assert_let!(Error::Parser(ref parser_err) = **inner => {
+            assert_eq!(parser_err.span().line, 2);
+            assert_eq!(parser_err.span().col, 3);
+        });
+    });
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "{}: found an unclosed section: \"items\" at line 2, column 3",
+            partial_path.display()
+        )
+    );
 }
 
-fn write_partials(tmpdir: &Path, value: &Json) {
-    assert_let!(Json::Object(ref d) = *value => {
-        for (key, value) in d {
-            assert_let!(Json::String(ref s) = *value => {
-                let path = tmpdir.join(&(key.clone() + ".mustache"));
-                File::create(&path)
-                    .and_then(|mut f| f.write_all(s.as_bytes()))
-                    .expect("Failed to generate partial");
-            })
-        }
-    })
+#[test]
+fn test_render_nested_standalone_closing_tags() {
+    let ctx = MapBuilder::new()
+        .insert_bool("a", true)
+        .insert_bool("b", true)
+        .build();
+    let template = compile_str("{{#a}}\n{{#b}}\nx\n{{/b}}\n{{/a}}\nend");
+    assert_eq!(render_data(&template, &ctx), "x\nend");
 }
 
-fn run_test(test: serde_json::Map<String, Json>, data: Data) {
-    let template = assert_let!(Some(&Json::String(ref s)) = test.get("template") => {
-        s.clone()
-    });
+mod dynamic_partials {
+    use super::*;
 
-    let expected = assert_let!(Some(&Json::String(ref s)) = test.get("expected") => {
-        s.clone()
-    });
+    #[test]
+    fn basic() {
+        let partials = [("content", "Hello, {{name}}!")];
+        let data = json!({ "dynamic": "content", "name": "world" });
+        assert_eq!(
+            render_with_partials("\"{{>*dynamic}}\"", &partials, &data),
+            "\"Hello, world!\""
+        );
+    }
 
-    // Make a temporary dir where we'll store our partials. This is to
-    // avoid a race on filenames.
-    let tmpdir = TempDir::new("").expect("Failed to make tempdir");
+    #[test]
+    fn dotted_names() {
+        let partials = [("content", "Hello")];
+        let data = json!({ "foo": { "bar": { "baz": "content" } } });
+        assert_eq!(
+            render_with_partials("{{> * foo.bar.baz }}", &partials, &data),
+            "Hello"
+        );
+    }
 
-    if let Some(value) = test.get("partials") {
-        write_partials(tmpdir.path(), value)
+    #[test]
+    fn failed_lookup() {
+        let partials = [("content", "Hello")];
+        assert_eq!(
+            render_with_partials("{{>*dynamic}}", &partials, &json!({})),
+            ""
+        );
+        assert_eq!(
+            render_with_partials(
+                "{{>*dynamic}}",
+                &partials,
+                &json!({ "dynamic": ["content"] })
+            ),
+            ""
+        );
+        assert_eq!(
+            render_with_partials("{{>*dynamic}}", &partials, &json!({ "dynamic": "missing" })),
+            ""
+        );
     }
 
-    let ctx = Context::new(tmpdir.path().to_path_buf());
-    let template = ctx
-        .compile(template.chars())
-        .expect(&format!("Failed to compile: {}", template));
-    let result = render_data(&template, &data);
+    #[test]
+    fn list_of_widgets() {
+        let partials = [("button", "[{{label}}]"), ("link", "<{{label}}>")];
+        let data = json!({
+            "widgets": [
+                { "type": "button", "label": "OK" },
+                { "type": "link", "label": "Home" },
+                { "type": "button", "label": "Cancel" },
+            ]
+        });
+        assert_eq!(
+            render_with_partials("{{#widgets}}{{>*type}}{{/widgets}}", &partials, &data),
+            "[OK]<Home>[Cancel]"
+        );
+    }
 
-    if result != expected {
-        println!("desc:     {:?}", test.get("desc"));
-        println!("context:  {:?}", test.get("data"));
-        println!("=>");
-        println!("template: {:?}", template);
-        println!("expected: {}", expected);
-        println!("actual:   {}", result);
-        println!("");
+    #[test]
+    fn precompiled_partial() {
+        let partials = [("content", "Hello")];
+        let data = json!({ "dynamic": "content" });
+        assert_eq!(
+            render_with_partials("{{>content}} {{>*dynamic}}", &partials, &data),
+            "Hello Hello"
+        );
+    }
+
+    #[test]
+    fn nested_partials() {
+        let partials = [
+            ("outer", "({{>inner}}{{>*next}})"),
+            ("inner", "inner"),
+            ("last", "last"),
+        ];
+        let data = json!({ "dynamic": "outer", "next": "last" });
+        assert_eq!(
+            render_with_partials("{{>*dynamic}}", &partials, &data),
+            "(innerlast)"
+        );
+    }
+
+    #[test]
+    fn standalone_indentation() {
+        let partials = [("content", "one\ntwo\n")];
+        let data = json!({ "dynamic": "content" });
+        assert_eq!(
+            render_with_partials("Hi,\n  {{>*dynamic}}\nBye\n", &partials, &data),
+            "Hi,\n  one\n  two\nBye\n"
+        );
     }
-    assert_eq!(result, expected);
 }
 
-fn run_tests(spec: &str) {
-    for json in parse_spec_tests(spec).into_iter() {
-        let test = assert_let!(Json::Object(m) = json => m);
+mod strict {
+    use super::*;
+    use mustache::Span;
+
+    fn render_strict(template: &str, data: &Json) -> Result<String, Error> {
+        let partials: HashMap<String, String> =
+            [("user".to_string(), "{{name}} <{{emial}}>".to_string())].into();
+        Context::new(PathBuf::from("."))
+            .with_loader(partials)
+            .with_strict(true)
+            .compile(template.chars())
+            .expect("Failed to compile")
+            .render_to_string(data)
+    }
 
-        let data = test.get("data").expect("No test data").clone();
-        let data = to_data(&data).expect("Failed to encode");
+    fn assert_unresolved(result: Result<String, Error>, name: &str, tag: &str, from: Option<&str>) {
+        match result {
+            Err(Error::Unresolved(ref n, ref t, _, ref f)) => {
+                assert_eq!(n, name);
+                assert_eq!(t, tag);
+                assert_eq!(f.as_deref(), from);
+            }
+            other => panic!("expected an unresolved name, found {other:?}"),
+        }
+    }
 
-        run_test(test, data);
+    #[test]
+    fn resolved_names_render() {
+        let data = json!({ "a": { "b": "c" }, "list": [], "none": null });
+        assert_eq!(
+            render_strict(
+                "{{a.b}}{{#list}}{{x}}{{/list}}{{^none}}!{{/none}}{{none}}",
+                &data
+            )
+            .unwrap(),
+            "c!"
+        );
     }
-}
 
-#[test]
-fn test_spec_comments() {
-    run_tests("spec/specs/comments.json");
-}
+    #[test]
+    fn missing_names() {
+        let data = json!({ "a": { "b": "c" } });
+        assert_unresolved(render_strict("{{a.c}}", &data), "a.c", "{{a.c}}", None);
+        assert_unresolved(render_strict("{{{ x }}}", &data), "x", "{{{ x }}}", None);
+        assert_unresolved(render_strict("{{#x}}{{/x}}", &data), "x", "{{#x}}", None);
+        assert_unresolved(render_strict("{{^x}}{{/x}}", &data), "x", "{{^x}}", None);
+    }
 
-#[test]
-fn test_spec_delimiters() {
-    run_tests("spec/specs/delimiters.json");
-}
+    #[test]
+    fn missing_partials() {
+        let data = json!({ "kind": "widget" });
+        assert_unresolved(
+            render_strict("{{>missing}}", &data),
+            "missing",
+            "{{>missing}}",
+            None,
+        );
+        assert_unresolved(
+            render_strict("{{>*kind}}", &data),
+            "widget",
+            "{{>*kind}}",
+            None,
+        );
+        assert_unresolved(
+            render_strict("{{>*type}}", &data),
+            "type",
+            "{{>*type}}",
+            None,
+        );
+    }
 
-#[test]
-fn test_spec_interpolation() {
-    run_tests("spec/specs/interpolation.json");
-}
+    #[test]
+    fn reports_partial() {
+        let result = render_strict("{{>user}}", &json!({ "name": "Jane" }));
+        assert_unresolved(result, "emial", "{{emial}}", Some("user"));
+
+        let err = render_strict("\n  {{>user}}", &json!({ "name": "Jane" })).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "user: unresolved name \"emial\" in {{emial}} at line 1, column 11"
+        );
+    }
 
-#[test]
-fn test_spec_inverted() {
-    run_tests("spec/specs/inverted.json");
-}
+    #[test]
+    fn reports_template_path() {
+        let tmpdir = write_tree(&[("page.mustache", "Hello\n{{ nmae }}")]);
+        let path = tmpdir.path().join("page.mustache");
+
+        let result = Context::new(tmpdir.path().to_path_buf())
+            .with_strict(true)
+            .compile_path("page")
+            .expect("Failed to compile")
+            .render_to_string(&json!({ "name": "Jane" }));
+
+        let path = path.display().to_string();
+        assert_unresolved(result, "nmae", "{{ nmae }}", Some(&path));
+    }
 
-#[test]
-fn test_spec_partials() {
-    run_tests("spec/specs/partials.json");
-}
+    #[test]
+    fn opt_in_per_template() {
+        let template = compile_str("[{{missing}}]");
+        assert_eq!(template.render_to_string(&json!({})).unwrap(), "[]");
 
-#[test]
-fn test_spec_sections() {
-    run_tests("spec/specs/sections.json");
+        let result = template.with_strict(true).render_to_string(&json!({}));
+        let Err(Error::Unresolved(_, _, span, _)) = result else {
+            panic!("expected an unresolved name, found {result:?}");
+        };
+        assert_eq!(
+            span,
+            Span {
+                start: 1,
+                end: 12,
+                line: 1,
+                col: 2
+            }
+        );
+    }
 }
 
-#[test]
-fn test_spec_lambdas() {
-    for json in parse_spec_tests("spec/specs/~lambdas.json").into_iter() {
-        let mut test = assert_let!(Json::Object(m) = json => m);
+mod escapers {
+    use super::*;
+    use mustache::Escaper;
+    use mustache::escape::{HtmlEscaper, JsonEscaper, NoEscaper, ShellEscaper};
+    use std::borrow::Cow;
 
-        let s = assert_let!(Some(Json::String(s)) = test.remove("name") => s);
+    #[test]
+    fn context_escaper() {
+        let data = json!({ "value": "<a & b>" });
+        let template = Context::new(PathBuf::from("."))
+            .with_escaper(NoEscaper)
+            .compile("{{value}}".chars())
+            .expect("Failed to compile");
+        assert_eq!(template.render_to_string(&data).unwrap(), "<a & b>");
+
+        let template = compile_str("{{value}} {{{value}}} {{&value}}");
+        assert_eq!(
+            template.render_to_string(&data).unwrap(),
+            "&lt;a &amp; b&gt; <a & b> <a & b>"
+        );
+    }
 
-        // Replace the lambda with rust code.
-        let data = test.remove("data").expect("No test data");
+    #[test]
+    fn per_render_escaper() {
+        let template = compile_str(r#"{"msg": "{{msg}}"}"#);
+        let data = json!({ "msg": "say \"hi\"\n" });
 
-        let data = to_data(&data).expect("Failed to encode");
+        let mut json = vec![];
+        template
+            .render_with_escaper(&mut json, &data, &JsonEscaper)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            r#"{"msg": "say \"hi\"\n"}"#
+        );
 
-        let mut ctx = assert_let!(Data::Map(ctx) = data => ctx);
+        let mut html = vec![];
+        template
+            .render_data_with_escaper(&mut html, &to_data(&data).unwrap(), &HtmlEscaper)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(html).unwrap(),
+            "{\"msg\": \"say &quot;hi&quot;\n\"}"
+        );
+    }
 
-        // needed for the closure test.
-        let mut calls = 0usize;
+    #[test]
+    fn custom_escaper() {
+        struct Upper;
 
-        match &*s {
-            "Interpolation" => {
-                let f = |_text| "world".to_string();
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
-            }
-            "Interpolation - Expansion" => {
-                let f = |_text| "{{planet}}".to_string();
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
+        impl Escaper for Upper {
+            fn escape<'a>(&self, value: &'a str) -> Cow<'a, str> {
+                Cow::Owned(value.to_uppercase())
             }
-            "Interpolation - Alternate Delimiters" => {
-                let f = |_text| "|planet| => {{planet}}".to_string();
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
+        }
+
+        let template = Context::new(PathBuf::from("."))
+            .with_escaper(Upper)
+            .compile("{{a}} {{{a}}}".chars())
+            .unwrap();
+        assert_eq!(
+            template.render_to_string(&json!({ "a": "hi" })).unwrap(),
+            "HI hi"
+        );
+    }
+
+    #[test]
+    fn auto_escape_by_extension() {
+        let tmpdir = write_tree(&[
+            ("page.html.mustache", "<p>{{v}}</p>"),
+            ("notes.txt.mustache", "{{v}}"),
+            ("plain.mustache", "{{v}}"),
+        ]);
+
+        let ctx = Context::new(tmpdir.path().to_path_buf())
+            .with_escaper(ShellEscaper)
+            .with_auto_escape(true);
+        let data = json!({ "v": "Tom & Jerry" });
+        let render = |name: &str| {
+            ctx.compile_path(name)
+                .expect("Failed to compile")
+                .render_to_string(&data)
+                .unwrap()
+        };
+
+        assert_eq!(render("page.html.mustache"), "<p>Tom &amp; Jerry</p>");
+        assert_eq!(render("notes.txt.mustache"), "Tom & Jerry");
+        // Unknown extensions keep the context's escaper.
+        assert_eq!(render("plain"), "'Tom & Jerry'");
+    }
+
+    #[test]
+    fn indented_partial() {
+        let partials: HashMap<String, String> =
+            [("cmd".to_string(), "{{arg}}\n".to_string())].into();
+        let template = Context::new(PathBuf::from("."))
+            .with_loader(partials)
+            .with_escaper(ShellEscaper)
+            .compile("run \\\n  {{>cmd}}".chars())
+            .unwrap();
+        assert_eq!(
+            template.render_to_string(&json!({ "arg": "a b" })).unwrap(),
+            "run \\\n  'a b'\n"
+        );
+    }
+}
+
+mod references {
+    use super::*;
+    use mustache::{ReferenceKind, Span};
+
+    #[test]
+    fn names_and_contexts() {
+        let template =
+            compile_str("{{title}}\n{{#items}}{{{name}}}{{^tags}}{{.}}{{/tags}}{{/items}}");
+        let refs = template.references();
+
+        let names: Vec<_> = refs
+            .names
+            .iter()
+            .map(|r| (r.dotted(), r.kind, r.context.len()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("title".to_string(), ReferenceKind::Variable, 0),
+                ("items".to_string(), ReferenceKind::Section, 0),
+                ("name".to_string(), ReferenceKind::UnescapedVariable, 1),
+                ("tags".to_string(), ReferenceKind::InvertedSection, 1),
+                (".".to_string(), ReferenceKind::Variable, 2),
+            ]
+        );
+        assert_eq!(
+            refs.names[4].context,
+            vec![vec!["items".to_string()], vec!["tags".to_string()]]
+        );
+        assert_eq!(
+            refs.names[2].span,
+            Span {
+                start: 20,
+                end: 30,
+                line: 2,
+                col: 11
             }
-            "Interpolation - Multiple Calls" => {
-                let f = move |_text: String| {
-                    calls += 1;
-                    calls.to_string()
-                };
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
+        );
+        assert!(refs.partials.is_empty());
+    }
+
+    #[cfg(not(feature = "CFEngine"))]
+    #[test]
+    fn transitive_partials() {
+        let partials: HashMap<String, String> = [
+            ("card", "{{name}}{{>avatar}}{{>card}}"),
+            ("avatar", "{{url}}"),
+            ("layout", "{{$body}}{{/body}}{{footer}}"),
+        ]
+        .iter()
+        .map(|&(name, src)| (name.to_string(), src.to_string()))
+        .collect();
+        let template = Context::new(PathBuf::from("."))
+            .with_loader(partials)
+            .compile(
+                "{{#user}}{{>card}}{{/user}}{{>*widget}}{{<layout}}{{$body}}{{a.b}}{{/body}}{{/layout}}"
+                    .chars(),
+            )
+            .expect("Failed to compile");
+        let refs = template.references();
+
+        assert_eq!(
+            refs.partials.iter().map(String::as_str).collect::<Vec<_>>(),
+            vec!["avatar", "card", "layout"]
+        );
+
+        let names: Vec<_> = refs
+            .names
+            .iter()
+            .map(|r| (r.dotted(), r.partial.as_deref()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("user".to_string(), None),
+                ("widget".to_string(), None),
+                ("a.b".to_string(), None),
+                ("name".to_string(), Some("card")),
+                ("footer".to_string(), Some("layout")),
+                ("url".to_string(), Some("avatar")),
+            ]
+        );
+        assert_eq!(refs.names[1].kind, ReferenceKind::DynamicPartial);
+    }
+}
+
+mod ast {
+    use super::*;
+    use mustache::Span;
+    use mustache::ast::{self, Fold, Node, Section, Variable, Visitor};
+
+    fn span(start: usize, end: usize) -> Span {
+        Span {
+            start,
+            end,
+            line: 1,
+            col: start + 1,
+        }
+    }
+
+    #[test]
+    fn nodes() {
+        let template = compile_str("Hi {{#user}}{{{name}}}{{/user}}");
+        let ast = template.ast();
+
+        assert_eq!(ast.nodes.len(), 2);
+        assert_eq!(ast.nodes[0].span(), span(0, 3));
+
+        let Node::Section(ref section) = ast.nodes[1] else {
+            panic!("expected a section, found {:?}", ast.nodes[1]);
+        };
+        assert_eq!(section.path, vec!["user".to_string()]);
+        assert!(!section.inverted);
+        assert_eq!(section.open_tag, "{{#user}}");
+        assert_eq!(section.close_tag, "{{/user}}");
+        assert_eq!(section.source, "{{{name}}}");
+        assert_eq!(section.delimiters, ("{{".to_string(), "}}".to_string()));
+        assert_eq!(section.span, span(3, 31));
+        assert_eq!(
+            section.children,
+            vec![Node::Variable(Variable {
+                path: vec!["name".to_string()],
+                escaped: false,
+                tag: "{{{name}}}".to_string(),
+                span: span(12, 22),
+            })]
+        );
+    }
+
+    #[test]
+    fn visitor() {
+        #[derive(Default)]
+        struct Sections {
+            depth: usize,
+            seen: Vec<(String, usize)>,
+        }
+
+        impl Visitor for Sections {
+            fn visit_section(&mut self, section: &Section) {
+                self.seen.push((section.path.join("."), self.depth));
+                self.depth += 1;
+                ast::walk_nodes(self, &section.children);
+                self.depth -= 1;
+            }
+        }
+
+        let template = compile_str("{{#a}}{{^b.c}}{{/b.c}}{{/a}}{{#d}}{{/d}}");
+        let mut sections = Sections::default();
+        template.ast().visit(&mut sections);
+        assert_eq!(
+            sections.seen,
+            vec![
+                ("a".to_string(), 0),
+                ("b.c".to_string(), 1),
+                ("d".to_string(), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn fold() {
+        // Renames `user` to `account`, in the template and its partials.
+        struct Rename;
+
+        impl Fold for Rename {
+            fn fold_variable(&mut self, mut variable: Variable) -> Node {
+                if variable.path.first().map(String::as_str) == Some("user") {
+                    variable.path[0] = "account".to_string();
+                }
+                Node::Variable(variable)
+            }
+        }
+
+        let partials: HashMap<String, String> =
+            [("footer".to_string(), "{{user.name}}".to_string())].into();
+        let template = Context::new(PathBuf::from("."))
+            .with_loader(partials)
+            .compile("{{#list}}{{user.id}}{{/list}}{{>footer}}".chars())
+            .expect("Failed to compile");
+
+        let ast = template.ast().fold(&mut Rename);
+        let Node::Section(ref section) = ast.nodes[0] else {
+            panic!("expected a section, found {:?}", ast.nodes[0]);
+        };
+        let Node::Variable(ref id) = section.children[0] else {
+            panic!("expected a variable, found {:?}", section.children[0]);
+        };
+        assert_eq!(id.path, vec!["account".to_string(), "id".to_string()]);
+
+        let Node::Variable(ref name) = ast.partials["footer"][0] else {
+            panic!("expected a variable, found {:?}", ast.partials["footer"][0]);
+        };
+        assert_eq!(name.path, vec!["account".to_string(), "name".to_string()]);
+    }
+}
+
+mod partial_loaders {
+    use super::*;
+    use mustache::{
+        ChainLoader, FileSystemLoader, PartialLoader, PartialSource, check_partial_name,
+    };
+
+    fn render<L: PartialLoader + 'static>(template: &str, loader: L, data: &Json) -> String {
+        Context::new(PathBuf::from("."))
+            .with_loader(loader)
+            .compile(template.chars())
+            .expect("Failed to compile")
+            .render_to_string(data)
+            .expect("Failed to render")
+    }
+
+    #[test]
+    fn in_memory() {
+        let loader = partials_map(&[("user", "<b>{{name}}</b>"), ("name", "{{first}}")]);
+        let data = json!({ "name": "Jane", "first": "Jane" });
+        assert_eq!(
+            render("{{>user}} {{>name}}", loader, &data),
+            "<b>Jane</b> Jane"
+        );
+        assert_eq!(render("[{{>missing}}]", partials_map(&[]), &data), "[]");
+    }
+
+    #[test]
+    fn dynamic_partials() {
+        let loader = partials_map(&[("button", "[{{label}}]")]);
+        let data = json!({ "kind": "button", "label": "OK" });
+        assert_eq!(render("{{>*kind}}", loader, &data), "[OK]");
+    }
+
+    #[test]
+    fn dynamic_partials_are_loaded_once() {
+        struct Counting(Arc<AtomicUsize>);
+
+        impl PartialLoader for Counting {
+            fn load(&self, name: &str) -> mustache::Result<Option<PartialSource>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Ok((name != "missing").then(|| PartialSource {
+                    origin: name.to_string(),
+                    source: "[{{label}}]".to_string(),
+                    root: None,
+                }))
+            }
+        }
+
+        let loads = Arc::new(AtomicUsize::new(0));
+        let template = Context::new(PathBuf::from("."))
+            .with_loader(Counting(loads.clone()))
+            .compile("{{#items}}{{>*kind}}{{/items}}".chars())
+            .expect("Failed to compile");
+        let data = json!({ "items": [
+            { "kind": "button", "label": "OK" },
+            { "kind": "button", "label": "Cancel" },
+            { "kind": "missing" },
+        ] });
+        for _ in 0..3 {
+            assert_eq!(template.render_to_string(&data).unwrap(), "[OK][Cancel]");
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+        // Clones share what was loaded.
+        let clone = template.clone();
+        assert_eq!(clone.render_to_string(&data).unwrap(), "[OK][Cancel]");
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn chain_falls_back_in_order() {
+        let tmpdir = write_tree(&[
+            ("header.mustache", "base header"),
+            ("footer.mustache", "base footer"),
+        ]);
+
+        let loader = ChainLoader::new()
+            .push(partials_map(&[("header", "theme header")]))
+            .push(FileSystemLoader::new(tmpdir.path().to_path_buf()));
+        assert_eq!(
+            render("{{>header}}, {{>footer}}", loader, &json!({})),
+            "theme header, base footer"
+        );
+    }
+
+    #[test]
+    fn partial_names() {
+        for name in ["user", "components/button", "a/b/c", ".hidden", "v1.2"] {
+            assert!(check_partial_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "",
+            "/etc/passwd",
+            "../secret",
+            "a/../../b",
+            "a//b",
+            "a/",
+            "./a",
+            "a\\b",
+            "C:x",
+        ] {
+            assert_let!(Err(Error::InvalidPartialName(invalid)) = check_partial_name(name) => {
+                assert_eq!(invalid, name);
+            });
+        }
+    }
+
+    #[test]
+    fn subdirectories() {
+        let tmpdir = write_tree(&[("components/button.mustache", "<button>{{label}}</button>")]);
+
+        let template = Context::new(tmpdir.path().to_path_buf())
+            .compile("{{>components/button}}".chars())
+            .expect("Failed to compile");
+        assert_eq!(
+            template
+                .render_to_string(&json!({ "label": "OK" }))
+                .unwrap(),
+            "<button>OK</button>"
+        );
+    }
+
+    #[test]
+    fn rejects_names_escaping_the_root() {
+        let tmpdir = write_tree(&[
+            ("secret.mustache", "secret"),
+            ("templates/page.mustache", ""),
+        ]);
+        let root = tmpdir.path().join("templates");
+
+        let ctx = Context::new(root);
+        assert_let!(Err(Error::InvalidPartialName(name)) = ctx.compile("{{> ../secret}}".chars()) => {
+            assert_eq!(name, "../secret");
+        });
+
+        // Names looked up in the data are checked too.
+        let template = ctx
+            .compile("{{>*page}}".chars())
+            .expect("Failed to compile");
+        assert_let!(Err(Error::InvalidPartialName(_)) = template.render_to_string(&json!({ "page": "../secret" })) => ());
+
+        // Even when the loader wouldn't read files.
+        let loader = partials_map(&[("../secret", "secret")]);
+        assert_let!(Err(Error::InvalidPartialName(_)) = Context::new(PathBuf::from(".")).with_loader(loader).compile("{{>../secret}}".chars()) => ());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_escaping_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let tmpdir = write_tree(&[
+            ("secret.mustache", "secret"),
+            ("templates/shared/button.mustache", "button"),
+        ]);
+        let root = tmpdir.path().join("templates");
+        symlink(
+            tmpdir.path().join("secret.mustache"),
+            root.join("leak.mustache"),
+        )
+        .unwrap();
+        symlink(root.join("shared"), root.join("components")).unwrap();
+
+        let ctx = Context::new(root);
+        assert_let!(Err(Error::InvalidPartialName(name)) = ctx.compile("{{>leak}}".chars()) => {
+            assert_eq!(name, "leak");
+        });
+
+        // Symlinks that stay inside the root are followed.
+        let template = ctx
+            .compile("{{>components/button}}".chars())
+            .expect("Failed to compile");
+        assert_eq!(template.render_to_string(&json!({})).unwrap(), "button");
+    }
+
+    #[test]
+    fn errors_report_origin() {
+        struct Bundle;
+
+        impl PartialLoader for Bundle {
+            fn load(&self, name: &str) -> mustache::Result<Option<PartialSource>> {
+                Ok(Some(PartialSource {
+                    origin: format!("bundle:{name}"),
+                    source: "{{#unclosed}}".to_string(),
+                    root: None,
+                }))
+            }
+        }
+
+        let err = Context::new(PathBuf::from("."))
+            .with_loader(Bundle)
+            .compile("{{>broken}}".chars())
+            .unwrap_err();
+        let Error::Template(ref origin, _) = err else {
+            panic!("expected a template error, found {err:?}");
+        };
+        assert_eq!(origin, "bundle:broken");
+    }
+}
+
+#[cfg(not(feature = "CFEngine"))]
+mod inheritance {
+    use super::*;
+
+    #[test]
+    fn default_block() {
+        assert_eq!(
+            render_with_partials("{{$title}}Default title{{/title}}\n", &[], &json!({})),
+            "Default title\n"
+        );
+    }
+
+    #[test]
+    fn overridden_block() {
+        let partials = [("super", "...{{$title}}Default title{{/title}}...")];
+        assert_eq!(
+            render_with_partials(
+                "{{<super}}{{$title}}sub template title{{/title}}{{/super}}",
+                &partials,
+                &json!({}),
+            ),
+            "...sub template title..."
+        );
+    }
+
+    #[test]
+    fn data_does_not_override_block() {
+        let partials = [("include", "{{$var}}var in include{{/var}}")];
+        let data = json!({ "var": "var in data" });
+        assert_eq!(
+            render_with_partials(
+                "{{<include}}{{$var}}var in template{{/var}}{{/include}}",
+                &partials,
+                &data,
+            ),
+            "var in template"
+        );
+        assert_eq!(
+            render_with_partials("{{<include}}{{/include}}", &partials, &data),
+            "var in include"
+        );
+    }
+
+    #[test]
+    fn blocks_render_with_data() {
+        let partials = [("parent", "<{{$body}}{{/body}}>")];
+        assert_eq!(
+            render_with_partials(
+                "{{<parent}}{{$body}}{{#items}}{{.}}{{/items}}{{/body}}{{/parent}}",
+                &partials,
+                &json!({ "items": ["a", "b"] }),
+            ),
+            "<ab>"
+        );
+    }
+
+    #[test]
+    fn two_overridden_parents() {
+        let partials = [(
+            "parent",
+            "|{{$stuff}}...{{/stuff}}{{$default}} default{{/default}}|",
+        )];
+        assert_eq!(
+            render_with_partials(
+                "test {{<parent}}{{$stuff}}override1{{/stuff}}{{/parent}} \
+                 {{<parent}}{{$stuff}}override2{{/stuff}}{{/parent}}\n",
+                &partials,
+                &json!({}),
+            ),
+            "test |override1 default| |override2 default|\n"
+        );
+    }
+
+    #[test]
+    fn multi_level_inheritance() {
+        let partials = [
+            ("parent", "{{<older}}{{$a}}p{{/a}}{{/older}}"),
+            ("older", "{{<grandParent}}{{$a}}o{{/a}}{{/grandParent}}"),
+            ("grandParent", "{{$a}}g{{/a}}"),
+        ];
+        assert_eq!(
+            render_with_partials("{{<parent}}{{/parent}}", &partials, &json!({})),
+            "p"
+        );
+        assert_eq!(
+            render_with_partials("{{<parent}}{{$a}}c{{/a}}{{/parent}}", &partials, &json!({})),
+            "c"
+        );
+    }
+
+    #[test]
+    fn standalone_parent() {
+        let partials = [("parent", "one\ntwo\n")];
+        assert_eq!(
+            render_with_partials("Hi,\n  {{<parent}}{{/parent}}\n", &partials, &json!({})),
+            "Hi,\n  one\n  two\n"
+        );
+    }
+
+    #[test]
+    fn standalone_block() {
+        let partials = [("parent", "Hi,\n  {{$block}}{{/block}}\n")];
+        assert_eq!(
+            render_with_partials(
+                "{{<parent}}{{$block}}\none\ntwo{{/block}}\n{{/parent}}\n",
+                &partials,
+                &json!({}),
+            ),
+            "Hi,\n  one\n  two\n"
+        );
+    }
+
+    #[test]
+    fn block_reindentation() {
+        let partials = [("parent", "Hi,\n  {{$block}}\n  {{/block}}\n")];
+        assert_eq!(
+            render_with_partials(
+                "{{<parent}}{{$block}}\n    one\n    two\n{{/block}}\n{{/parent}}\n",
+                &partials,
+                &json!({}),
+            ),
+            "Hi,\n  one\n  two\n"
+        );
+    }
+
+    #[test]
+    fn intrinsic_indentation() {
+        let partials = [("parent", "Hi,\n  {{$block}}\n    default\n  {{/block}}\n")];
+        assert_eq!(
+            render_with_partials("{{<parent}}{{/parent}}", &partials, &json!({})),
+            "Hi,\n    default\n"
+        );
+        assert_eq!(
+            render_with_partials(
+                "{{<parent}}{{$block}}\none\ntwo\n{{/block}}\n{{/parent}}\n",
+                &partials,
+                &json!({}),
+            ),
+            "Hi,\n    one\n    two\n"
+        );
+    }
+
+    #[test]
+    fn inline_override_is_indented() {
+        let partials = [("parent", "stop:\n  {{$nested}}\n  three\n  {{/nested}}\n")];
+        assert_eq!(
+            render_with_partials(
+                "{{<parent}}{{$nested}}one\ntwo\n{{/nested}}{{/parent}}",
+                &partials,
+                &json!({}),
+            ),
+            "stop:\n  one\n  two\n"
+        );
+    }
+
+    #[test]
+    fn nested_block_reindentation() {
+        let partials = [
+            (
+                "parent",
+                "{{<grandparent}}{{$block}}\none\n  {{$nested}}\n  two\n  {{/nested}}\n{{/block}}{{/grandparent}}",
+            ),
+            ("grandparent", "  {{$block}}\n  default\n  {{/block}}\n"),
+        ];
+        assert_eq!(
+            render_with_partials(
+                "{{<parent}}{{$nested}}\nthree\n{{/nested}}{{/parent}}",
+                &partials,
+                &json!({}),
+            ),
+            "  one\n    three\n"
+        );
+    }
+
+    #[test]
+    fn layout() {
+        let partials = [(
+            "layout",
+            "<html>\n<title>{{$title}}Default{{/title}}</title>\n<body>\n  {{$body}}\n  {{/body}}\n</body>\n</html>\n",
+        )];
+        let template = "\
+{{<layout}}
+  {{$title}}{{name}}{{/title}}
+  {{$body}}
+    <h1>Hello {{name}}</h1>
+    <p>{{#name}}
+      {{.}}
+    {{/name}}</p>
+  {{/body}}
+{{/layout}}
+";
+        assert_eq!(
+            render_with_partials(template, &partials, &json!({ "name": "World" })),
+            "<html>\n<title>World</title>\n<body>\n  <h1>Hello World</h1>\n  <p>\n    World\n  </p>\n</body>\n</html>\n"
+        );
+    }
+}
+
+mod search_paths {
+    use super::*;
+
+    #[test]
+    fn partials_fall_back_in_order() {
+        let theme = write_tree(&[("header.mustache", "theme header")]);
+        let base = write_tree(&[
+            ("header.mustache", "base header"),
+            ("footer.mustache", "base footer"),
+            ("page.mustache", "{{>header}}, {{>footer}}"),
+        ]);
+        let ctx =
+            Context::new(theme.path().to_path_buf()).with_search_path(base.path().to_path_buf());
+
+        let template = ctx.compile_path("page").expect("Failed to compile");
+        assert_eq!(
+            template.render_to_string(&json!({})).unwrap(),
+            "theme header, base footer"
+        );
+
+        let roots: Vec<_> = ctx.search_roots().collect();
+        assert_eq!(roots, [theme.path(), base.path()]);
+    }
+
+    #[test]
+    fn reports_the_root() {
+        let theme = write_tree(&[("header.mustache", "")]);
+        let base = write_tree(&[("header.mustache", ""), ("footer.mustache", "")]);
+        let ctx =
+            Context::new(theme.path().to_path_buf()).with_search_path(base.path().to_path_buf());
+
+        let root = |name: &str| ctx.load_partial(name).unwrap().and_then(|p| p.root);
+        assert_eq!(root("header").as_deref(), Some(theme.path()));
+        assert_eq!(root("footer").as_deref(), Some(base.path()));
+        assert_eq!(root("missing"), None);
+    }
+
+    #[test]
+    fn missing_template() {
+        let theme = write_tree(&[]);
+        let base = write_tree(&[]);
+        let err = Context::new(theme.path().to_path_buf())
+            .with_search_path(base.path().to_path_buf())
+            .compile_path("page")
+            .unwrap_err();
+        assert_let!(Error::Io(err) = err => {
+            assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        });
+    }
+}
+
+mod limits {
+    use super::*;
+    use mustache::Limits;
+
+    fn ctx(limits: Limits) -> Context {
+        Context::new(PathBuf::from(".")).with_limits(limits)
+    }
+
+    #[test]
+    fn depth() {
+        let limits = Limits {
+            max_depth: Some(3),
+            ..Limits::default()
+        };
+
+        // Partials including themselves forever.
+        let template = compile_with(ctx(limits), "{{>loop}}", &[("loop", "x{{>loop}}")]);
+        assert_let!(Err(Error::TooDeep(3)) = template.render_to_string(&json!({})) => ());
+
+        let template = compile_with(
+            ctx(limits),
+            "{{#a}}{{#b}}{{#c}}{{d}}{{/c}}{{/b}}{{/a}}",
+            &[],
+        );
+        let data = json!({ "a": true, "b": true, "c": true, "d": "deep" });
+        assert_eq!(template.render_to_string(&data).unwrap(), "deep");
+
+        let template = compile_with(
+            ctx(limits),
+            "{{#a}}{{#b}}{{#c}}{{>d}}{{/c}}{{/b}}{{/a}}",
+            &[("d", "{{#a}}{{/a}}")],
+        );
+        assert_let!(Err(Error::TooDeep(3)) = template.render_to_string(&data) => ());
+
+        // Sections nested too deeply in the source fail to compile, before
+        // they cost anything.
+        let source = "{{#a}}".repeat(20_000) + &"{{/a}}".repeat(20_000);
+        assert_let!(Err(Error::TooDeep(3)) = ctx(limits).compile(source.chars()) => ());
+        assert_let!(Err(Error::Template(origin, err)) = ctx(limits).with_loader(partials_map(&[("deep", "{{#a}}{{<b}}{{#c}}{{#d}}")])).compile("{{>deep}}".chars()) => {
+            assert_eq!(origin, "deep");
+            assert_let!(Error::TooDeep(3) = *err => ());
+        });
+    }
+
+    #[test]
+    fn output() {
+        let limits = Limits {
+            max_output: Some(10),
+            ..Limits::default()
+        };
+        let template = compile_with(ctx(limits), "{{#items}}{{.}}{{/items}}", &[]);
+        assert_eq!(
+            template
+                .render_to_string(&json!({ "items": ["12345", "67890"] }))
+                .unwrap(),
+            "1234567890"
+        );
+        assert_let!(Err(Error::OutputTooLarge(10)) = template.render_to_string(&json!({ "items": ["12345", "67890", "!"] })) => ());
+
+        // The limit applies to each render.
+        assert_eq!(
+            template
+                .render_to_string(&json!({ "items": ["ok"] }))
+                .unwrap(),
+            "ok"
+        );
+
+        // Only what lambdas return counts, once.
+        let template = compile_with(ctx(limits), "{{#l}}12345678{{/l}}", &[]);
+        let data = MapBuilder::new()
+            .insert_lambda("l", |ctx| Ok(ctx.render_section()?))
+            .build();
+        assert_eq!(template.render_data_to_string(&data).unwrap(), "12345678");
+
+        let template = compile_with(ctx(limits), "{{#twice}}12345{{/twice}}", &[]);
+        let data = MapBuilder::new()
+            .insert_lambda("twice", |ctx| {
+                ctx.render_section()?;
+                ctx.render_section()?;
+                Ok(String::new())
+            })
+            .build();
+        assert_eq!(template.render_data_to_string(&data).unwrap(), "");
+
+        let data = MapBuilder::new()
+            .insert_lambda("twice", |ctx| {
+                Ok(ctx.render_section()? + &ctx.render_section()? + "!")
+            })
+            .build();
+        assert_let!(Err(Error::OutputTooLarge(10)) = template.render_data_to_string(&data) => ());
+
+        // What a lambda renders for itself can't grow past the limit either.
+        let template = compile_with(ctx(limits), "{{#l}}{{#items}}{{.}}{{/items}}{{/l}}", &[]);
+        let data = MapBuilder::new()
+            .insert("items", &["12345", "67890", "!"])
+            .unwrap()
+            .insert_lambda("l", |ctx| {
+                ctx.render_section()?;
+                Ok(String::new())
+            })
+            .build();
+        assert_let!(Err(Error::OutputTooLarge(10)) = template.render_data_to_string(&data) => ());
+    }
+
+    #[test]
+    fn iterations() {
+        let limits = Limits {
+            max_iterations: Some(6),
+            ..Limits::default()
+        };
+        let template = compile_with(ctx(limits), "{{#rows}}{{#cols}}.{{/cols}}{{/rows}}", &[]);
+        assert_eq!(
+            template
+                .render_to_string(&json!({ "rows": [{ "cols": [1, 2] }, { "cols": [1, 2] }] }))
+                .unwrap(),
+            "...."
+        );
+        assert_let!(Err(Error::TooManyIterations(6)) = template.render_to_string(&json!({ "rows": [{ "cols": [1, 2, 3] }, { "cols": [1, 2] }] })) => ());
+    }
+
+    #[test]
+    fn source() {
+        let limits = Limits {
+            max_source: Some(8),
+            ..Limits::default()
+        };
+        assert!(ctx(limits).compile("{{name}}".chars()).is_ok());
+        assert_let!(Err(Error::SourceTooLarge(8)) = ctx(limits).compile("{{#open}} and more".chars()) => ());
+
+        let err = ctx(limits)
+            .with_loader(partials_map(&[("big", "0123456789")]))
+            .compile("{{>big}}".chars())
+            .unwrap_err();
+        assert_let!(Error::Template(origin, err) = err => {
+            assert_eq!(origin, "big");
+            assert_let!(Error::SourceTooLarge(8) = *err => ());
+        });
+    }
+}
+
+mod context_values {
+    use super::*;
+    use mustache::{ContextValue, Value};
+    use std::borrow::Cow;
+
+    const TEMPLATE: &str = "{{title}}: {{#rows}}[{{id}} {{score}} {{#tags}}{{.}},{{/tags}}{{^tags}}none{{/tags}}]{{/rows}} {{meta.total}}";
+
+    fn data() -> Json {
+        json!({
+            "title": "<Report>",
+            "rows": [
+                { "id": 1, "score": 2.5, "tags": ["a", "b"] },
+                { "id": 2, "score": null, "tags": [] },
+            ],
+            "meta": { "total": 2 },
+        })
+    }
+
+    #[test]
+    fn json_matches_data() {
+        let template = compile_str(TEMPLATE);
+        let data = data();
+        let expected = "&lt;Report&gt;: [1 2.5 a,b,][2  none] 2";
+        assert_eq!(template.render_value_to_string(&data).unwrap(), expected);
+        assert_eq!(template.render_to_string(&data).unwrap(), expected);
+    }
+
+    #[test]
+    fn std_values() {
+        let template = compile_str(
+            "{{#names}}{{.}} {{/names}}{{count}} {{#flag}}yes{{/flag}}{{^missing}}!{{/missing}}",
+        );
+        let mut data: HashMap<String, Box<dyn ContextValue>> = HashMap::new();
+        data.insert("names".into(), Box::new(vec!["a", "b"]));
+        data.insert("count".into(), Box::new(3u8));
+        data.insert("flag".into(), Box::new(true));
+        data.insert("missing".into(), Box::new(None::<String>));
+
+        struct Values(HashMap<String, Box<dyn ContextValue>>);
+
+        impl ContextValue for Values {
+            fn value(&self) -> Value<'_> {
+                Value::Map
+            }
+
+            fn get(&self, key: &str) -> Option<&dyn ContextValue> {
+                self.0.get(key).map(|value| &**value)
+            }
+        }
+
+        assert_eq!(
+            template.render_value_to_string(&Values(data)).unwrap(),
+            "a b 3 yes!"
+        );
+    }
+
+    #[test]
+    fn borrowed_items() {
+        struct Item<'a>(&'a str);
+
+        impl ContextValue for Item<'_> {
+            fn value(&self) -> Value<'_> {
+                Value::String(Cow::Borrowed(self.0))
+            }
+        }
+
+        let names = ["x".to_string(), "y".to_string()];
+        let items: Vec<Item> = names.iter().map(|name| Item(name)).collect();
+        let template = compile_str("{{#.}}{{.}};{{/.}}");
+        assert_eq!(template.render_value_to_string(&items).unwrap(), "x;y;");
+    }
+
+    #[test]
+    fn strict_mode() {
+        let template = compile_str("{{missing}}").with_strict(true);
+        assert_let!(Err(Error::Unresolved(name, _, _, _)) = template.render_value_to_string(&json!({})) => {
+            assert_eq!(name, "missing");
+        });
+    }
+}
+
+mod lambdas {
+    use super::*;
+    use mustache::{Value, VecBuilder};
+
+    #[test]
+    fn render_section_per_item() {
+        let data = MapBuilder::new()
+            .insert_vec("people", |vec| {
+                vec.push_map(|map| map.insert_str("name", "ann"))
+                    .push_map(|map| map.insert_str("name", "bob"))
+            })
+            .insert_lambda("loud", |ctx| Ok(ctx.render_section()?.to_uppercase()))
+            .build();
+        let template = compile_str("{{#people}}{{#loud}}hi {{name}}{{/loud}} {{/people}}");
+        assert_eq!(render_data(&template, &data), "HI ANN HI BOB ");
+    }
+
+    #[test]
+    fn render_with_other_data() {
+        let data = MapBuilder::new()
+            .insert_str("name", "outer")
+            .insert_lambda("twice", |ctx| {
+                let first = ctx.render_section_with(&json!({ "name": "one" }))?;
+                let second = ctx.render_with("[{{name}} {{x}}]", &json!({ "x": 2 }))?;
+                Ok(first + &second)
+            })
+            .build();
+        let template = compile_str("{{#twice}}<{{name}}>{{/twice}}");
+        assert_eq!(render_data(&template, &data), "<one>[outer 2]");
+    }
+
+    #[test]
+    fn source_and_delimiters() {
+        let data = MapBuilder::new()
+            .insert_str("planet", "Earth")
+            .insert_lambda("wrap", |ctx| {
+                assert_eq!(ctx.source(), "<% planet %>");
+                assert_eq!(ctx.delimiters(), ("<%", "%>"));
+                Ok(ctx.render("(<% planet %>)")?)
+            })
+            .build();
+        let template = compile_str("{{=<% %>=}}<%#wrap%><% planet %><%/wrap%>");
+        assert_eq!(render_data(&template, &data), "(Earth)");
+    }
+
+    #[test]
+    fn source_as_written() {
+        // Comments, standalone lines and delimiter changes are all kept.
+        let source = "\n  {{! note }}\n  {{#a}}\n  {{=<% %>=}}<% b %>\n  <%/a%>\n";
+        let template = compile_str(&format!("{{{{#fun}}}}{source}<%/fun%>"));
+
+        let seen = Arc::new(Mutex::new(String::new()));
+        let fun_seen = seen.clone();
+        let data = MapBuilder::new()
+            .insert_fn("fun", move |s| {
+                *fun_seen.lock().unwrap() = s;
+                String::new()
+            })
+            .build();
+        assert_eq!(render_data(&template, &data), "");
+        assert_eq!(*seen.lock().unwrap(), source);
+
+        let template = compile_str(&format!("{{{{#l}}}}{source}<%/l%>"));
+        let data = MapBuilder::new()
+            .insert_lambda("l", move |ctx| {
+                assert_eq!(ctx.source(), source);
+                Ok(String::new())
+            })
+            .build();
+        assert_eq!(render_data(&template, &data), "");
+    }
+
+    #[test]
+    fn lookup() {
+        let data = MapBuilder::new()
+            .insert_map("user", |map| map.insert_str("name", "ann"))
+            .insert_vec("items", |vec| vec.push_str("a").push_str("b"))
+            .insert_lambda("show", |ctx| {
+                let Some(Value::String(item)) = ctx.get(".").map(|v| v.value()) else {
+                    panic!("expected the current item");
+                };
+                let Some(Value::String(name)) = ctx.get("user.name").map(|v| v.value()) else {
+                    panic!("expected user.name");
+                };
+                Ok(format!("{name}:{item} "))
+            })
+            .build();
+        let template = compile_str("{{#items}}{{#show}}{{/show}}{{/items}}");
+        assert_eq!(render_data(&template, &data), "ann:a ann:b ");
+    }
+
+    #[test]
+    fn interpolation_is_escaped() {
+        let data = VecBuilder::new()
+            .push_lambda(|ctx| {
+                assert_eq!(ctx.source(), "");
+                Ok("<b>".to_string())
+            })
+            .build();
+        let template = compile_str("{{#.}}{{.}} {{{.}}}{{/.}}");
+        assert_eq!(render_data(&template, &data), "&lt;b&gt; <b>");
+    }
+
+    #[test]
+    fn render_errors_pass_through() {
+        let data = MapBuilder::new()
+            .insert_lambda("fail", |ctx| Ok(ctx.render("{{#unclosed}}")?))
+            .build();
+        let template = compile_str("{{#fail}}{{/fail}}");
+        assert_let!(Err(Error::Parser(_)) = template.render_data_to_string(&data));
+    }
+
+    #[test]
+    fn fallible_fn() {
+        let data = MapBuilder::new()
+            .insert_str("planet", "Earth")
+            .insert_map("i18n", |map| {
+                map.insert_fallible_fn("t", |key| match key.as_str() {
+                    "hello" => Ok("Hello, {{planet}}".to_string()),
+                    _ => Err(format!("no translation for {key:?}")),
+                })
+            })
+            .build();
+
+        let template = compile_str("{{#i18n.t}}hello{{/i18n.t}}!");
+        assert_eq!(render_data(&template, &data), "Hello, Earth!");
+
+        let template = compile_str("{{#i18n.t}}hello{{/i18n.t}}\n {{#i18n.t}}bye{{/i18n.t}}");
+        let result = template.render_data_to_string(&data);
+        assert_let!(Err(Error::Lambda(ref name, span, None, ref err)) = result => {
+            assert_eq!(name, "i18n.t");
+            assert_eq!((span.line, span.col), (2, 2));
+            assert_eq!(err.to_string(), "no translation for \"bye\"");
+        });
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "lambda \"i18n.t\" at line 2, column 2 failed: no translation for \"bye\""
+        );
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn errors_name_the_partial() {
+        #[derive(Debug)]
+        struct RateUnavailable;
+
+        impl std::fmt::Display for RateUnavailable {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("no exchange rate")
+            }
+        }
+
+        impl std::error::Error for RateUnavailable {}
+
+        let template = compile_with(
+            Context::new(PathBuf::from(".")),
+            "{{> price}}",
+            &[("price", "{{convert}}")],
+        );
+
+        let data = MapBuilder::new()
+            .insert_lambda("convert", |_| Err(RateUnavailable.into()))
+            .build();
+        let result = template.render_data_to_string(&data);
+        assert_let!(Err(Error::Lambda(ref name, _, Some(ref partial), ref err)) = result => {
+            assert_eq!(name, "convert");
+            assert_eq!(partial, "price");
+            assert!(err.downcast_ref::<RateUnavailable>().is_some());
+        });
+    }
+
+    #[test]
+    fn output_in_lists_and_partials() {
+        use mustache::{PartialLoader, PartialSource};
+
+        // The partial isn't part of the template, so the first lambda output
+        // using it loads it, and outputs after that reuse it.
+        struct Counting(Arc<AtomicUsize>);
+
+        impl PartialLoader for Counting {
+            fn load(&self, name: &str) -> mustache::Result<Option<PartialSource>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Ok(Some(PartialSource {
+                    origin: name.to_string(),
+                    source: "[{{.}}]".to_string(),
+                    root: None,
+                }))
+            }
+        }
+
+        let loads = Arc::new(AtomicUsize::new(0));
+        let template = Context::new(PathBuf::from("."))
+            .with_loader(Counting(loads.clone()))
+            .compile(
+                "{{#rows}}{{#row}}{{/row}}{{/rows}} \
+                 {{#same}}{{/same}}{{=<% %>=}}<%#same%><%/same%>"
+                    .chars(),
+            )
+            .expect("Failed to compile");
+
+        let calls = AtomicUsize::new(0);
+        let data = MapBuilder::new()
+            .insert("rows", &(1..=100).collect::<Vec<_>>())
+            .expect("Failed to encode rows")
+            .insert_fn("row", move |_| {
+                match calls.fetch_add(1, Ordering::SeqCst) % 2 {
+                    0 => "{{> row}}".to_string(),
+                    _ => "({{> row}})".to_string(),
+                }
+            })
+            .insert_fn("same", |_| "{{.}}<%.%>".to_string())
+            .build();
+
+        let rows: String = (1..=100)
+            .map(|i| match i % 2 {
+                1 => format!("[{i}]"),
+                _ => format!("([{i}])"),
+            })
+            .collect();
+        assert_eq!(
+            template.render_data_to_string(&data).unwrap(),
+            format!("{rows} <%.%>{{{{.}}}}")
+        );
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn many_distinct_outputs() {
+        let template = compile_str("{{#rows}}{{#cell}}{{/cell}}{{/rows}}");
+        let calls = AtomicUsize::new(0);
+        let data = MapBuilder::new()
+            .insert("rows", &vec![0; 1000])
+            .expect("Failed to encode rows")
+            .insert_fn("cell", move |_| {
+                format!("<{}>", calls.fetch_add(1, Ordering::SeqCst))
+            })
+            .build();
+
+        let expected: String = (0..1000).map(|i| format!("<{i}>")).collect();
+        assert_eq!(template.render_data_to_string(&data).unwrap(), expected);
+    }
+}
+
+mod computed {
+    use super::*;
+
+    #[test]
+    fn computed_once_when_used() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let data = MapBuilder::new()
+            .insert_str("title", "Shop")
+            .insert_computed("items", move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                to_data(["a", "b"]).unwrap()
+            })
+            .build();
+
+        let template = compile_str("{{title}}");
+        assert_eq!(render_data(&template, &data), "Shop");
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        let template = compile_str("{{#items}}{{.}}{{/items}}{{^items}}none{{/items}}");
+        assert_eq!(render_data(&template, &data), "ab");
+        assert_eq!(render_data(&template, &data.clone()), "ab");
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn treated_like_stored_data() {
+        let data = MapBuilder::new()
+            .insert_computed("empty", || Data::Vec(Vec::new()))
+            .insert_computed("no", || Data::Bool(false))
+            .insert_computed("zero", || Data::Int(0))
+            .insert_computed("report", || {
+                MapBuilder::new()
+                    .insert_str("total", "12")
+                    .insert_computed("rows", || to_data([1, 2]).unwrap())
+                    .build()
+            })
+            .build();
+
+        let template = compile_str(
+            "{{^empty}}e{{/empty}}{{^no}}n{{/no}}{{^zero}}z{{/zero}} \
+             {{report.total}} {{#report}}{{#rows}}[{{.}}]{{/rows}}{{/report}}",
+        );
+        assert_eq!(render_data(&template, &data), "enz 12 [1][2]");
+
+        assert_eq!(
+            serde_json::to_value(&data).unwrap(),
+            json!({
+                "empty": [],
+                "no": false,
+                "zero": 0,
+                "report": { "total": "12", "rows": [1, 2] },
+            })
+        );
+
+        #[derive(Debug, PartialEq, serde_derive::Deserialize)]
+        struct Report {
+            total: u32,
+            rows: Vec<u8>,
+        }
+
+        #[derive(Debug, serde_derive::Deserialize)]
+        struct Page {
+            zero: i32,
+            report: Report,
+        }
+
+        let page: Page = mustache::from_data(data).unwrap();
+        assert_eq!(page.zero, 0);
+        assert_eq!(
+            page.report,
+            Report {
+                total: 12,
+                rows: vec![1, 2]
+            }
+        );
+    }
+}
+
+mod streams {
+    use super::*;
+
+    // Counts the bytes written so far, so a stream can check that earlier
+    // rows are already out.
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.fetch_add(buf.len(), Ordering::Relaxed);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rows_are_written_as_they_are_made() {
+        let written = Arc::new(AtomicUsize::new(0));
+        let seen = written.clone();
+        let data = MapBuilder::new()
+            .insert_stream("rows", move || {
+                let seen = seen.clone();
+                (0..10_000).map(move |i| {
+                    // Every earlier row is "<n>\n", at least four bytes.
+                    assert!(seen.load(Ordering::Relaxed) >= i * 4);
+                    Data::Int(i as i64)
+                })
+            })
+            .build();
+
+        let template = compile_str("{{#rows}}<{{.}}>\n{{/rows}}");
+        template
+            .render_data(&mut Counting(written.clone()), &data)
+            .unwrap();
+        assert!(written.load(Ordering::Relaxed) > 10_000 * 4);
+    }
+
+    #[test]
+    fn made_again_for_each_use() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let data = MapBuilder::new()
+            .insert_stream("rows", move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                vec![
+                    MapBuilder::new().insert_str("name", "a").build(),
+                    MapBuilder::new().insert_str("name", "b").build(),
+                ]
+            })
+            .insert_stream("empty", Vec::new)
+            .build();
+
+        let template =
+            compile_str("{{#rows}}{{name}}{{/rows}}{{^rows}}none{{/rows}}{{^empty}}-{{/empty}}");
+        assert_eq!(render_data(&template, &data), "ab-");
+        assert_eq!(render_data(&template, &data), "ab-");
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn treated_like_stored_lists() {
+        let data = MapBuilder::new()
+            .insert_stream("rows", || (1..=3).map(Data::Int))
+            .build();
+
+        assert_eq!(
+            serde_json::to_value(&data).unwrap(),
+            json!({ "rows": [1, 2, 3] })
+        );
+
+        #[derive(Debug, serde_derive::Deserialize)]
+        struct Page {
+            rows: Vec<u8>,
+        }
+
+        let page: Page = mustache::from_data(data).unwrap();
+        assert_eq!(page.rows, vec![1, 2, 3]);
+    }
+}
+
+mod formatter {
+    use super::*;
+    use mustache::Error;
+    use mustache::fmt::{Options, Spacing, format};
+
+    const TEMPLATE: &str = "\
+{{! users }}
+<ul>
+{{^hidden}}
+   {{#users}}
+  <li>{{ name}} {{{bio}}}</li>
+      {{/users}}
+   {{^users}}
+ {{! none }}
+   {{/users}}
+{{/hidden}}
+</ul>
+  {{>footer}}
+";
+
+    #[test]
+    fn default_options_change_nothing() {
+        assert_eq!(format(TEMPLATE, &Options::default()).unwrap(), TEMPLATE);
+
+        let crlf = TEMPLATE.replace('\n', "\r\n");
+        assert_eq!(format(&crlf, &Options::default()).unwrap(), crlf);
+    }
+
+    #[test]
+    fn spacing() {
+        let padded = Options {
+            spacing: Spacing::Padded,
+            ..Options::default()
+        };
+        assert_eq!(
+            format("{{a}} {{{ b}}} {{&c }} {{>d}} {{>*e}}", &padded).unwrap(),
+            "{{ a }} {{{ b }}} {{& c }} {{> d }} {{> *e }}"
+        );
+
+        let tight = Options {
+            spacing: Spacing::Tight,
+            ..Options::default()
+        };
+        assert_eq!(
+            format("{{ a }}{{# b }}{{/ b }}{{> * e }}", &tight).unwrap(),
+            "{{a}}{{#b}}{{/b}}{{>*e}}"
+        );
+    }
+
+    #[test]
+    fn reindents_standalone_tags() {
+        let options = Options {
+            indent: Some("  ".to_string()),
+            ..Options::default()
+        };
+        let formatted = format(TEMPLATE, &options).unwrap();
+        assert_eq!(
+            formatted,
+            "\
+{{! users }}
+<ul>
+{{^hidden}}
+   {{#users}}
+  <li>{{ name}} {{{bio}}}</li>
+      {{/users}}
+  {{^users}}
+    {{! none }}
+  {{/users}}
+{{/hidden}}
+</ul>
+  {{>footer}}
+"
+        );
+
+        // Partial indentation is significant, so it's left alone.
+        let partials = [("footer", "<p>\n{{bio}}</p>")];
+        let data = json!({ "users": [{ "name": "Jo", "bio": "<b>" }] });
+        assert_eq!(
+            render_with_partials(&formatted, &partials, &data),
+            render_with_partials(TEMPLATE, &partials, &data)
+        );
+    }
+
+    #[cfg(not(feature = "CFEngine"))]
+    #[test]
+    fn leaves_block_indentation_alone() {
+        let options = Options {
+            indent: Some("  ".to_string()),
+            ..Options::default()
+        };
+        let template = "{{<layout}}\n{{$body}}\n  {{^a}}\n  x\n  {{/a}}\n{{/body}}\n{{/layout}}\n";
+        assert_eq!(
+            format(template, &options).unwrap(),
+            "{{<layout}}\n{{$body}}\n    {{^a}}\n  x\n    {{/a}}\n{{/body}}\n{{/layout}}\n"
+        );
+    }
+
+    #[test]
+    fn normalizes_delimiters() {
+        let options = Options {
+            normalize_delimiters: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            format("{{=<% %>=}}\n<%^a%>\n  <%b%>\n<%/a%>\n", &options).unwrap(),
+            "{{^a}}\n  {{b}}\n{{/a}}\n"
+        );
+        assert_eq!(
+            format("{{=<% %>=}}<%a%><%={{ }}=%>{{b}}", &options).unwrap(),
+            "{{a}}{{b}}"
+        );
+
+        // Text that would turn into a tag keeps its delimiters, and so do
+        // sections whose contents lambdas get.
+        let literal = "{{=<% %>=}}\n{{literal}} <%x%>\n";
+        assert_eq!(format(literal, &options).unwrap(), literal);
+        let section = "{{=<% %>=}}\n<%#a%>\n  <%b%>\n<%/a%>\n";
+        assert_eq!(format(section, &options).unwrap(), section);
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let options = Options {
+            spacing: Spacing::Padded,
+            indent: Some("\t".to_string()),
+            ..Options::default()
+        };
+        assert_eq!(
+            format("{{^a}}\n{{!note}}\n{{/a}}", &options).unwrap(),
+            "{{^ a }}\n\t{{!note}}\n{{/ a }}"
+        );
+
+        // Comment bodies are kept exactly as written.
+        for comment in ["{{!f}}", "{{!  two  spaces }}", "{{!\n  - a\n  - b\n}}"] {
+            for spacing in [Spacing::Tight, Spacing::Padded] {
+                let options = Options {
+                    spacing,
+                    ..Options::default()
+                };
+                assert_eq!(format(comment, &options).unwrap(), comment);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_what_lambdas_get() {
+        let options = Options {
+            spacing: Spacing::Padded,
+            indent: Some("  ".to_string()),
+            normalize_delimiters: true,
+        };
+        let template = "{{#wrap}}\n{{#inner}}\n{{ name}}\n{{/inner}}\n{{/wrap}}\n";
+        let formatted = format(template, &options).unwrap();
+        assert_eq!(
+            formatted,
+            "{{# wrap }}\n{{#inner}}\n{{ name}}\n{{/inner}}\n{{/ wrap }}\n"
+        );
+
+        let data = MapBuilder::new()
+            .insert_fn("wrap", |source| source.replace('{', "("))
+            .build();
+        assert_eq!(
+            render_data(&compile_str(&formatted), &data),
+            render_data(&compile_str(template), &data)
+        );
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert_let!(Err(Error::Parser(_)) = format("{{#a}}", &Options::default()) => ());
+    }
+}
+
+mod template_sets {
+    use super::*;
+    use mustache::TemplateSet;
+    use std::time::{Duration, SystemTime};
+
+    fn load(tmpdir: &TempDir) -> TemplateSet {
+        mustache::compile_dir(tmpdir.path()).expect("Failed to compile")
+    }
+
+    #[test]
+    fn renders_by_name() {
+        let tmpdir = write_tree(&[
+            (
+                "emails/welcome.mustache",
+                "{{>emails/header}}Welcome, {{name}}!",
+            ),
+            ("emails/header.mustache", "[{{title}}] "),
+            ("page.mustache", "<p>{{>footer}}</p>"),
+            ("footer.mustache", "{{name}}"),
+            ("notes.txt", "not a template"),
+        ]);
+        let set = load(&tmpdir);
+
+        assert_eq!(
+            set.names().collect::<Vec<_>>(),
+            ["emails/header", "emails/welcome", "footer", "page"]
+        );
+
+        let data = json!({ "name": "Jo", "title": "Hi" });
+        assert_eq!(
+            set.render("emails/welcome", &data).unwrap(),
+            "[Hi] Welcome, Jo!"
+        );
+        assert_eq!(set.render("page", &data).unwrap(), "<p>Jo</p>");
+        assert_eq!(
+            set.get("footer").unwrap().render_to_string(&data).unwrap(),
+            "Jo"
+        );
+    }
+
+    #[test]
+    fn ast_has_only_reachable_partials() {
+        let tmpdir = write_tree(&[
+            ("a.mustache", "{{>b}}"),
+            ("b.mustache", "{{#x}}{{>d}}{{/x}}"),
+            ("c.mustache", "{{>d}}"),
+            ("d.mustache", "d"),
+        ]);
+        let set = load(&tmpdir);
+
+        let partials = |name| {
+            let mut names: Vec<_> = set.get(name).unwrap().ast().partials.into_keys().collect();
+            names.sort();
+            names
+        };
+        assert_eq!(partials("a"), ["b", "d"]);
+        assert_eq!(partials("c"), ["d"]);
+        assert!(partials("d").is_empty());
+    }
+
+    #[test]
+    fn unknown_template() {
+        let tmpdir = write_tree(&[("page.mustache", "")]);
+        assert_let!(Err(Error::UnknownTemplate(name)) = load(&tmpdir).render("missing", &json!({})) => {
+            assert_eq!(name, "missing");
+        });
+    }
+
+    #[test]
+    fn falls_back_to_context_loader() {
+        let tmpdir = write_tree(&[
+            ("page.mustache", "{{>shared}} {{>footer}}"),
+            ("footer.mustache", "file"),
+        ]);
+        let partials = partials_map(&[("shared", "memory"), ("footer", "memory")]);
+        let set = Context::new(tmpdir.path().to_path_buf())
+            .with_loader(partials)
+            .compile_dir()
+            .expect("Failed to compile");
+        assert_eq!(set.render("page", &json!({})).unwrap(), "memory file");
+    }
+
+    #[test]
+    fn reports_the_failing_file() {
+        let tmpdir = write_tree(&[("ok.mustache", "fine"), ("bad.mustache", "{{#open}}")]);
+        assert_let!(Err(Error::Template(name, _)) = mustache::compile_dir(tmpdir.path()) => {
+            assert!(name.ends_with("bad.mustache"), "{name}");
+        });
+    }
+
+    #[test]
+    fn theme_overrides_base() {
+        let theme = write_tree(&[("header.mustache", "theme"), ("extra.mustache", "+")]);
+        let base = write_tree(&[
+            ("page.mustache", "{{>header}} {{>footer}}"),
+            ("header.mustache", "base"),
+            ("footer.mustache", "base"),
+        ]);
+
+        let mut set = Context::new(theme.path().to_path_buf())
+            .with_search_path(base.path().to_path_buf())
+            .compile_dir()
+            .expect("Failed to compile");
+        assert_eq!(
+            set.names().collect::<Vec<_>>(),
+            ["extra", "footer", "header", "page"]
+        );
+        assert_eq!(set.render("page", &json!({})).unwrap(), "theme base");
+
+        // Removing an override falls back to the base template.
+        fs::remove_file(theme.path().join("header.mustache")).unwrap();
+        assert_eq!(set.reload().unwrap(), ["header", "page"]);
+        assert_eq!(set.render("page", &json!({})).unwrap(), "base base");
+    }
+
+    #[test]
+    fn skips_missing_search_roots() {
+        let base = write_tree(&[("page.mustache", "{{>header}}")]);
+        let themes = write_tree(&[]);
+        let theme = themes.path().join("theme");
+
+        let mut set = Context::new(theme.clone())
+            .with_search_path(themes.path().join("missing"))
+            .with_search_path(base.path().to_path_buf())
+            .compile_dir()
+            .expect("Failed to compile");
+        assert_eq!(set.names().collect::<Vec<_>>(), ["page"]);
+        assert_eq!(set.render("page", &json!({})).unwrap(), "");
+
+        // A root made later is picked up on reload.
+        fs::create_dir(&theme).unwrap();
+        fs::write(theme.join("header.mustache"), "theme").unwrap();
+        assert_eq!(set.reload().unwrap(), ["header", "page"]);
+        assert_eq!(set.render("page", &json!({})).unwrap(), "theme");
+    }
+
+    // Writes `src` to `path` with a modification time later than the last
+    // one, however coarse the filesystem's timestamps are.
+    fn touch(tmpdir: &TempDir, path: &str, src: &str) {
+        let path = tmpdir.path().join(path);
+        let later = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .map_or(SystemTime::now(), |time| time + Duration::from_secs(2));
+        fs::write(&path, src).expect("Failed to write template");
+        File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(later))
+            .expect("Failed to set modification time");
+    }
+
+    #[test]
+    fn reload_recompiles_dependents() {
+        let tmpdir = write_tree(&[
+            ("page.mustache", "<{{>layout}}>"),
+            ("layout.mustache", "[{{>footer}}]"),
+            ("footer.mustache", "old"),
+            ("other.mustache", "other"),
+        ]);
+        let mut set = load(&tmpdir);
+        assert_eq!(set.reload().unwrap(), Vec::<String>::new());
+
+        touch(&tmpdir, "footer.mustache", "new");
+        assert_eq!(set.reload().unwrap(), ["footer", "layout", "page"]);
+        assert_eq!(set.render("page", &json!({})).unwrap(), "<[new]>");
+        assert_eq!(set.render("other", &json!({})).unwrap(), "other");
+        assert_eq!(set.reload().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reload_added_and_removed_files() {
+        let tmpdir = write_tree(&[("page.mustache", "a{{>missing}}b"), ("old.mustache", "")]);
+        let mut set = load(&tmpdir);
+        assert_eq!(set.render("page", &json!({})).unwrap(), "ab");
+
+        fs::write(tmpdir.path().join("missing.mustache"), "-").unwrap();
+        fs::remove_file(tmpdir.path().join("old.mustache")).unwrap();
+        assert_eq!(set.reload().unwrap(), ["missing", "page"]);
+        assert_eq!(set.render("page", &json!({})).unwrap(), "a-b");
+        assert_eq!(set.names().collect::<Vec<_>>(), ["missing", "page"]);
+    }
+
+    #[test]
+    fn reload_keeps_the_set_on_errors() {
+        let tmpdir = write_tree(&[("page.mustache", "fine")]);
+        let mut set = load(&tmpdir);
+
+        touch(&tmpdir, "page.mustache", "{{#broken}}");
+        assert!(set.reload().is_err());
+        assert_eq!(set.render("page", &json!({})).unwrap(), "fine");
+
+        touch(&tmpdir, "page.mustache", "fixed");
+        assert_eq!(set.reload().unwrap(), ["page"]);
+        assert_eq!(set.render("page", &json!({})).unwrap(), "fixed");
+    }
+
+    #[test]
+    fn auto_escape_per_file() {
+        let tmpdir = write_tree(&[
+            ("data.json.mustache", "\"{{v}}\""),
+            ("page.html.mustache", "{{v}}"),
+        ]);
+        let set = Context::new(tmpdir.path().to_path_buf())
+            .with_auto_escape(true)
+            .compile_dir()
+            .expect("Failed to compile");
+
+        let data = json!({ "v": "<\"x\">" });
+        assert_eq!(set.render("data.json", &data).unwrap(), "\"<\\\"x\\\">\"");
+        assert_eq!(
+            set.render("page.html", &data).unwrap(),
+            "&lt;&quot;x&quot;&gt;"
+        );
+    }
+}
+
+fn parse_spec_tests(src: &str) -> Vec<Json> {
+    let path = PathBuf::from(src);
+    let file =
+        File::open(&path).unwrap_or_else(|_| panic!("Could not read file {}", path.display()));
+    let json = serde_json::from_reader(file)
+        .unwrap_or_else(|_| panic!("Invalid json in file {}", path.display()));
+
+    assert_let!(Json::Object(mut d) = json => {
+        assert_let!(Some(Json::Array(tests)) = d.remove("tests") => {
+            tests.into_iter().collect()
+        })
+    })
+}
+
+fn write_partials(tmpdir: &Path, value: &Json) {
+    assert_let!(Json::Object(ref d) = *value => {
+        for (key, value) in d {
+            assert_let!(Json::String(ref s) = *value => {
+                let path = tmpdir.join(&(key.clone() + ".mustache"));
+                File::create(&path)
+                    .and_then(|mut f| f.write_all(s.as_bytes()))
+                    .expect("Failed to generate partial");
+            })
+        }
+    })
+}
+
+fn run_test(test: serde_json::Map<String, Json>, data: Data) {
+    let template = assert_let!(Some(Json::String(s)) = test.get("template") => {
+        s.clone()
+    });
+
+    let expected = assert_let!(Some(Json::String(s)) = test.get("expected") => {
+        s.clone()
+    });
+
+    // Make a temporary dir where we'll store our partials. This is to
+    // avoid a race on filenames.
+    let tmpdir = TempDir::new("").expect("Failed to make tempdir");
+
+    if let Some(value) = test.get("partials") {
+        write_partials(tmpdir.path(), value)
+    }
+
+    let ctx = Context::new(tmpdir.path().to_path_buf());
+    let template = ctx
+        .compile(template.chars())
+        .unwrap_or_else(|_| panic!("Failed to compile: {}", template));
+    let result = render_data(&template, &data);
+
+    if result != expected {
+        println!("desc:     {:?}", test.get("desc"));
+        println!("context:  {:?}", test.get("data"));
+        println!("=>");
+        println!("template: {:?}", template);
+        println!("expected: {}", expected);
+        println!("actual:   {}", result);
+        println!();
+    }
+    assert_eq!(result, expected);
+}
+
+fn run_tests(spec: &str) {
+    for json in parse_spec_tests(spec).into_iter() {
+        let test = assert_let!(Json::Object(m) = json => m);
+
+        let data = test.get("data").expect("No test data").clone();
+        let data = to_data(&data).expect("Failed to encode");
+
+        run_test(test, data);
+    }
+}
+
+#[test]
+fn test_spec_comments() {
+    run_tests("spec/specs/comments.json");
+}
+
+#[test]
+fn test_spec_delimiters() {
+    run_tests("spec/specs/delimiters.json");
+}
+
+#[test]
+fn test_spec_interpolation() {
+    run_tests("spec/specs/interpolation.json");
+}
+
+#[test]
+fn test_spec_inverted() {
+    run_tests("spec/specs/inverted.json");
+}
+
+#[test]
+fn test_spec_partials() {
+    run_tests("spec/specs/partials.json");
+}
+
+#[test]
+fn test_spec_sections() {
+    run_tests("spec/specs/sections.json");
+}
+
+#[cfg(not(feature = "CFEngine"))]
+#[test]
+fn test_spec_inheritance() {
+    run_tests("spec/specs/~inheritance.json");
+}
+
+#[test]
+fn test_spec_dynamic_names() {
+    run_tests("spec/specs/~dynamic-names.json");
+}
+
+#[test]
+fn test_spec_lambdas() {
+    for json in parse_spec_tests("spec/specs/~lambdas.json").into_iter() {
+        let mut test = assert_let!(Json::Object(m) = json => m);
+
+        let s = assert_let!(Some(Json::String(s)) = test.remove("name") => s);
+
+        // Replace the lambda with rust code.
+        let data = test.remove("data").expect("No test data");
+
+        let data = to_data(&data).expect("Failed to encode");
+
+        let mut ctx = assert_let!(Data::Map(ctx) = data => ctx);
+
+        // needed for the closure test.
+        let calls = AtomicUsize::new(0);
+
+        match &*s {
+            "Interpolation" => {
+                let f = |_text| "world".to_string();
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
+            }
+            "Interpolation - Expansion" => {
+                let f = |_text| "{{planet}}".to_string();
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
+            }
+            "Interpolation - Alternate Delimiters" => {
+                let f = |_text| "|planet| => {{planet}}".to_string();
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
+            }
+            "Interpolation - Multiple Calls" => {
+                let f =
+                    move |_text: String| (calls.fetch_add(1, Ordering::Relaxed) + 1).to_string();
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
             }
             "Escaping" => {
                 let f = |_text| ">".to_string();
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
             }
             "Section" => {
                 let f = |text: String| {
@@ -650,23 +2901,23 @@ fn test_spec_lambdas() {
                         "no".to_string()
                     }
                 };
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
             }
             "Section - Expansion" => {
                 let f = |text: String| text.clone() + "{{planet}}" + &text;
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
             }
             "Section - Alternate Delimiters" => {
                 let f = |text: String| text.clone() + "{{planet}} => |planet|" + &text;
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
             }
             "Section - Multiple Calls" => {
                 let f = |text: String| "__".to_string() + &text + "__";
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
             }
             "Inverted Section" => {
                 let f = |_text| "".to_string();
-                ctx.insert("lambda".to_string(), Data::Fun(RefCell::new(Box::new(f))));
+                ctx.insert("lambda".to_string(), Data::Fun(Arc::new(f)));
             }
             spec_name => panic!("unimplemented lambda spec test: {}", spec_name),
         };
@@ -7,4 +7,5 @@ extern crate tempdir;
 #[macro_use]
 mod macros;
 mod builder;
+mod decoder;
 mod template;